@@ -1,111 +1,590 @@
 //! A websocket chat client.
 
 use std::str;
+use std::io;
+use std::cmp;
+use std::mem;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, Condvar};
 use std::thread::{self, JoinHandle};
-use bincode;
+use rand::{self, Rng};
 use url::Url;
+use chrono::{DateTime, Utc};
+use openssl::ssl::SslStream;
 use ws::{self, Sender as WsSender, Message, Handler, Handshake, CloseCode, Factory,
-    Builder as WsBuilder, Settings};
-use chrono::Utc;
-use ::{UiRemote, Pingstamp, Error};
+    Builder as WsBuilder, Settings, Request, util::{Token, TcpStream}};
+use ::{EventSink, Error, millis};
+use protocol::{self, Envelope, Decoded, Pingstamp, PeerKey, SealedPart, Timestamps, FileMsg,
+    DEFAULT_ROOM, PASSWORD_HEADER, AUTH_FAILED};
+use crypto::{self, Identity, Context};
+use tls;
+
+
+/// The name the server is known by in round-trip time reports.
+pub const SERVER_PEER: &str = "server";
+
+/// The timeout token of the background ping.
+const PING_TIMEOUT: Token = Token(1);
+
+
+/// Parses a server url. Urls without a scheme (e.g. 'localhost:3030')
+/// default to unencrypted 'ws'.
+pub fn parse_url(url: &str) -> Result<Url, Error> {
+    let url = if url.contains("://") {
+        Url::parse(url)?
+    } else {
+        Url::parse(&format!("ws://{}", url))?
+    };
+    match url.scheme() {
+        "ws" | "wss" => Ok(url),
+        scheme => Err(Error::unsupported_scheme(scheme)),
+    }
+}
+
+
+/// Automatic reconnection settings.
+#[derive(Debug, Clone)]
+pub struct ReconnectOptions {
+    /// Whether to reconnect when the connection to the server is lost.
+    pub enabled: bool,
+    /// The delay before the first attempt. Doubles with each failed attempt.
+    pub initial_delay: Duration,
+    /// The maximum delay between attempts.
+    pub max_delay: Duration,
+    /// The number of consecutive failed attempts after which to give up.
+    /// Never gives up if zero.
+    pub max_attempts: u32,
+}
+
+impl ReconnectOptions {
+    /// Returns the jittered delay before attempt number `attempt` (starting
+    /// from 1): a random duration between half of and the full exponential
+    /// backoff delay.
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        let delay = self.initial_delay.checked_mul(factor)
+            .map(|d| cmp::min(d, self.max_delay))
+            .unwrap_or(self.max_delay);
+        let millis = millis(delay);
+        Duration::from_millis(rand::thread_rng().gen_range(millis / 2, millis + 1))
+    }
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> ReconnectOptions {
+        ReconnectOptions {
+            enabled: false,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: 0,
+        }
+    }
+}
+
+
+/// Client settings.
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    pub reconnect: ReconnectOptions,
+    /// Whether to skip verification of the server certificate when
+    /// connecting to a 'wss' url. Only use this with self-signed
+    /// certificates on trusted networks.
+    pub insecure: bool,
+    /// Our keypair. Chat messages are end-to-end encrypted if set, and
+    /// unencrypted ones received are ignored.
+    pub identity: Option<Arc<Identity>>,
+    /// The password required by the server, if any.
+    pub password: Option<String>,
+    /// The interval at which to ping the server in the background, if any.
+    pub ping_interval: Option<Duration>,
+    /// The number of consecutive background pings left unanswered after
+    /// which the connection is considered lost and closed. Never closes if
+    /// zero.
+    pub max_missed_pings: u32,
+}
+
+
+/// Connection state shared between a client, its connection thread and its
+/// handler.
+struct ClientState {
+    // The sender of the current connection, if any:
+    sender: Option<WsSender>,
+    // Set while the current connection is open:
+    open: bool,
+    // Set once the current connection has opened, even if it has since
+    // closed:
+    opened: bool,
+    // Our nickname and joined rooms, restored upon reconnecting:
+    nick: String,
+    rooms: Vec<String>,
+    // The public keys of all participants, by nickname:
+    keys: BTreeMap<String, Vec<u8>>,
+    // The send time of the latest encrypted message from each participant,
+    // by public key, to recognize replayed messages:
+    latest_sealed: BTreeMap<Vec<u8>, DateTime<Utc>>,
+    // The nicknames of all participants, as last listed by the server:
+    nicks: Vec<String>,
+    // Set once the user closes the connection or cancels reconnection:
+    stopped: bool,
+    // Set to skip the remainder of the current reconnection delay:
+    retry_now: bool,
+}
+
+struct Shared {
+    state: Mutex<ClientState>,
+    wake: Condvar,
+}
 
 
 /// A chat client handler.
 struct ClientHandler {
-    ui_remote: UiRemote,
+    events: Arc<dyn EventSink>,
     output: WsSender,
+    shared: Arc<Shared>,
+    opts: ClientOptions,
+    // The number of background pings sent since anything was received:
+    missed_pings: u32,
+    // Set once the connection is dropped for not answering pings:
+    timed_out: bool,
 }
 
-impl Handler for ClientHandler {
-    fn on_shutdown(&mut self) {
-        self.ui_remote.client_shutdown();
+impl ClientHandler {
+    /// Replaces the known public keys, warning about any which have changed.
+    fn set_keys(&mut self, keys: Vec<PeerKey>) {
+        let keys: BTreeMap<_, _> = keys.into_iter().map(|pk| (pk.nick, pk.key)).collect();
+        let old_keys = mem::replace(&mut self.shared.state.lock().unwrap().keys, keys.clone());
+        for (nick, key) in keys.iter() {
+            match old_keys.get(nick) {
+                Some(old_key) if old_key != key => {
+                    self.events.notice_recvd(format!("WARNING: The encryption key of {} has \
+                        changed. New fingerprint: {}", nick, crypto::fingerprint(key)));
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Reports a message which was not end-to-end encrypted although ours
+    /// are. As anyone relaying it could have written it, it is not shown.
+    fn reject_unencrypted(&self, from: &str, room: Option<&str>) {
+        self.events.notice_recvd(match room {
+            Some(room) => format!("Ignored an unencrypted message in {} claiming to be from \
+                {}.", room, from),
+            None => format!("Ignored an unencrypted private message claiming to be from {}.",
+                from),
+        });
+    }
+
+    /// Reports a file transfer message from `from`, decrypting it if we have
+    /// an identity. Unencrypted messages are then ignored, except for
+    /// cancellations, which the server sends on behalf of participants who
+    /// have left. Unencrypted offers are cancelled.
+    fn open_file(&mut self, from: String, id: u64, msg: FileMsg) -> Result<(), ws::Error> {
+        let identity = match self.opts.identity {
+            Some(ref identity) => identity,
+            None => {
+                self.events.file_recvd(from, id, msg);
+                return Ok(());
+            },
+        };
+        let msg = match msg {
+            FileMsg::Sealed { kind, offset, data } => {
+                let state = self.shared.state.lock().unwrap();
+                state.keys.get(&from).ok_or_else(|| Error::no_key(from.clone())).and_then(|key| {
+                    let context = Context::File { from: &from, to: &state.nick, id, kind, offset };
+                    identity.open_file(key, &context, &data)
+                })
+            },
+            msg @ FileMsg::Cancel(_) => Ok(msg),
+            FileMsg::Offer { .. } => {
+                let cancel = FileMsg::Cancel("The recipient only accepts encrypted files."
+                    .to_owned());
+                self.output.send(Envelope::File { from: None, to: from.clone(), id, msg: cancel }
+                    .to_message()?)?;
+                Err(Error::not_encrypted())
+            },
+            _ => Err(Error::not_encrypted()),
+        };
+        match msg {
+            Ok(msg) => self.events.file_recvd(from, id, msg),
+            Err(err) => self.events.notice_recvd(format!("Ignored a file transfer message from \
+                {}: {}", from, err)),
+        }
+        Ok(())
     }
 
+    /// Decrypts a message from `from`, sent to `room` or privately.
+    ///
+    /// Messages which were not sent after the latest one from the same
+    /// sender are replays and are ignored.
+    fn open_sealed(&mut self, room: Option<String>, from: String, parts: Vec<SealedPart>,
+            times: Timestamps) {
+        let text = {
+            let mut state = self.shared.state.lock().unwrap();
+            let state = &mut *state;
+            match (self.opts.identity.as_ref(), state.keys.get(&from),
+                    parts.iter().find(|p| p.to == state.nick)) {
+                (Some(id), Some(key), Some(part)) => {
+                    let context = Context::Message { room: room.as_deref(), from: &from,
+                        to: &state.nick, sent: times.sent };
+                    let latest = &mut state.latest_sealed;
+                    id.open(key, &context, &part.data).and_then(|text| match latest.get(key) {
+                        Some(&sent) if sent >= times.sent => Err(Error::replayed()),
+                        _ => {
+                            latest.insert(key.clone(), times.sent);
+                            Ok(text)
+                        },
+                    })
+                },
+                (_, None, _) => Err(Error::no_key(from.clone())),
+                _ => Err(Error::decryption_failed()),
+            }
+        };
+        match (text, room) {
+            (Ok(text), Some(room)) => self.events.message_recvd(room, from, text, times),
+            (Ok(text), None) => self.events.direct_recvd(from, text, times),
+            (Err(err), _) => self.events.notice_recvd(format!("Ignored a message from {}: {}",
+                from, err)),
+        }
+    }
+}
+
+impl Handler for ClientHandler {
     fn on_open(&mut self, shake: Handshake) -> Result<(), ws::Error> {
-        self.ui_remote.client_connected(shake);
+        let rooms = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.open = true;
+            state.opened = true;
+            // Rooms are added back as the server confirms each join:
+            mem::take(&mut state.rooms)
+        };
+        self.events.client_connected(shake.peer_addr);
+        if let Some(interval) = self.opts.ping_interval {
+            self.output.timeout(millis(interval), PING_TIMEOUT)?;
+        }
+
+        if let Some(ref id) = self.opts.identity {
+            self.output.send(Envelope::Key(id.public_key().to_vec()).to_message()?)?;
+        }
+
+        // Restore the rooms joined before reconnecting, in order, so that
+        // the active room remains the same. The server joins us to the
+        // default room automatically.
+        if !rooms.is_empty() {
+            if !rooms.iter().any(|r| r == DEFAULT_ROOM) {
+                self.output.send(Envelope::Part(DEFAULT_ROOM.to_owned()).to_message()?)?;
+            }
+            let skip = if rooms[0] == DEFAULT_ROOM { 1 } else { 0 };
+            for room in rooms.into_iter().skip(skip) {
+                self.output.send(Envelope::Join(room).to_message()?)?;
+            }
+        }
         Ok(())
     }
 
     fn on_message(&mut self, msg: Message) -> Result<(), ws::Error> {
-        match msg {
-            Message::Text(s) => {
-                self.ui_remote.message_recvd(s, self.output.token());
-                Ok(())
-            },
-            Message::Binary(b) => {
-                match bincode::deserialize::<Pingstamp>(&b) {
-                    Ok(Pingstamp::Ping(ts)) => {
-                        self.output.send(bincode::serialize(&Pingstamp::Pong(ts)).unwrap())
+        self.missed_pings = 0;
+        match Envelope::decode(msg) {
+            Ok(Decoded::Envelope(env)) => {
+                // Messages from older servers are stamped upon receipt:
+                let (env, times) = env.unstamp();
+                let times = times.unwrap_or_else(Timestamps::now);
+                match env {
+                    Envelope::Chat { room, from, text } => {
+                        let from = from.unwrap_or_else(|| "Server".to_owned());
+                        if self.opts.identity.is_some() {
+                            self.reject_unencrypted(&from, Some(&room));
+                        } else {
+                            self.events.message_recvd(room, from, text, times);
+                        }
+                        Ok(())
+                    },
+                    Envelope::Ping(ts) => self.output.send(Envelope::Pong(ts).to_message()?),
+                    Envelope::Pong(ts) => {
+                        self.events.pong_recvd(SERVER_PEER.to_owned(), ts.elapsed());
+                        Ok(())
+                    },
+                    Envelope::Notice(text) => {
+                        self.events.notice_recvd(text);
+                        Ok(())
+                    },
+                    Envelope::Presence { room, who, presence } => {
+                        self.events.presence_recvd(room, who, presence);
+                        Ok(())
                     },
-                    Ok(Pingstamp::Pong(ts)) => {
-                        let elapsed = Utc::now().signed_duration_since(ts);
-                        self.ui_remote.pong_recvd(elapsed);
+                    Envelope::Reply { ok, text } => {
+                        self.events.reply_recvd(ok, text);
                         Ok(())
-                    }
-                    Err(err) => {
-                        self.ui_remote.client_error(err.into());
+                    },
+                    Envelope::Nick(nick) => {
+                        self.shared.state.lock().unwrap().nick = nick.clone();
+                        self.events.nick_changed(nick);
+                        Ok(())
+                    },
+                    // Only unencrypted messages are recorded:
+                    Envelope::History(ref entries) if self.opts.identity.is_some() => {
+                        if !entries.is_empty() {
+                            self.events.notice_recvd(format!("Ignored {} unencrypted messages \
+                                from the history.", entries.len()));
+                        }
+                        Ok(())
+                    },
+                    Envelope::History(entries) => {
+                        self.events.history_recvd(entries);
+                        Ok(())
+                    },
+                    Envelope::Join(room) => {
+                        {
+                            let rooms = &mut self.shared.state.lock().unwrap().rooms;
+                            rooms.retain(|r| r != &room);
+                            rooms.push(room.clone());
+                        }
+                        self.events.room_joined(room);
+                        Ok(())
+                    },
+                    Envelope::Part(room) => {
+                        self.shared.state.lock().unwrap().rooms.retain(|r| r != &room);
+                        self.events.room_parted(room);
+                        Ok(())
+                    },
+                    Envelope::RoomList(rooms) => {
+                        self.events.room_list_recvd(rooms);
+                        Ok(())
+                    },
+                    Envelope::Direct { from, text, .. } => {
+                        let from = from.unwrap_or_else(|| "Server".to_owned());
+                        if self.opts.identity.is_some() {
+                            self.reject_unencrypted(&from, None);
+                        } else {
+                            self.events.direct_recvd(from, text, times);
+                        }
+                        Ok(())
+                    },
+                    Envelope::Keys(keys) => {
+                        self.set_keys(keys);
+                        Ok(())
+                    },
+                    Envelope::Nicks(nicks) => {
+                        self.shared.state.lock().unwrap().nicks = nicks;
+                        Ok(())
+                    },
+                    Envelope::Sealed { room, from, parts } => {
+                        let from = from.unwrap_or_else(|| "Server".to_owned());
+                        self.open_sealed(room, from, parts, times);
+                        Ok(())
+                    },
+                    Envelope::File { from, id, msg, .. } => {
+                        let from = from.unwrap_or_else(|| "Server".to_owned());
+                        self.open_file(from, id, msg)
+                    },
+                    env @ Envelope::HistoryRequest { .. } | env @ Envelope::ListRequest |
+                            env @ Envelope::Key(..) | env @ Envelope::Stamped { .. } => {
+                        self.events.unknown_recvd(format!("unexpected message from server: {:?}", env));
                         Ok(())
                     },
                 }
-            }
+            },
+            Ok(Decoded::Unknown(desc)) => {
+                self.events.unknown_recvd(desc);
+                Ok(())
+            },
+            // Nothing sent by an incompatible server can be understood, so
+            // retrying is pointless:
+            Err(ref err) if protocol::is_incompatible(err) => {
+                self.events.client_error(err.clone());
+                self.shared.state.lock().unwrap().stopped = true;
+                self.output.close_with_reason(CloseCode::Protocol, err.to_string())
+            },
+            Err(err) => {
+                self.events.client_error(err);
+                Ok(())
+            },
+        }
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<(), ws::Error> {
+        match (event, self.opts.ping_interval) {
+            (PING_TIMEOUT, Some(_)) if self.opts.max_missed_pings != 0 &&
+                    self.missed_pings >= self.opts.max_missed_pings => {
+                // A close frame would go unanswered, so the connection is
+                // dropped with an error instead:
+                self.timed_out = true;
+                self.events.notice_recvd(format!("The server has not answered {} pings. \
+                    Closing the connection.", self.missed_pings));
+                Err(io::Error::new(io::ErrorKind::TimedOut, "No reply to pings.").into())
+            },
+            (PING_TIMEOUT, Some(interval)) => {
+                self.output.send(Envelope::Ping(Pingstamp::now()).to_message()?)?;
+                self.missed_pings += 1;
+                self.output.timeout(millis(interval), PING_TIMEOUT)
+            },
+            _ => Ok(()),
         }
     }
 
     fn on_close(&mut self, code: CloseCode, reason: &str) {
-        self.ui_remote.client_closed(code, reason.to_owned());
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.open = false;
+            if code == AUTH_FAILED {
+                // Retrying with the same password is pointless:
+                state.stopped = true;
+            }
+        }
+        self.events.client_closed(code, reason.to_owned());
     }
 
     fn on_error(&mut self, err: ws::Error) {
-        self.ui_remote.client_error(err.into());
+        if self.timed_out { return; }
+        self.events.client_error(err.into());
+    }
+
+    fn upgrade_ssl_client(&mut self, stream: TcpStream, url: &Url)
+            -> Result<SslStream<TcpStream>, ws::Error> {
+        tls::connect(stream, url, self.opts.insecure)
+    }
+
+    fn build_request(&mut self, url: &Url) -> Result<Request, ws::Error> {
+        let mut req = Request::from_url(url)?;
+        if let Some(ref password) = self.opts.password {
+            req.headers_mut().push((PASSWORD_HEADER.to_owned(), password.as_bytes().to_vec()));
+        }
+        Ok(req)
     }
 }
 
 
 struct ClientHandlerFactory {
-    ui_remote: UiRemote
+    events: Arc<dyn EventSink>,
+    shared: Arc<Shared>,
+    opts: ClientOptions,
 }
 
 impl Factory for ClientHandlerFactory {
     type Handler = ClientHandler;
 
     fn connection_made(&mut self, output: WsSender) -> Self::Handler {
-        ClientHandler { ui_remote: self.ui_remote.clone(), output }
+        ClientHandler {
+            events: self.events.clone(),
+            output,
+            shared: self.shared.clone(),
+            opts: self.opts.clone(),
+            missed_pings: 0,
+            timed_out: false,
+        }
+    }
+}
+
+
+/// Connects to `url` and runs the connection until it closes. Returns true
+/// if the connection was opened.
+fn run_connection(url: &Url, opts: &ClientOptions, shared: &Arc<Shared>,
+        events: &Arc<dyn EventSink>) -> Result<bool, Error> {
+    let factory = ClientHandlerFactory { events: events.clone(), shared: shared.clone(),
+        opts: opts.clone() };
+    let mut ws = WsBuilder::new()
+        .with_settings(Settings {
+            // Defaults to true:
+            panic_on_internal: false,
+            ..Settings::default()
+        })
+        .build(factory)?;
+
+    let mut connect_url = url.clone();
+    {
+        let mut state = shared.state.lock().unwrap();
+        connect_url.query_pairs_mut().append_pair("nick", &state.nick);
+        state.sender = Some(ws.broadcaster());
+        state.open = false;
+        state.opened = false;
     }
+
+    let res = ws.connect(connect_url).map(|_| ()).and_then(|_| ws.run().map(|_| ()));
+    let mut state = shared.state.lock().unwrap();
+    state.sender = None;
+    state.open = false;
+    res.map(|_| state.opened).map_err(Error::from)
 }
 
 
 /// A websocket chat client.
 pub struct Client {
     _th: JoinHandle<()>,
-    sender: WsSender,
+    shared: Arc<Shared>,
     url: Url,
+    identity: Option<Arc<Identity>>,
 }
 
 impl Client {
-    pub fn new(url: Url, ui_remote: UiRemote) -> Result<Client, Error> {
-        let factory = ClientHandlerFactory { ui_remote: ui_remote.clone() };
-        let mut ws = WsBuilder::new()
-            .with_settings(Settings {
-                // Defaults to true:
-                panic_on_internal: false,
-                ..Settings::default()
-            })
-            .build(factory)?;
-        let sender = ws.broadcaster();
-        ws.connect(url.clone())?;
+    /// Connects to the server at `url`, requesting `nick` as a nickname.
+    /// The connection is encrypted if the url scheme is 'wss'.
+    ///
+    /// The connection is made and, if enabled, re-made on a separate thread.
+    /// `Event::ClientDisconnected` is sent once the client has stopped
+    /// trying.
+    pub fn new(url: Url, nick: &str, opts: ClientOptions, events: Arc<dyn EventSink>)
+            -> Result<Client, Error> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(ClientState {
+                sender: None,
+                open: false,
+                opened: false,
+                nick: nick.to_owned(),
+                rooms: Vec::new(),
+                keys: BTreeMap::new(),
+                latest_sealed: BTreeMap::new(),
+                nicks: Vec::new(),
+                stopped: false,
+                retry_now: false,
+            }),
+            wake: Condvar::new(),
+        });
+        let th_shared = shared.clone();
+        let th_url = url.clone();
+        let identity = opts.identity.clone();
 
         let _th = thread::Builder::new()
                 .name("chat-client".to_owned())
                 .spawn(move || {
-            if let Err(err) = ws.run() {
-                ui_remote.client_error(err.into());
+            let mut attempt = 0;
+            loop {
+                match run_connection(&th_url, &opts, &th_shared, &events) {
+                    Ok(true) => attempt = 0,
+                    Ok(false) => {},
+                    Err(err) => events.client_error(err),
+                }
+
+                let mut state = th_shared.state.lock().unwrap();
+                let reconnect = &opts.reconnect;
+                if !reconnect.enabled || state.stopped { break; }
+                attempt += 1;
+                if reconnect.max_attempts != 0 && attempt > reconnect.max_attempts {
+                    events.notice_recvd(format!("Giving up on reconnecting after {} \
+                        attempts.", reconnect.max_attempts));
+                    break;
+                }
+
+                // Wait, unless woken to retry immediately or to stop:
+                let delay = reconnect.delay(attempt);
+                events.client_reconnecting(delay, attempt);
+                let deadline = Instant::now() + delay;
+                while !state.stopped && !state.retry_now {
+                    let now = Instant::now();
+                    if now >= deadline { break; }
+                    state = th_shared.wake.wait_timeout(state, deadline - now).unwrap().0;
+                }
+                if state.stopped { break; }
+                state.retry_now = false;
             }
+            events.client_disconnected();
         })?;
 
         Ok(Client {
             _th,
-            sender,
+            shared,
             url,
+            identity,
         })
     }
 
@@ -113,17 +592,191 @@ impl Client {
         &self.url
     }
 
-    pub fn send<M: Into<Message>>(&self, msg: M) -> Result<(), Error> {
-        let ts: Vec<u8> = bincode::serialize(&Pingstamp::now())?;
-        self.sender.send(msg).and(self.sender.send(ts)).map_err(Error::from)
+    /// Returns true if the connection is open.
+    pub fn is_connected(&self) -> bool {
+        self.shared.state.lock().unwrap().open
+    }
+
+    /// Sends a protocol message to the server.
+    pub fn send(&self, env: &Envelope) -> Result<(), Error> {
+        let state = self.shared.state.lock().unwrap();
+        match state.sender {
+            Some(ref sender) if state.open => sender.send(env.to_message()?).map_err(Error::from),
+            _ => Err(Error::not_connected()),
+        }
+    }
+
+    /// Requests a nickname change.
+    pub fn set_nick(&self, nick: &str) -> Result<(), Error> {
+        self.send(&Envelope::Nick(nick.to_owned()))
+    }
+
+    /// Requests up to `count` of the most recent messages in `room` from the
+    /// server.
+    pub fn request_history(&self, room: &str, count: u32) -> Result<(), Error> {
+        self.send(&Envelope::HistoryRequest { room: room.to_owned(), count })
+    }
+
+    /// Requests to join a room.
+    pub fn join(&self, room: &str) -> Result<(), Error> {
+        self.send(&Envelope::Join(room.to_owned()))
+    }
+
+    /// Requests to leave a room.
+    pub fn part(&self, room: &str) -> Result<(), Error> {
+        self.send(&Envelope::Part(room.to_owned()))
+    }
+
+    /// Requests the list of rooms.
+    pub fn list_rooms(&self) -> Result<(), Error> {
+        self.send(&Envelope::ListRequest)
+    }
+
+    /// Returns the nicknames of all participants, as last listed by the
+    /// server.
+    pub fn nicks(&self) -> Vec<String> {
+        self.shared.state.lock().unwrap().nicks.clone()
+    }
+
+    /// Returns the key fingerprint of the participant using `nick`.
+    pub fn fingerprint(&self, nick: &str) -> Option<String> {
+        self.shared.state.lock().unwrap().keys.get(nick).map(|k| crypto::fingerprint(k))
+    }
+
+    /// Sends a private message to the participant using the nickname `to`,
+    /// encrypted if we have an identity.
+    pub fn send_direct(&self, to: &str, text: &str) -> Result<(), Error> {
+        match self.identity {
+            Some(ref id) => {
+                let times = Timestamps::now();
+                let part = {
+                    let state = self.shared.state.lock().unwrap();
+                    let key = state.keys.get(to).ok_or_else(|| Error::no_key(to))?;
+                    let context = Context::Message { room: None, from: &state.nick, to,
+                        sent: times.sent };
+                    SealedPart { to: to.to_owned(), data: id.seal(key, &context, text)? }
+                };
+                self.send(&Envelope::Sealed { room: None, from: None, parts: vec![part] }
+                    .stamped(times))
+            },
+            None => self.send(&Envelope::Direct { from: None, to: to.to_owned(),
+                text: text.to_owned() }.stamped(Timestamps::now())),
+        }
+    }
+
+    /// Sends a chat message to `room`.
+    ///
+    /// If we have an identity, the message is encrypted for every other
+    /// participant with a key. The server delivers it to those in `room`.
+    pub fn send_chat(&self, room: &str, text: &str) -> Result<(), Error> {
+        match self.identity {
+            Some(ref id) => {
+                let times = Timestamps::now();
+                let parts = {
+                    let state = self.shared.state.lock().unwrap();
+                    id.seal_parts(state.keys.iter().filter(|&(nick, _)| nick != &state.nick),
+                        room, &state.nick, times.sent, text)?
+                };
+                self.send(&Envelope::Sealed { room: Some(room.to_owned()), from: None, parts }
+                    .stamped(times))
+            },
+            None => self.send(&Envelope::chat(room, text).stamped(Timestamps::now())),
+        }
+    }
+
+    /// Sends a file transfer message about transfer `id` to the participant
+    /// using the nickname `to`, encrypted if we have an identity.
+    pub fn send_file(&self, to: &str, id: u64, msg: FileMsg) -> Result<(), Error> {
+        let msg = match self.identity {
+            Some(ref identity) => {
+                let state = self.shared.state.lock().unwrap();
+                let key = state.keys.get(to).ok_or_else(|| Error::no_key(to))?;
+                identity.seal_file(key, &state.nick, to, id, &msg)?
+            },
+            None => msg,
+        };
+        self.send(&Envelope::File { from: None, to: to.to_owned(), id, msg })
+    }
+
+    /// Sends a ping to the server. The round-trip time is reported once it
+    /// replies.
+    pub fn ping(&self) -> Result<(), Error> {
+        self.send(&Envelope::Ping(Pingstamp::now()))
+    }
+
+    /// Skips the remainder of a pending reconnection delay. Returns false if
+    /// no reconnection is pending.
+    pub fn reconnect(&self) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.sender.is_some() || state.stopped { return false; }
+        state.retry_now = true;
+        self.shared.wake.notify_all();
+        true
     }
 
+    /// Cancels a pending reconnection. Returns false if no reconnection is
+    /// pending.
+    pub fn cancel(&self) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.open || state.stopped { return false; }
+        state.stopped = true;
+        if let Some(ref sender) = state.sender {
+            // Abandon the connection attempt in progress:
+            sender.shutdown().ok();
+        }
+        self.shared.wake.notify_all();
+        true
+    }
+
+    /// Closes the connection without reconnecting.
     pub fn close(&self) -> Result<(), Error>  {
-        self.sender.close(CloseCode::Normal).map_err(Error::from)
+        let mut state = self.shared.state.lock().unwrap();
+        state.stopped = true;
+        self.shared.wake.notify_all();
+        match state.sender {
+            Some(ref sender) => sender.close(CloseCode::Normal).map_err(Error::from),
+            None => Ok(()),
+        }
     }
 
-    #[allow(dead_code)]
     pub fn shutdown(&self) -> Result<(), Error>  {
-        self.sender.shutdown().map_err(Error::from)
+        let mut state = self.shared.state.lock().unwrap();
+        state.stopped = true;
+        self.shared.wake.notify_all();
+        match state.sender {
+            Some(ref sender) => sender.shutdown().map_err(Error::from),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Squelch any errors:
+        self.shutdown().ok();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_are_jittered_and_capped() {
+        let opts = ReconnectOptions {
+            enabled: true,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            max_attempts: 0,
+        };
+        for _ in 0..50 {
+            let first = opts.delay(1);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let third = opts.delay(3);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+            let last = opts.delay(40);
+            assert!(last >= Duration::from_millis(500) && last <= Duration::from_millis(1000));
+        }
     }
 }
@@ -0,0 +1,309 @@
+//! The registry of console commands.
+//!
+//! Each command declares its name, aliases, arguments and help text along
+//! with the function handling it, so that input lines can be parsed,
+//! validated and dispatched (and help output) in one place.
+
+use ::Error;
+
+
+/// The kind of value taken by an argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamKind {
+    /// A single word.
+    Word,
+    /// A non-negative whole number.
+    Count,
+    /// The remainder of the line, including any spaces.
+    Text,
+}
+
+
+/// An argument declared by a command.
+#[derive(Debug, Clone)]
+pub struct Param {
+    name: &'static str,
+    kind: ParamKind,
+    required: bool,
+}
+
+impl Param {
+    /// Returns a required argument.
+    pub fn required(name: &'static str, kind: ParamKind) -> Param {
+        Param { name, kind, required: true }
+    }
+
+    /// Returns an optional argument.
+    pub fn optional(name: &'static str, kind: ParamKind) -> Param {
+        Param { name, kind, required: false }
+    }
+
+    /// Returns the argument as shown in usage strings, e.g. '{url}' or
+    /// '[password]'.
+    fn usage(&self) -> String {
+        if self.required { format!("{{{}}}", self.name) } else { format!("[{}]", self.name) }
+    }
+}
+
+
+/// The validated arguments given to a command, in declaration order.
+#[derive(Debug, Default)]
+pub struct Args {
+    values: Vec<Option<String>>,
+}
+
+impl Args {
+    /// Returns the value of argument `idx`, if given.
+    pub fn get(&self, idx: usize) -> Option<&str> {
+        self.values.get(idx).and_then(|v| v.as_ref()).map(String::as_str)
+    }
+
+    /// Returns the value of `Count` argument `idx`, if given.
+    pub fn count(&self, idx: usize) -> Option<u32> {
+        self.get(idx).and_then(|v| v.parse().ok())
+    }
+}
+
+
+/// A function handling a command for a `T`.
+pub type Handler<T> = fn(&mut T, &Args) -> Result<(), Error>;
+
+
+/// A command, built by chaining calls starting with `Command::new`.
+pub struct Command<T> {
+    name: &'static str,
+    aliases: Vec<&'static str>,
+    args: Vec<Param>,
+    summary: &'static str,
+    details: Vec<&'static str>,
+    handler: Handler<T>,
+}
+
+impl<T> Command<T> {
+    /// Returns a new command named `name` (without the leading '/')
+    /// handled by `handler`.
+    pub fn new(name: &'static str, handler: Handler<T>) -> Command<T> {
+        Command {
+            name,
+            aliases: Vec::new(),
+            args: Vec::new(),
+            summary: "",
+            details: Vec::new(),
+            handler,
+        }
+    }
+
+    /// Adds another name for the command.
+    pub fn alias(mut self, alias: &'static str) -> Command<T> {
+        self.aliases.push(alias);
+        self
+    }
+
+    /// Adds an argument. Optional arguments must follow required ones and
+    /// `Text` arguments must be last.
+    pub fn param(mut self, arg: Param) -> Command<T> {
+        debug_assert!(self.args.last().map(|a| a.kind != ParamKind::Text).unwrap_or(true));
+        debug_assert!(!arg.required || self.args.iter().all(|a| a.required));
+        self.args.push(arg);
+        self
+    }
+
+    /// Sets the one line description of what the command does, completing
+    /// the sentence "Type '/command' to...".
+    pub fn summary(mut self, summary: &'static str) -> Command<T> {
+        self.summary = summary;
+        self
+    }
+
+    /// Adds a line to the detailed help shown by '/help {command}'.
+    pub fn details(mut self, line: &'static str) -> Command<T> {
+        self.details.push(line);
+        self
+    }
+
+    /// Returns the usage string of the command, e.g.
+    /// '/connect {url} [password]'.
+    pub fn usage(&self) -> String {
+        let mut usage = format!("/{}", self.name);
+        for arg in &self.args {
+            usage.push(' ');
+            usage.push_str(&arg.usage());
+        }
+        usage
+    }
+
+    /// Returns the one line description of the command, including its
+    /// aliases, e.g. "Type '/close' to close the current connection."
+    pub fn summary_line(&self) -> String {
+        let aliases: Vec<String> = self.aliases.iter().map(|a| format!("'/{}'", a)).collect();
+        match aliases.len() {
+            0 => format!("Type '{}' to {}", self.usage(), self.summary),
+            _ => format!("Type '{}' (or {}) to {}", self.usage(), aliases.join(", "),
+                self.summary),
+        }
+    }
+
+    /// Returns the lines of detailed help.
+    pub fn details_lines(&self) -> &[&'static str] {
+        &self.details
+    }
+
+    /// Returns true if `name` is the name or an alias of the command.
+    fn is_named(&self, name: &str) -> bool {
+        self.name == name || self.aliases.contains(&name)
+    }
+
+    /// Parses and validates the arguments following the command name.
+    fn parse_args(&self, mut rest: &str) -> Result<Args, Error> {
+        let mut values = Vec::with_capacity(self.args.len());
+        for arg in &self.args {
+            rest = rest.trim_start();
+            let value = match arg.kind {
+                ParamKind::Text => {
+                    let text = rest;
+                    rest = "";
+                    text
+                },
+                ParamKind::Word | ParamKind::Count => {
+                    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                    let (word, remainder) = rest.split_at(end);
+                    rest = remainder;
+                    word
+                },
+            };
+
+            if value.is_empty() {
+                if arg.required {
+                    return Err(Error::command_usage(format!("Missing argument: {}.", arg.name),
+                        self.usage()));
+                }
+                values.push(None);
+                continue;
+            }
+            if arg.kind == ParamKind::Count && value.parse::<u32>().is_err() {
+                return Err(Error::command_usage(format!("Invalid {}: '{}' is not a number.",
+                    arg.name, value), self.usage()));
+            }
+            values.push(Some(value.to_owned()));
+        }
+
+        match rest.trim() {
+            "" => Ok(Args { values }),
+            extra => Err(Error::command_usage(format!("Unexpected argument: '{}'.", extra),
+                self.usage())),
+        }
+    }
+}
+
+
+/// The set of commands understood by a `T`.
+pub struct Registry<T> {
+    commands: Vec<Command<T>>,
+}
+
+impl<T> Registry<T> {
+    /// Returns a new, empty registry.
+    pub fn new() -> Registry<T> {
+        Registry { commands: Vec::new() }
+    }
+
+    /// Adds a command. Commands are listed in the order they are added.
+    pub fn register(&mut self, command: Command<T>) {
+        debug_assert!(self.find(command.name).is_none(),
+            "command '/{}' registered twice", command.name);
+        self.commands.push(command);
+    }
+
+    /// Returns the command named `name` (without the leading '/') or with
+    /// `name` as an alias.
+    pub fn find(&self, name: &str) -> Option<&Command<T>> {
+        self.commands.iter().find(|c| c.is_named(name))
+    }
+
+    /// Returns all commands.
+    pub fn commands(&self) -> &[Command<T>] {
+        &self.commands
+    }
+
+    /// Returns the names and aliases of all commands, with the leading '/'.
+    pub fn names(&self) -> Vec<String> {
+        self.commands.iter()
+            .flat_map(|c| Some(c.name).into_iter().chain(c.aliases.iter().cloned()))
+            .map(|name| format!("/{}", name))
+            .collect()
+    }
+
+    /// Parses a command line starting with '/', returning the handler of
+    /// the command and its validated arguments.
+    pub fn parse(&self, line: &str) -> Result<(Handler<T>, Args), Error> {
+        let line = line.strip_prefix('/').unwrap_or(line);
+        let end = line.find(char::is_whitespace).unwrap_or(line.len());
+        let (name, rest) = line.split_at(end);
+        let command = self.find(name).ok_or_else(|| Error::unknown_command(name))?;
+        Ok((command.handler, command.parse_args(rest)?))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::ErrorKind;
+
+    fn noop(_: &mut (), _: &Args) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn registry() -> Registry<()> {
+        let mut commands = Registry::new();
+        commands.register(Command::new("history", noop)
+            .param(Param::optional("count", ParamKind::Count)));
+        commands.register(Command::new("msg", noop)
+            .alias("m")
+            .param(Param::required("nick", ParamKind::Word))
+            .param(Param::required("text", ParamKind::Text)));
+        commands.register(Command::new("connect", noop)
+            .param(Param::required("url", ParamKind::Word))
+            .param(Param::optional("password", ParamKind::Word)));
+        commands
+    }
+
+    /// Returns the reason given by a usage error.
+    fn usage_error(line: &str) -> String {
+        match registry().parse(line).map(|_| ()).unwrap_err().kind() {
+            ErrorKind::CommandUsage { reason, .. } => reason.clone(),
+            kind => panic!("unexpected error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn parses_args() {
+        let (_, args) = registry().parse("/m bob  hello,  world ").unwrap();
+        assert_eq!((args.get(0), args.get(1)), (Some("bob"), Some("hello,  world ")));
+        let (_, args) = registry().parse("/history 20").unwrap();
+        assert_eq!(args.count(0), Some(20));
+        let (_, args) = registry().parse("/connect localhost:3030").unwrap();
+        assert_eq!((args.get(0), args.get(1)), (Some("localhost:3030"), None));
+    }
+
+    #[test]
+    fn rejects_invalid_args() {
+        assert_eq!(usage_error("/msg bob"), "Missing argument: text.");
+        assert_eq!(usage_error("/history ten"), "Invalid count: 'ten' is not a number.");
+        assert_eq!(usage_error("/connect url password extra"),
+            "Unexpected argument: 'extra'.");
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        let err = registry().parse("/nope").map(|_| ()).unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::UnknownCommand(ref name) if name == "nope"));
+    }
+
+    #[test]
+    fn lists_names_and_usage() {
+        let commands = registry();
+        assert_eq!(commands.names(), ["/history", "/msg", "/m", "/connect"]);
+        assert_eq!(commands.find("m").unwrap().usage(), "/msg {nick} {text}");
+    }
+}
@@ -0,0 +1,133 @@
+//! Tab completion of commands, nicknames and server urls.
+
+
+/// The sources of completion candidates.
+pub struct Sources<'s> {
+    /// Command names, including the leading '/'.
+    pub commands: &'s [String],
+    /// The nicknames of connected participants.
+    pub nicks: &'s [String],
+    /// Previously connected to server urls, most recent first.
+    pub targets: &'s [String],
+}
+
+
+/// The candidates for completing a word and the one last inserted.
+#[derive(Debug)]
+pub struct Completion {
+    // The byte position of the word within the line:
+    start: usize,
+    candidates: Vec<String>,
+    idx: usize,
+}
+
+impl Completion {
+    /// Returns the completions of the last word of `before`, the part of the
+    /// line before the cursor, or `None` if there are none.
+    ///
+    /// The first word of a line completes to a command if it starts with
+    /// '/'. The url argument of '/connect' and '/open' completes to a
+    /// previous target. Other words complete to nicknames.
+    pub fn new(before: &str, sources: &Sources) -> Option<Completion> {
+        let start = before.rfind(char::is_whitespace)
+            .map(|i| i + before[i..].chars().next().unwrap().len_utf8())
+            .unwrap_or(0);
+        let (prefix, word) = before.split_at(start);
+        let mut args = prefix.split_whitespace();
+
+        let candidates: Vec<String> = match (args.next(), args.next()) {
+            (None, _) if word.starts_with('/') => sources.commands.iter()
+                .filter(|cmd| cmd.starts_with(word))
+                .map(|cmd| format!("{} ", cmd))
+                .collect(),
+            (Some("/connect"), None) | (Some("/open"), None) => sources.targets.iter()
+                .filter(|url| url.starts_with(word))
+                .map(|url| format!("{} ", url))
+                .collect(),
+            (first, _) => {
+                let word = word.to_lowercase();
+                // A nickname starting a chat line addresses its owner:
+                let suffix = if first.is_none() { ": " } else { " " };
+                sources.nicks.iter()
+                    .filter(|nick| nick.to_lowercase().starts_with(&word))
+                    .map(|nick| format!("{}{}", nick, suffix))
+                    .collect()
+            },
+        };
+
+        if candidates.is_empty() {
+            None
+        } else {
+            Some(Completion { start, candidates, idx: 0 })
+        }
+    }
+
+    /// Returns the byte position of the completed word within the line.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Returns the current candidate.
+    pub fn current(&self) -> &str {
+        &self.candidates[self.idx]
+    }
+
+    /// Advances to the next candidate, wrapping around after the last.
+    pub fn next(&mut self) -> &str {
+        self.idx = (self.idx + 1) % self.candidates.len();
+        &self.candidates[self.idx]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|&s| s.to_owned()).collect()
+    }
+
+    fn complete(before: &str) -> Option<Vec<String>> {
+        let commands = strings(&["/connect", "/close", "/join", "/open"]);
+        let nicks = strings(&["Alice", "albert", "bob"]);
+        let targets = strings(&["ws://chat.example.com", "wss://secure.example.com"]);
+        let sources = Sources { commands: &commands, nicks: &nicks, targets: &targets };
+        Completion::new(before, &sources).map(|mut completion| {
+            let first = completion.current().to_owned();
+            let mut candidates = vec![first.clone()];
+            while completion.next() != first {
+                candidates.push(completion.current().to_owned());
+            }
+            candidates
+        })
+    }
+
+    #[test]
+    fn completes_commands() {
+        assert_eq!(complete("/c"), Some(strings(&["/connect ", "/close "])));
+        assert_eq!(complete("/x"), None);
+    }
+
+    #[test]
+    fn completes_targets_of_connect() {
+        assert_eq!(complete("/connect wss"), Some(strings(&["wss://secure.example.com "])));
+        assert_eq!(complete("/open "), Some(strings(&["ws://chat.example.com ",
+            "wss://secure.example.com "])));
+    }
+
+    #[test]
+    fn completes_nicks_ignoring_case() {
+        assert_eq!(complete("al"), Some(strings(&["Alice: ", "albert: "])));
+        assert_eq!(complete("hi AL"), Some(strings(&["Alice ", "albert "])));
+        assert_eq!(complete("hi carol"), None);
+    }
+
+    #[test]
+    fn start_is_that_of_the_last_word() {
+        let nicks = strings(&["bob"]);
+        let sources = Sources { commands: &[], nicks: &nicks, targets: &[] };
+        assert_eq!(Completion::new("hi  b", &sources).unwrap().start(), 4);
+        assert_eq!(Completion::new("é b", &sources).unwrap().start(), 3);
+    }
+}
@@ -0,0 +1,381 @@
+//! A local control socket through which other programs can drive a running
+//! instance.
+//!
+//! Each line written to the socket is a JSON request, e.g.
+//! `{"command": "join", "room": "#rust"}`, answered with a `result` line to
+//! the program which sent it, e.g. `{"event": "result", "ok": true}`. Every
+//! event of the current connection is written back to each connected program
+//! as a line of JSON, e.g. `{"event": "message", "room": "#rust", "from":
+//! "bob", ...}`.
+
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, Shutdown};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender as MpscSender, Receiver as MpscReceiver, SyncSender};
+use std::thread;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde_json::{self, Value};
+use headsup::{Error, Event, EventSink};
+use protocol::{Presence, FileMsg};
+
+
+/// How long writing an event to a program may block before it is
+/// disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The number of lines which may wait to be written to a program before it
+/// is disconnected.
+const QUEUE_LEN: usize = 1000;
+
+
+/// A request read from the control socket. Requests mirror the commands typed
+/// at the prompt.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Connect { url: String, password: Option<String> },
+    /// Sends a chat message to the active room. Unlike typed input, text
+    /// starting with '/' is sent as is.
+    Send { text: String },
+    Msg { to: String, text: String },
+    Join { room: String },
+    Part { room: Option<String> },
+    Nick { nick: String },
+    Close,
+    /// Handles a line as if it had been typed at the prompt.
+    Input { line: String },
+}
+
+
+/// Answers a request to the program which sent it.
+pub struct Reply {
+    queue: SyncSender<String>,
+}
+
+impl Reply {
+    /// Writes the outcome of the request, and the error if it failed.
+    pub fn send(self, res: &Result<(), Error>) {
+        let value = match *res {
+            Ok(()) => json!({ "event": "result", "ok": true }),
+            Err(ref err) => json!({ "event": "result", "ok": false, "text": err.to_string() }),
+        };
+        self.queue.try_send(format!("{}\n", value)).ok();
+    }
+}
+
+
+/// The queues of lines to be written to the programs connected to the
+/// control socket.
+type Clients = Arc<Mutex<Vec<SyncSender<String>>>>;
+
+/// Queues a line of JSON for each client without waiting for it to be
+/// written, disconnecting those which have fallen too far behind or cannot
+/// be written to.
+fn broadcast(clients: &Clients, value: &Value) {
+    let line = format!("{}\n", value);
+    if let Ok(mut clients) = clients.lock() {
+        clients.retain(|c| c.try_send(line.clone()).is_ok());
+    }
+}
+
+
+/// A listening control socket. The socket file is removed when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+    clients: Clients,
+    requests: MpscReceiver<(Request, Reply)>,
+}
+
+impl ControlSocket {
+    /// Listens on the socket file at `path`, replacing the file if it is a
+    /// socket left over from an instance which is no longer running. Only the
+    /// owner may connect.
+    pub fn bind(path: &Path) -> Result<ControlSocket, Error> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                    "A file which is not a socket exists at the path.").into());
+            }
+            if UnixStream::connect(path).is_err() {
+                fs::remove_file(path)?;
+            }
+        }
+        let listener = UnixListener::bind(path)?;
+        if let Err(err) = fs::set_permissions(path, Permissions::from_mode(0o600)) {
+            fs::remove_file(path).ok();
+            return Err(err.into());
+        }
+        let clients = Clients::default();
+        let (tx, requests) = mpsc::channel();
+
+        let accepted = clients.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => accept(stream, &accepted, tx.clone()),
+                    Err(_) => continue,
+                }
+            }
+        });
+
+        Ok(ControlSocket { path: path.to_owned(), clients, requests })
+    }
+
+    /// Returns a sink writing each event to the connected programs.
+    pub fn sink(&self) -> Arc<dyn EventSink> {
+        Arc::new(ControlSink { clients: self.clients.clone() })
+    }
+
+    /// Returns the next request received, if any, and the means to answer
+    /// it.
+    pub fn try_recv(&self) -> Option<(Request, Reply)> {
+        self.requests.try_recv().ok()
+    }
+
+    /// Reports an error of the user interface to the connected programs.
+    pub fn error(&self, text: &str) {
+        broadcast(&self.clients, &json!({ "event": "error", "text": text }));
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+
+/// Adds a newly connected program, writing the lines queued for it and
+/// reading its requests on new threads.
+fn accept(mut stream: UnixStream, clients: &Clients, tx: MpscSender<(Request, Reply)>) {
+    let reader = match stream.try_clone() {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+    if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_err() { return; }
+    let (queue, lines) = mpsc::sync_channel::<String>(QUEUE_LEN);
+    if let Ok(mut clients) = clients.lock() {
+        clients.push(queue.clone());
+    }
+
+    // Once the queue is dropped or writing fails, the program is
+    // disconnected, which also ends the reading thread:
+    thread::spawn(move || {
+        for line in lines {
+            if stream.write_all(line.as_bytes()).is_err() { break; }
+        }
+        stream.shutdown(Shutdown::Both).ok();
+    });
+
+    thread::spawn(move || {
+        for line in BufReader::new(&reader).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() { continue; }
+            match serde_json::from_str(&line) {
+                Ok(req) => {
+                    if tx.send((req, Reply { queue: queue.clone() })).is_err() { break; }
+                },
+                // Malformed requests are only answered to their sender:
+                Err(err) => {
+                    let reply = json!({ "event": "invalid_request", "text": err.to_string() });
+                    if queue.try_send(format!("{}\n", reply)).is_err() { break; }
+                },
+            }
+        }
+    });
+}
+
+
+/// Formats a time as an RFC 3339 string.
+fn rfc3339(time: &DateTime<Utc>) -> Value {
+    Value::String(time.to_rfc3339())
+}
+
+/// Formats a peer address, which may be unknown.
+fn addr(addr: &Option<SocketAddr>) -> Option<String> {
+    addr.map(|a| a.to_string())
+}
+
+/// Returns the JSON representation of an event.
+pub fn event_json(event: &Event) -> Value {
+    match *event {
+        Event::ServerOpened(ref peer_addr, ref nick) => {
+            json!({ "event": "server_opened", "peer_addr": addr(peer_addr), "nick": nick })
+        },
+        Event::ServerClosed(code, ref reason) => {
+            let code: u16 = code.into();
+            json!({ "event": "server_closed", "code": code, "reason": reason })
+        },
+        Event::ServerError(ref err) => json!({ "event": "server_error", "text": err.to_string() }),
+        Event::ServerShutdown => json!({ "event": "server_shutdown" }),
+        Event::ClientOpened(ref peer_addr) => {
+            json!({ "event": "client_opened", "peer_addr": addr(peer_addr) })
+        },
+        Event::ClientClosed(code, ref reason) => {
+            let code: u16 = code.into();
+            json!({ "event": "client_closed", "code": code, "reason": reason })
+        },
+        Event::ClientError(ref err) => json!({ "event": "client_error", "text": err.to_string() }),
+        Event::ClientReconnecting(delay, attempt) => {
+            json!({ "event": "client_reconnecting", "delay_secs": delay.as_secs(),
+                "attempt": attempt })
+        },
+        Event::ClientDisconnected => json!({ "event": "client_disconnected" }),
+        Event::MessageRecvd(ref room, ref from, ref text, ref times) => {
+            json!({ "event": "message", "room": room, "from": from, "text": text,
+                "sent": rfc3339(&times.sent), "received": times.received.as_ref().map(rfc3339) })
+        },
+        Event::NickChanged(ref nick) => json!({ "event": "nick_changed", "nick": nick }),
+        Event::HistoryRecvd(ref entries) => {
+            let entries: Vec<Value> = entries.iter().map(|e| json!({ "time": rfc3339(&e.time),
+                "room": e.room, "from": e.from, "text": e.text })).collect();
+            json!({ "event": "history", "entries": entries })
+        },
+        Event::RoomJoined(ref room) => json!({ "event": "room_joined", "room": room }),
+        Event::RoomParted(ref room) => json!({ "event": "room_parted", "room": room }),
+        Event::RoomListRecvd(ref rooms) => {
+            let rooms: Vec<Value> = rooms.iter().map(|r| json!({ "name": r.name,
+                "members": r.members })).collect();
+            json!({ "event": "room_list", "rooms": rooms })
+        },
+        Event::DirectRecvd(ref from, ref text, ref times) => {
+            json!({ "event": "direct", "from": from, "text": text,
+                "sent": rfc3339(&times.sent), "received": times.received.as_ref().map(rfc3339) })
+        },
+        Event::NoticeRecvd(ref text) => json!({ "event": "notice", "text": text }),
+        Event::PresenceRecvd(ref room, ref who, presence) => {
+            let event = match presence { Presence::Joined => "joined", Presence::Left => "left" };
+            json!({ "event": event, "room": room, "nick": who })
+        },
+        Event::ReplyRecvd(ok, ref text) => json!({ "event": "reply", "ok": ok, "text": text }),
+        Event::UnknownRecvd(ref desc) => json!({ "event": "unknown", "text": desc }),
+        Event::PongRecvd(ref from, elapsed) => {
+            let rtt_ms = elapsed.num_microseconds().map(|us| us as f64 / 1000.0);
+            json!({ "event": "pong", "from": from, "rtt_ms": rtt_ms })
+        },
+        Event::FileRecvd(ref from, id, ref msg) => {
+            let id = format!("{:016x}", id);
+            match *msg {
+                FileMsg::Offer { ref name, size, .. } => json!({ "event": "file_offer",
+                    "from": from, "id": id, "name": name, "size": size }),
+                FileMsg::Accept { offset } => json!({ "event": "file_accept", "from": from,
+                    "id": id, "offset": offset }),
+                FileMsg::Reject => json!({ "event": "file_reject", "from": from, "id": id }),
+                // The contents of files are left out:
+                FileMsg::Chunk { offset, ref data } => json!({ "event": "file_chunk",
+                    "from": from, "id": id, "offset": offset, "len": data.len() }),
+                FileMsg::Ack { offset } => json!({ "event": "file_ack", "from": from, "id": id,
+                    "offset": offset }),
+                FileMsg::Cancel(ref reason) => json!({ "event": "file_cancel", "from": from,
+                    "id": id, "reason": reason }),
+                // Only received by clients which cannot decrypt it:
+                FileMsg::Sealed { ref data, .. } => json!({ "event": "file_sealed", "from": from,
+                    "id": id, "len": data.len() }),
+            }
+        },
+    }
+}
+
+
+/// Writes each event to the programs connected to a control socket.
+struct ControlSink {
+    clients: Clients,
+}
+
+impl EventSink for ControlSink {
+    fn send(&self, event: Event) {
+        broadcast(&self.clients, &event_json(&event));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::time::Instant;
+
+    /// Returns a path in the temporary directory at which nothing exists.
+    fn socket_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("headsup-control-{}-{}", process::id(), name));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    /// Connects to the socket at `path`, returning a reader of the lines
+    /// written back and the stream to write requests to.
+    fn connect(path: &Path) -> (BufReader<UnixStream>, UnixStream) {
+        let stream = UnixStream::connect(path).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (BufReader::new(stream.try_clone().unwrap()), stream)
+    }
+
+    /// Reads a line of JSON.
+    fn read_json(reader: &mut BufReader<UnixStream>) -> Value {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    /// Waits for the next request.
+    fn recv(socket: &ControlSocket) -> (Request, Reply) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(req) = socket.try_recv() { return req; }
+            assert!(Instant::now() < deadline, "no request");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn answers_requests_to_their_sender_only() {
+        let path = socket_path("answers");
+        let socket = ControlSocket::bind(&path).unwrap();
+        let (mut alice, mut alice_requests) = connect(&path);
+        let (mut bob, mut bob_requests) = connect(&path);
+
+        bob_requests.write_all(b"{\"command\": \"shout\"}\n").unwrap();
+        assert_eq!(read_json(&mut bob)["event"], "invalid_request");
+
+        alice_requests.write_all(b"{\"command\": \"join\", \"room\": \"#rust\"}\n").unwrap();
+        let (req, reply) = recv(&socket);
+        assert!(matches!(req, Request::Join { ref room } if room == "#rust"));
+        reply.send(&Err(Error::not_connected()));
+        assert_eq!(read_json(&mut alice),
+            json!({ "event": "result", "ok": false, "text": "Not connected." }));
+
+        // Events are written to every program:
+        socket.sink().notice_recvd("hello".to_owned());
+        for reader in &mut [alice, bob] {
+            assert_eq!(read_json(reader), json!({ "event": "notice", "text": "hello" }));
+        }
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn replaces_only_stale_sockets() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        let socket = ControlSocket::bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // The socket is in use:
+        assert!(ControlSocket::bind(&path).is_err());
+        drop(socket);
+
+        fs::write(&path, b"").unwrap();
+        assert!(ControlSocket::bind(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+//! End-to-end encryption of chat messages between participants.
+//!
+//! Each participant has a long-term X25519 keypair. Messages are encrypted
+//! separately for each recipient with AES-256-GCM using a key derived from
+//! the Diffie-Hellman secret of the sender and recipient keypairs, so that a
+//! relaying server only ever sees ciphertext. File transfer messages are
+//! encrypted the same way. The room, sender, recipient and send time of a
+//! message are authenticated along with it, so that the server cannot
+//! deliver it elsewhere, attribute it to someone else or change when it was
+//! sent.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use openssl::derive::Deriver;
+use openssl::memcmp;
+use openssl::pkey::{PKey, Private, Id};
+use openssl::rand::rand_bytes;
+use openssl::sha::{sha256, Sha256};
+use openssl::symm::{self, Cipher};
+use bincode;
+use chrono::{DateTime, Utc};
+use ::Error;
+use protocol::{SealedPart, FileMsg};
+
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+
+/// Returns the fingerprint of a public key, shown to users so that they can
+/// verify each other's keys (e.g. '3f2a 91c0 ...').
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let hash = sha256(public_key);
+    hash[..16].chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+
+/// Returns true if `given` matches the `expected` password, taking the same
+/// time regardless of where they differ.
+pub fn password_matches(expected: &str, given: &[u8]) -> bool {
+    memcmp::eq(&sha256(expected.as_bytes()), &sha256(given))
+}
+
+
+/// The context a message is sealed in. A sealed message only opens in the
+/// same context.
+#[derive(Debug, Clone, Copy)]
+pub enum Context<'a> {
+    /// A chat message sent to `room` or, if `None`, privately, by `from` to
+    /// `to` at the time `sent`.
+    Message { room: Option<&'a str>, from: &'a str, to: &'a str, sent: DateTime<Utc> },
+    /// A message about file transfer `id`, sent by `from` to `to`, of the
+    /// kind and offset given by `FileMsg::header`, so that messages cannot
+    /// be replayed at another point of the transfer.
+    File { from: &'a str, to: &'a str, id: u64, kind: u8, offset: u64 },
+}
+
+impl<'a> Context<'a> {
+    /// Returns the additional data authenticated with the message.
+    fn associated_data(&self) -> Result<Vec<u8>, Error> {
+        // The leading tag keeps the contexts of different kinds apart:
+        Ok(match *self {
+            Context::Message { room, from, to, sent } => {
+                let sent = (sent.timestamp(), sent.timestamp_subsec_nanos());
+                bincode::serialize(&(0u8, room, from, to, sent))?
+            },
+            Context::File { from, to, id, kind, offset } => {
+                bincode::serialize(&(1u8, from, to, id, kind, offset))?
+            },
+        })
+    }
+}
+
+
+/// A long-term keypair.
+pub struct Identity {
+    key: PKey<Private>,
+    public_key: Vec<u8>,
+}
+
+impl Identity {
+    /// Loads the PEM encoded private key at `path`, generating and saving a
+    /// new one if the file does not exist.
+    pub fn load_or_generate(path: &Path) -> Result<Identity, Error> {
+        let key = if path.exists() {
+            PKey::private_key_from_pem(&fs::read(path)?)?
+        } else {
+            let key = PKey::generate_x25519()?;
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            // The private key must only be readable by its owner:
+            #[cfg(unix)]
+            options.mode(0o600);
+            options.open(path)?.write_all(&key.private_key_to_pem_pkcs8()?)?;
+            key
+        };
+        if key.id() != Id::X25519 {
+            return Err(Error::invalid_identity(path.display().to_string()));
+        }
+        let public_key = key.raw_public_key()?;
+        Ok(Identity { key, public_key })
+    }
+
+    /// Returns the raw public key, as sent to other participants.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Returns the fingerprint of the public key.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+
+    /// Derives the message key shared with the owner of `peer_key`.
+    fn shared_key(&self, peer_key: &[u8]) -> Result<[u8; 32], Error> {
+        let peer = PKey::public_key_from_raw_bytes(peer_key, Id::X25519)?;
+        let mut deriver = Deriver::new(&self.key)?;
+        deriver.set_peer(&peer)?;
+        let secret = deriver.derive_to_vec()?;
+
+        // Bind the key to both public keys, in a canonical order:
+        let (a, b) = if self.public_key.as_slice() < peer_key {
+            (self.public_key.as_slice(), peer_key)
+        } else {
+            (peer_key, self.public_key.as_slice())
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(&secret);
+        hasher.update(a);
+        hasher.update(b);
+        Ok(hasher.finish())
+    }
+
+    /// Encrypts `data` for the owner of `peer_key`, sent in `context`.
+    fn seal_data(&self, peer_key: &[u8], context: &Context, data: &[u8])
+            -> Result<Vec<u8>, Error> {
+        let key = self.shared_key(peer_key)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = symm::encrypt_aead(Cipher::aes_256_gcm(), &key, Some(&nonce),
+            &context.associated_data()?, data, &mut tag)?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + TAG_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&tag);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts data sealed by the owner of `peer_key` in `context`.
+    fn open_data(&self, peer_key: &[u8], context: &Context, sealed: &[u8])
+            -> Result<Vec<u8>, Error> {
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(Error::decryption_failed());
+        }
+        let key = self.shared_key(peer_key)?;
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (tag, ciphertext) = rest.split_at(TAG_LEN);
+        symm::decrypt_aead(Cipher::aes_256_gcm(), &key, Some(nonce),
+            &context.associated_data()?, ciphertext, tag).map_err(|_| Error::decryption_failed())
+    }
+
+    /// Encrypts `text` for the owner of `peer_key`, sent in `context`.
+    pub fn seal(&self, peer_key: &[u8], context: &Context, text: &str)
+            -> Result<Vec<u8>, Error> {
+        self.seal_data(peer_key, context, text.as_bytes())
+    }
+
+    /// Encrypts `text`, sent by `from` to `room`, for each recipient, given
+    /// as nickname and public key pairs.
+    pub fn seal_parts<'k, I>(&self, recipients: I, room: &str, from: &str, sent: DateTime<Utc>,
+            text: &str) -> Result<Vec<SealedPart>, Error>
+            where I: IntoIterator<Item = (&'k String, &'k Vec<u8>)> {
+        recipients.into_iter()
+            .map(|(nick, key)| {
+                let context = Context::Message { room: Some(room), from, to: nick, sent };
+                Ok(SealedPart { to: nick.clone(), data: self.seal(key, &context, text)? })
+            })
+            .collect()
+    }
+
+    /// Decrypts a message sealed by the owner of `peer_key` in `context`.
+    pub fn open(&self, peer_key: &[u8], context: &Context, sealed: &[u8])
+            -> Result<String, Error> {
+        String::from_utf8(self.open_data(peer_key, context, sealed)?)
+            .map_err(|_| Error::decryption_failed())
+    }
+
+    /// Encrypts file transfer message `msg` about transfer `id`, sent by
+    /// `from`, for `to`, the owner of `peer_key`. Returns the message to send
+    /// in its place.
+    pub fn seal_file(&self, peer_key: &[u8], from: &str, to: &str, id: u64, msg: &FileMsg)
+            -> Result<FileMsg, Error> {
+        let (kind, offset) = msg.header();
+        let context = Context::File { from, to, id, kind, offset };
+        let data = self.seal_data(peer_key, &context, &bincode::serialize(msg)?)?;
+        Ok(FileMsg::Sealed { kind, offset, data })
+    }
+
+    /// Decrypts the contents of a `FileMsg::Sealed` sealed by the owner of
+    /// `peer_key` in `context`.
+    pub fn open_file(&self, peer_key: &[u8], context: &Context, sealed: &[u8])
+            -> Result<FileMsg, Error> {
+        let header = match *context {
+            Context::File { kind, offset, .. } => (kind, offset),
+            Context::Message { .. } => return Err(Error::decryption_failed()),
+        };
+        match bincode::deserialize::<FileMsg>(&self.open_data(peer_key, context, sealed)?) {
+            Ok(FileMsg::Sealed { .. }) | Err(_) => Err(Error::decryption_failed()),
+            // Sealed in another context than claimed:
+            Ok(ref msg) if msg.header() != header => Err(Error::decryption_failed()),
+            Ok(msg) => Ok(msg),
+        }
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Identity({})", self.fingerprint())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// Generates a new identity, saved only temporarily in the file `name`.
+    fn identity(name: &str) -> Identity {
+        let path = env::temp_dir().join(format!("headsup-crypto-{}-{}", process::id(), name));
+        let identity = Identity::load_or_generate(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        identity
+    }
+
+    #[test]
+    fn file_messages_open_only_at_their_offset() {
+        let (alice, bob) = (identity("alice"), identity("bob"));
+        let chunk = FileMsg::Chunk { offset: 64, data: b"data".to_vec() };
+        let (kind, offset, data) = match alice.seal_file(bob.public_key(), "alice", "bob", 7,
+                &chunk).unwrap() {
+            FileMsg::Sealed { kind, offset, data } => (kind, offset, data),
+            msg => panic!("not sealed: {}", msg),
+        };
+        assert_eq!((kind, offset), chunk.header());
+
+        let open = |id, kind, offset| bob.open_file(alice.public_key(),
+            &Context::File { from: "alice", to: "bob", id, kind, offset }, &data);
+        match open(7, kind, offset).unwrap() {
+            FileMsg::Chunk { offset: 64, ref data } if data == b"data" => {},
+            msg => panic!("unexpected message: {}", msg),
+        }
+        assert!(open(7, kind, 0).is_err());
+        assert!(open(7, FileMsg::Ack { offset: 64 }.header().0, offset).is_err());
+        assert!(open(8, kind, offset).is_err());
+    }
+}
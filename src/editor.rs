@@ -0,0 +1,280 @@
+//! The line editor of the console input prompt.
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+
+/// Returns the display width of `c` in terminal columns.
+fn char_width(c: char) -> usize {
+    c.width().unwrap_or(0)
+}
+
+
+/// Returns the end of `s` which fits within `width` columns, preceded by
+/// '…' if anything was cut off.
+pub fn truncate_start(s: &str, width: usize) -> String {
+    if s.width() <= width {
+        return s.to_owned();
+    }
+    let mut col = 1;
+    let mut start = s.len();
+    for (i, c) in s.char_indices().rev() {
+        col += char_width(c);
+        if col > width { break; }
+        start = i;
+    }
+    format!("…{}", &s[start..])
+}
+
+
+/// An editable line of input and its cursor.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    buf: String,
+    // The byte index of the cursor within `buf`, always at a char boundary:
+    cursor: usize,
+    // The display column of `buf` shown at the left edge of the prompt:
+    scroll: usize,
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        LineEditor::default()
+    }
+
+    /// Returns the line.
+    pub fn as_str(&self) -> &str {
+        &self.buf
+    }
+
+    /// Replaces the line, moving the cursor to its end.
+    pub fn set<S: Into<String>>(&mut self, line: S) {
+        self.buf = line.into();
+        self.cursor = self.buf.len();
+        self.scroll = 0;
+    }
+
+    /// Clears the line, returning its previous contents.
+    pub fn take(&mut self) -> String {
+        self.cursor = 0;
+        self.scroll = 0;
+        ::std::mem::take(&mut self.buf)
+    }
+
+    /// Returns the part of the line before the cursor.
+    pub fn before_cursor(&self) -> &str {
+        &self.buf[..self.cursor]
+    }
+
+    /// Replaces the part of the line from byte position `start` up to the
+    /// cursor with `text`.
+    pub fn replace_before_cursor(&mut self, start: usize, text: &str) {
+        self.buf.replace_range(start..self.cursor, text);
+        self.cursor = start + text.len();
+    }
+
+    /// Returns the position of the char before the cursor, skipping back
+    /// over zero-width (e.g. combining) chars so that they move and are
+    /// deleted together with the char they modify.
+    fn prev_boundary(&self) -> usize {
+        let mut idx = self.cursor;
+        for (i, c) in self.buf[..self.cursor].char_indices().rev() {
+            idx = i;
+            if char_width(c) > 0 { break; }
+        }
+        idx
+    }
+
+    /// Returns the position after the char at the cursor and any zero-width
+    /// chars following it.
+    fn next_boundary(&self) -> usize {
+        let mut chars = self.buf[self.cursor..].char_indices();
+        let mut idx = match chars.next() {
+            Some((_, c)) => self.cursor + c.len_utf8(),
+            None => return self.cursor,
+        };
+        for (i, c) in chars {
+            if char_width(c) > 0 { break; }
+            idx = self.cursor + i + c.len_utf8();
+        }
+        idx
+    }
+
+    /// Inserts a char at the cursor. Control chars, including line breaks
+    /// and tabs, are inserted as spaces.
+    pub fn insert(&mut self, c: char) {
+        let c = if c.is_control() { ' ' } else { c };
+        self.buf.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    /// Deletes the char before the cursor.
+    pub fn backspace(&mut self) {
+        let start = self.prev_boundary();
+        self.buf.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    /// Deletes the char at the cursor.
+    pub fn delete(&mut self) {
+        let end = self.next_boundary();
+        self.buf.drain(self.cursor..end);
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.prev_boundary();
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = self.next_boundary();
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.buf.len();
+    }
+
+    /// Deletes the word before the cursor along with any whitespace between
+    /// it and the cursor.
+    pub fn delete_word(&mut self) {
+        let before = &self.buf[..self.cursor];
+        let word_end = before.trim_end().len();
+        let start = before[..word_end].rfind(char::is_whitespace)
+            .map(|i| i + before[i..].chars().next().unwrap().len_utf8())
+            .unwrap_or(0);
+        self.buf.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    /// Deletes everything before the cursor.
+    pub fn kill_to_start(&mut self) {
+        self.buf.drain(..self.cursor);
+        self.cursor = 0;
+    }
+
+    /// Deletes everything from the cursor on.
+    pub fn kill_to_end(&mut self) {
+        self.buf.truncate(self.cursor);
+    }
+
+    /// Returns the part of the line which fits within `width` columns,
+    /// scrolled horizontally to keep the cursor in view, and the column of
+    /// the cursor within it.
+    pub fn view(&mut self, width: usize) -> (String, usize) {
+        let width = width.max(1);
+        let cursor_col = self.buf[..self.cursor].width();
+        // Leave a column for the cursor at the end of the line:
+        if cursor_col < self.scroll {
+            self.scroll = cursor_col;
+        } else if cursor_col >= self.scroll + width {
+            self.scroll = cursor_col + 1 - width;
+        }
+
+        let mut view = String::new();
+        let mut col = 0;
+        for c in self.buf.chars() {
+            let w = char_width(c);
+            if col + w <= self.scroll {
+                // Left of the view.
+            } else if col < self.scroll {
+                // A wide char partially left of the view is blanked out:
+                view.extend((self.scroll..col + w).map(|_| ' '));
+            } else if col + w <= self.scroll + width {
+                view.push(c);
+            } else {
+                break;
+            }
+            col += w;
+        }
+        (view, cursor_col - self.scroll)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(line: &str) -> LineEditor {
+        let mut editor = LineEditor::new();
+        editor.set(line);
+        editor
+    }
+
+    #[test]
+    fn cursor_moves_over_combining_chars() {
+        let mut editor = editor("ae\u{301}b");
+        editor.left();
+        assert_eq!(editor.before_cursor(), "ae\u{301}");
+        editor.left();
+        assert_eq!(editor.before_cursor(), "a");
+        editor.right();
+        assert_eq!(editor.before_cursor(), "ae\u{301}");
+        editor.home();
+        editor.left();
+        assert_eq!(editor.before_cursor(), "");
+        editor.end();
+        editor.right();
+        assert_eq!(editor.before_cursor(), "ae\u{301}b");
+    }
+
+    #[test]
+    fn edits_at_cursor() {
+        let mut editor = editor("helo");
+        editor.left();
+        editor.insert('l');
+        assert_eq!(editor.as_str(), "hello");
+        editor.backspace();
+        editor.backspace();
+        assert_eq!(editor.as_str(), "heo");
+        editor.delete();
+        assert_eq!(editor.as_str(), "he");
+        editor.insert('\t');
+        assert_eq!(editor.as_str(), "he ");
+    }
+
+    #[test]
+    fn deletes_words_and_kills() {
+        let mut editor = editor("/msg bob  hi there");
+        editor.delete_word();
+        assert_eq!(editor.as_str(), "/msg bob  hi ");
+        editor.delete_word();
+        assert_eq!(editor.as_str(), "/msg bob  ");
+        editor.delete_word();
+        assert_eq!(editor.as_str(), "/msg ");
+        editor.left();
+        editor.kill_to_end();
+        assert_eq!(editor.as_str(), "/msg");
+        editor.left();
+        editor.kill_to_start();
+        assert_eq!((editor.as_str(), editor.before_cursor()), ("g", ""));
+    }
+
+    #[test]
+    fn replaces_before_cursor() {
+        let mut editor = editor("hi al there");
+        for _ in 0.." there".len() { editor.left(); }
+        editor.replace_before_cursor(3, "alice:");
+        assert_eq!(editor.as_str(), "hi alice: there");
+        assert_eq!(editor.before_cursor(), "hi alice:");
+    }
+
+    #[test]
+    fn view_scrolls_to_cursor() {
+        let mut editor = editor("hello world");
+        assert_eq!(editor.view(5), ("orld".to_owned(), 4));
+        editor.home();
+        assert_eq!(editor.view(5), ("hello".to_owned(), 0));
+
+        let mut editor = self::editor("日本語");
+        assert_eq!(editor.view(3), ("語".to_owned(), 2));
+    }
+
+    #[test]
+    fn truncates_start() {
+        assert_eq!(truncate_start("hello", 5), "hello");
+        assert_eq!(truncate_start("hello world", 5), "…orld");
+    }
+}
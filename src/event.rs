@@ -0,0 +1,376 @@
+//! Events reported by clients and servers and the sinks which receive them.
+
+use std::fmt;
+use std::mem;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender, Receiver};
+use ws::CloseCode;
+use chrono::{self, Local};
+use protocol::{Presence, HistoryEntry, RoomInfo, Timestamps, FileMsg};
+use ::Error;
+
+
+/// Something which has happened on a client or server connection.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A client has connected to the server from the address given, using
+    /// the nickname given.
+    ServerOpened(Option<SocketAddr>, String),
+    /// A client connection to the server has closed.
+    ServerClosed(CloseCode, String),
+    /// An error has occurred on the server. Unless `Server::is_listening`
+    /// returns false, the server keeps running.
+    ServerError(Error),
+    /// The server has stopped listening.
+    ServerShutdown,
+    /// The client has connected to the server at the address given.
+    ClientOpened(Option<SocketAddr>),
+    /// The client connection has closed.
+    ClientClosed(CloseCode, String),
+    ClientError(Error),
+    /// The client will reconnect after a delay, as attempt number `u32`.
+    ClientReconnecting(Duration, u32),
+    /// The client has stopped trying to connect.
+    ClientDisconnected,
+    /// A chat message was sent to a room: room, sender and text.
+    MessageRecvd(String, String, String, Timestamps),
+    /// Our own nickname has been changed or confirmed.
+    NickChanged(String),
+    HistoryRecvd(Vec<HistoryEntry>),
+    RoomJoined(String),
+    RoomParted(String),
+    RoomListRecvd(Vec<RoomInfo>),
+    /// A private message was received: sender and text.
+    DirectRecvd(String, String, Timestamps),
+    NoticeRecvd(String),
+    /// A participant has joined or left a room: room and nickname.
+    PresenceRecvd(String, String, Presence),
+    /// The server has replied to a request, successfully or not.
+    ReplyRecvd(bool, String),
+    /// A message which could not be handled was received.
+    UnknownRecvd(String),
+    /// A ping was answered by the peer named, after the round-trip time given.
+    PongRecvd(String, chrono::Duration),
+    /// A file transfer message was received from the participant named,
+    /// about the transfer identified.
+    FileRecvd(String, u64, FileMsg),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addr = |addr: &Option<SocketAddr>| addr.map(|a| a.to_string())
+            .unwrap_or_else(|| "an unknown address".to_owned());
+        match *self {
+            Event::ServerOpened(ref peer_addr, ref nick) => {
+                write!(f, "{} connected from {}", nick, addr(peer_addr))
+            },
+            Event::ServerClosed(code, ref reason) => {
+                write!(f, "Client connection closed ({:?}) {}", code, reason)
+            },
+            Event::ServerError(ref err) => write!(f, "Server error: {}", err),
+            Event::ServerShutdown => write!(f, "Server shut down"),
+            Event::ClientOpened(ref peer_addr) => write!(f, "Connected to {}", addr(peer_addr)),
+            Event::ClientClosed(code, ref reason) => {
+                write!(f, "Connection closed ({:?}) {}", code, reason)
+            },
+            Event::ClientError(ref err) => write!(f, "Client error: {}", err),
+            Event::ClientReconnecting(delay, attempt) => {
+                write!(f, "Reconnecting in {}s (attempt {})", delay.as_secs(), attempt)
+            },
+            Event::ClientDisconnected => write!(f, "Disconnected"),
+            Event::MessageRecvd(ref room, ref from, ref text, _) => {
+                write!(f, "{} {{{}}}: {}", room, from, text)
+            },
+            Event::NickChanged(ref nick) => write!(f, "Now known as {}", nick),
+            Event::HistoryRecvd(ref entries) => write!(f, "{} history entries", entries.len()),
+            Event::RoomJoined(ref room) => write!(f, "Joined {}", room),
+            Event::RoomParted(ref room) => write!(f, "Left {}", room),
+            Event::RoomListRecvd(ref rooms) => write!(f, "{} rooms", rooms.len()),
+            Event::DirectRecvd(ref from, ref text, _) => write!(f, "*{}*: {}", from, text),
+            Event::NoticeRecvd(ref text) => write!(f, "*** {}", text),
+            Event::PresenceRecvd(ref room, ref who, Presence::Joined) => {
+                write!(f, "{} has joined {}", who, room)
+            },
+            Event::PresenceRecvd(ref room, ref who, Presence::Left) => {
+                write!(f, "{} has left {}", who, room)
+            },
+            Event::ReplyRecvd(true, ref text) => write!(f, "{}", text),
+            Event::ReplyRecvd(false, ref text) => write!(f, "Error: {}", text),
+            Event::UnknownRecvd(ref desc) => write!(f, "Unrecognized message: {}", desc),
+            Event::PongRecvd(ref from, elapsed) => {
+                write!(f, "Round-trip to {}: {}ms", from, elapsed.num_milliseconds())
+            },
+            Event::FileRecvd(ref from, id, ref msg) => {
+                write!(f, "File transfer {:016x} with {}: {}", id, from, msg)
+            },
+        }
+    }
+}
+
+
+/// Receives the events of clients and servers.
+///
+/// Only `send` must be implemented. It is called on network threads, so it
+/// should return quickly and must not panic: events which cannot be
+/// delivered are dropped.
+pub trait EventSink: Send + Sync {
+    /// Handles an event.
+    fn send(&self, event: Event);
+
+    fn server_connected(&self, peer_addr: Option<SocketAddr>, nick: String) {
+        self.send(Event::ServerOpened(peer_addr, nick))
+    }
+
+    fn server_closed(&self, code: CloseCode, reason: String) {
+        self.send(Event::ServerClosed(code, reason))
+    }
+
+    fn server_error(&self, err: Error) {
+        self.send(Event::ServerError(err))
+    }
+
+    fn server_shutdown(&self) {
+        self.send(Event::ServerShutdown)
+    }
+
+    fn client_connected(&self, peer_addr: Option<SocketAddr>) {
+        self.send(Event::ClientOpened(peer_addr))
+    }
+
+    fn client_closed(&self, code: CloseCode, reason: String) {
+        self.send(Event::ClientClosed(code, reason))
+    }
+
+    fn client_error(&self, err: Error) {
+        self.send(Event::ClientError(err))
+    }
+
+    fn client_reconnecting(&self, delay: Duration, attempt: u32) {
+        self.send(Event::ClientReconnecting(delay, attempt))
+    }
+
+    fn client_disconnected(&self) {
+        self.send(Event::ClientDisconnected)
+    }
+
+    fn message_recvd(&self, room: String, from: String, msg_text: String, times: Timestamps) {
+        self.send(Event::MessageRecvd(room, from, msg_text, times))
+    }
+
+    fn nick_changed(&self, nick: String) {
+        self.send(Event::NickChanged(nick))
+    }
+
+    fn history_recvd(&self, entries: Vec<HistoryEntry>) {
+        self.send(Event::HistoryRecvd(entries))
+    }
+
+    fn room_joined(&self, room: String) {
+        self.send(Event::RoomJoined(room))
+    }
+
+    fn room_parted(&self, room: String) {
+        self.send(Event::RoomParted(room))
+    }
+
+    fn room_list_recvd(&self, rooms: Vec<RoomInfo>) {
+        self.send(Event::RoomListRecvd(rooms))
+    }
+
+    fn direct_recvd(&self, from: String, text: String, times: Timestamps) {
+        self.send(Event::DirectRecvd(from, text, times))
+    }
+
+    fn notice_recvd(&self, text: String) {
+        self.send(Event::NoticeRecvd(text))
+    }
+
+    fn presence_recvd(&self, room: String, who: String, presence: Presence) {
+        self.send(Event::PresenceRecvd(room, who, presence))
+    }
+
+    fn reply_recvd(&self, ok: bool, text: String) {
+        self.send(Event::ReplyRecvd(ok, text))
+    }
+
+    fn unknown_recvd(&self, desc: String) {
+        self.send(Event::UnknownRecvd(desc))
+    }
+
+    fn pong_recvd(&self, from: String, elapsed: chrono::Duration) {
+        self.send(Event::PongRecvd(from, elapsed))
+    }
+
+    fn file_recvd(&self, from: String, id: u64, msg: FileMsg) {
+        self.send(Event::FileRecvd(from, id, msg))
+    }
+}
+
+
+/// Sends events to the receiver of a channel. Events are dropped once the
+/// receiver has gone away.
+#[derive(Debug)]
+pub struct ChannelSink {
+    // `Sender` is not `Sync`:
+    tx: Mutex<Sender<Event>>,
+}
+
+impl ChannelSink {
+    /// Returns a new sink and the receiver of the events it sends.
+    pub fn channel() -> (ChannelSink, Receiver<Event>) {
+        let (tx, rx) = mpsc::channel();
+        (ChannelSink::new(tx), rx)
+    }
+
+    /// Returns a sink sending events to `tx`.
+    pub fn new(tx: Sender<Event>) -> ChannelSink {
+        ChannelSink { tx: Mutex::new(tx) }
+    }
+}
+
+impl EventSink for ChannelSink {
+    fn send(&self, event: Event) {
+        if let Ok(tx) = self.tx.lock() {
+            tx.send(event).ok();
+        }
+    }
+}
+
+
+/// Writes a timestamped line describing each event. Write errors are
+/// ignored.
+pub struct LogSink {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl LogSink {
+    /// Returns a sink writing to `out`.
+    pub fn new<W: Write + Send + 'static>(out: W) -> LogSink {
+        LogSink { out: Mutex::new(Box::new(out)) }
+    }
+}
+
+impl EventSink for LogSink {
+    fn send(&self, event: Event) {
+        if let Ok(mut out) = self.out.lock() {
+            writeln!(out, "[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), event).ok();
+            out.flush().ok();
+        }
+    }
+}
+
+
+/// Keeps every event, e.g. for inspection by tests.
+#[derive(Debug, Default)]
+pub struct RecordingSink {
+    events: Mutex<Vec<Event>>,
+}
+
+impl RecordingSink {
+    /// Returns a new, empty recorder.
+    pub fn new() -> RecordingSink {
+        RecordingSink::default()
+    }
+
+    /// Returns the events recorded so far, oldest first.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().map(|events| events.clone()).unwrap_or_default()
+    }
+
+    /// Returns and forgets the events recorded so far, oldest first.
+    pub fn take(&self) -> Vec<Event> {
+        self.events.lock().map(|mut events| mem::take(&mut *events))
+            .unwrap_or_default()
+    }
+}
+
+impl EventSink for RecordingSink {
+    fn send(&self, event: Event) {
+        if let Ok(mut events) = self.events.lock() {
+            events.push(event);
+        }
+    }
+}
+
+
+/// Passes each event on to several sinks, in order.
+#[derive(Default)]
+pub struct FanOut {
+    sinks: Vec<Arc<dyn EventSink>>,
+}
+
+impl FanOut {
+    /// Returns a sink passing events on to each of `sinks`.
+    pub fn new(sinks: Vec<Arc<dyn EventSink>>) -> FanOut {
+        FanOut { sinks }
+    }
+
+    /// Adds a sink.
+    pub fn push(&mut self, sink: Arc<dyn EventSink>) {
+        self.sinks.push(sink);
+    }
+}
+
+impl EventSink for FanOut {
+    fn send(&self, event: Event) {
+        if let Some((last, others)) = self.sinks.split_last() {
+            for sink in others {
+                sink.send(event.clone());
+            }
+            last.send(event);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notices(events: &[Event]) -> Vec<&str> {
+        events.iter()
+            .filter_map(|e| match *e {
+                Event::NoticeRecvd(ref text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recording_sink_keeps_events_until_taken() {
+        let sink = RecordingSink::new();
+        sink.notice_recvd("one".to_owned());
+        sink.notice_recvd("two".to_owned());
+        assert_eq!(notices(&sink.events()), ["one", "two"]);
+        assert_eq!(notices(&sink.take()), ["one", "two"]);
+        assert!(sink.events().is_empty());
+    }
+
+    #[test]
+    fn channel_sink_drops_events_once_the_receiver_is_gone() {
+        let (sink, rx) = ChannelSink::channel();
+        sink.notice_recvd("one".to_owned());
+        assert_eq!(notices(&rx.try_iter().collect::<Vec<_>>()), ["one"]);
+        drop(rx);
+        sink.notice_recvd("two".to_owned());
+    }
+
+    #[test]
+    fn fan_out_passes_each_event_to_every_sink() {
+        let first = Arc::new(RecordingSink::new());
+        let second = Arc::new(RecordingSink::new());
+        let mut fan_out = FanOut::new(vec![first.clone()]);
+        fan_out.push(second.clone());
+        fan_out.notice_recvd("one".to_owned());
+        fan_out.client_disconnected();
+        assert_eq!(notices(&first.events()), ["one"]);
+        assert_eq!(notices(&second.events()), ["one"]);
+        assert!(matches!(second.events()[1], Event::ClientDisconnected));
+
+        // Nothing to pass events on to:
+        FanOut::default().notice_recvd("two".to_owned());
+    }
+}
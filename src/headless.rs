@@ -0,0 +1,143 @@
+//! A headless server which logs chat activity instead of displaying it.
+
+use std::fs::OpenOptions;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver as MpscReceiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use chrono::Local;
+use signal_hook;
+use ::{ChannelSink, Event, Error};
+use protocol::Presence;
+use history::{History, HistoryOptions};
+use server::{Server, ServerOptions};
+
+
+/// How long to wait for connections to close when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+
+/// Writes timestamped log lines.
+struct Log {
+    out: Box<dyn Write>,
+}
+
+impl Log {
+    fn line(&mut self, args: fmt::Arguments) -> Result<(), Error> {
+        writeln!(self.out, "[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), args)?;
+        self.out.flush().map_err(Error::from)
+    }
+}
+
+
+/// A server running without a terminal user interface.
+struct Daemon {
+    server: Server,
+    cmd_rx: MpscReceiver<Event>,
+    log: Log,
+    clients: usize,
+}
+
+impl Daemon {
+    /// Logs a command sent from the server. Returns an error if the server
+    /// can no longer run.
+    fn handle_command(&mut self, cmd: Event) -> Result<(), Error> {
+        match cmd {
+            Event::ServerOpened(peer_addr, nick) => {
+                self.clients += 1;
+                match peer_addr {
+                    Some(addr) => self.log.line(format_args!("{} connected from {} ({} clients)",
+                        nick, addr, self.clients)),
+                    None => self.log.line(format_args!("{} connected ({} clients)", nick,
+                        self.clients)),
+                }
+            },
+            Event::ServerClosed(code, reason) => {
+                self.clients = self.clients.saturating_sub(1);
+                let sep = if reason.is_empty() { "" } else { ": " };
+                self.log.line(format_args!("Client connection closed ({:?}{}{}) ({} clients)",
+                    code, sep, reason, self.clients))
+            },
+            Event::MessageRecvd(room, from, text, _) => {
+                self.log.line(format_args!("{} {{{}}}: {}", room, from, text))
+            },
+            Event::DirectRecvd(from, text, _) => {
+                self.log.line(format_args!("*{}*: {}", from, text))
+            },
+            Event::PresenceRecvd(room, who, Presence::Joined) => {
+                self.log.line(format_args!("{} has joined {}", who, room))
+            },
+            Event::PresenceRecvd(room, who, Presence::Left) => {
+                self.log.line(format_args!("{} has left {}", who, room))
+            },
+            Event::NoticeRecvd(text) => self.log.line(format_args!("*** {}", text)),
+            Event::UnknownRecvd(desc) => {
+                self.log.line(format_args!("Ignoring unrecognized message: {}", desc))
+            },
+            // Errors on a single connection are only logged:
+            Event::ServerError(err) => {
+                self.log.line(format_args!("The server has encountered an error: {}", err))?;
+                if self.server.is_listening() { Ok(()) } else { Err(err) }
+            },
+            // Client events and replies to the local user do not occur:
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles commands until `terminate` is set or the server stops listening.
+    fn run(&mut self, terminate: &AtomicBool) -> Result<(), Error> {
+        while !terminate.load(Ordering::SeqCst) {
+            match self.cmd_rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(Event::ServerShutdown) => return Ok(()),
+                Ok(cmd) => self.handle_command(cmd)?,
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+
+        self.log.line(format_args!("Shutting down..."))?;
+        self.server.close_all()?;
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while self.clients > 0 && Instant::now() < deadline {
+            match self.cmd_rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(Event::ServerShutdown) => return Ok(()),
+                // Errors while closing are only logged:
+                Ok(Event::ServerError(err)) => self.log.line(format_args!("{}", err))?,
+                Ok(cmd) => self.handle_command(cmd)?,
+                Err(_) => {},
+            }
+        }
+        self.server.shutdown()
+    }
+}
+
+
+/// Runs a server listening on `addr` until it receives SIGTERM or SIGINT,
+/// logging to the file at `log_path` or, if `None`, to stdout.
+pub fn run(addr: SocketAddr, nick: &str, history_opts: &HistoryOptions, opts: ServerOptions,
+        log_path: Option<&Path>) -> Result<(), Error> {
+    let out: Box<dyn Write> = match log_path {
+        Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let mut log = Log { out };
+
+    let terminate = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGTERM, terminate.clone())?;
+    signal_hook::flag::register(signal_hook::SIGINT, terminate.clone())?;
+
+    let (sink, cmd_rx) = ChannelSink::channel();
+    let opts = ServerOptions { observer: true, ..opts };
+    let secure = opts.tls.is_some();
+    let server = Server::new(addr, nick, History::open(history_opts)?, opts,
+        Arc::new(sink))?;
+    log.line(format_args!("Listening on {}{}", if secure { "wss://" } else { "" }, addr))?;
+
+    let mut daemon = Daemon { server, cmd_rx, log, clients: 0 };
+    daemon.run(&terminate)?;
+    daemon.log.line(format_args!("Server stopped."))
+}
@@ -0,0 +1,271 @@
+//! Server-side chat history.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use bincode;
+use chrono::{DateTime, Utc, serde::ts_nanoseconds};
+use ::Error;
+use protocol::{self, HistoryEntry, DEFAULT_ROOM};
+
+
+/// The name of the history file within the history directory.
+pub const HISTORY_FILE_NAME: &str = "history.bin";
+
+/// The start of history files, identifying the layout of their entries.
+/// Change it whenever the layout of `HistoryEntry` changes.
+const FILE_HEADER: &[u8] = b"HUHIST\x00\x01";
+
+
+/// An entry of the history files written before rooms were added, which
+/// start without a header.
+#[derive(Deserialize)]
+struct LegacyEntry {
+    #[serde(with = "ts_nanoseconds")]
+    time: DateTime<Utc>,
+    from: String,
+    text: String,
+}
+
+/// Returns true if the file at `path` starts with the current header.
+fn has_header(path: &Path) -> Result<bool, Error> {
+    let mut header = Vec::with_capacity(FILE_HEADER.len());
+    File::open(path)?.take(FILE_HEADER.len() as u64).read_to_end(&mut header)?;
+    Ok(header == FILE_HEADER)
+}
+
+/// Rewrites a history file without a header, whose entries have either the
+/// legacy or (if written before the header was added) the current layout,
+/// in the current format. Entries without a room are placed in the default
+/// room.
+fn convert(path: &Path) -> Result<(), Error> {
+    let data = fs::read(path)?;
+    let mut rest = &data[..];
+    let mut entries = Vec::new();
+    // Nicknames, which legacy entries start with after the time, are never
+    // valid room names:
+    let current = bincode::deserialize::<HistoryEntry>(&data)
+        .map(|e| protocol::is_valid_room(&e.room)).unwrap_or(false);
+    if current {
+        while let Ok(entry) = bincode::deserialize_from(&mut rest) {
+            entries.push(entry);
+        }
+    } else {
+        while let Ok(LegacyEntry { time, from, text }) = bincode::deserialize_from(&mut rest) {
+            entries.push(HistoryEntry { time, room: DEFAULT_ROOM.to_owned(), from, text });
+        }
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        file.write_all(FILE_HEADER)?;
+        for entry in &entries {
+            bincode::serialize_into(&mut file, entry)?;
+        }
+        file.flush()?;
+    }
+    fs::rename(&tmp_path, path).map_err(Error::from)
+}
+
+
+/// History settings.
+#[derive(Debug, Clone)]
+pub struct HistoryOptions {
+    /// The directory containing the history file. History is kept in memory
+    /// only if `None`.
+    pub dir: Option<PathBuf>,
+    /// The maximum number of messages kept in memory.
+    pub capacity: usize,
+    /// The number of messages replayed to clients joining a room.
+    pub replay_len: usize,
+}
+
+impl Default for HistoryOptions {
+    fn default() -> HistoryOptions {
+        HistoryOptions {
+            dir: None,
+            capacity: 500,
+            replay_len: 20,
+        }
+    }
+}
+
+
+/// A bounded in-memory message history backed by an append-only file.
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize,
+    replay_len: usize,
+    file: Option<BufWriter<File>>,
+}
+
+impl History {
+    /// Opens the history file, if any, loading its most recent entries.
+    pub fn open(opts: &HistoryOptions) -> Result<History, Error> {
+        let mut history = History {
+            entries: VecDeque::with_capacity(opts.capacity),
+            capacity: opts.capacity,
+            replay_len: opts.replay_len,
+            file: None,
+        };
+
+        if let Some(ref dir) = opts.dir {
+            fs::create_dir_all(dir)?;
+            let path = dir.join(HISTORY_FILE_NAME);
+
+            if path.exists() && !has_header(&path)? {
+                convert(&path)?;
+            }
+
+            // The length of the header and the entries read:
+            let mut len = FILE_HEADER.len() as u64;
+            if path.exists() {
+                let mut reader = BufReader::new(File::open(&path)?);
+                reader.read_exact(&mut [0; FILE_HEADER.len()])?;
+                // Loading stops at the end of the file or at the first
+                // unreadable (e.g. partially written) entry:
+                while let Ok(entry) = bincode::deserialize_from(&mut reader) {
+                    len += bincode::serialized_size(&entry)?;
+                    history.push_bounded(entry);
+                }
+            }
+
+            // An unreadable remainder is cut off, as entries appended after
+            // it could not be read either:
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            match file.metadata()?.len() {
+                0 => file.write_all(FILE_HEADER)?,
+                file_len if file_len > len => file.set_len(len)?,
+                _ => {},
+            }
+            history.file = Some(BufWriter::new(file));
+        }
+
+        Ok(history)
+    }
+
+    fn push_bounded(&mut self, entry: HistoryEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        if self.capacity > 0 {
+            self.entries.push_back(entry);
+        }
+    }
+
+    /// Records an entry, appending it to the history file.
+    ///
+    /// If writing to the file fails, the file is closed and further entries
+    /// are only kept in memory.
+    pub fn push(&mut self, entry: HistoryEntry) -> Result<(), Error> {
+        let res = match self.file {
+            Some(ref mut file) => bincode::serialize_into(&mut *file, &entry)
+                .map_err(Error::from)
+                .and_then(|_| file.flush().map_err(Error::from)),
+            None => Ok(()),
+        };
+        if res.is_err() { self.file = None; }
+        self.push_bounded(entry);
+        res
+    }
+
+    /// Returns up to `n` of the most recent entries in `room`, oldest first.
+    pub fn last(&self, room: &str, n: usize) -> Vec<HistoryEntry> {
+        let mut entries: Vec<_> = self.entries.iter().rev()
+            .filter(|e| e.room == room)
+            .take(n)
+            .cloned()
+            .collect();
+        entries.reverse();
+        entries
+    }
+
+    /// Returns the entries of `room` to replay to a newly joined client.
+    pub fn replay(&self, room: &str) -> Vec<HistoryEntry> {
+        self.last(room, self.replay_len)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// Returns options keeping history in a new, empty directory.
+    fn options(name: &str) -> HistoryOptions {
+        let dir = env::temp_dir().join(format!("headsup-history-{}-{}", process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        HistoryOptions { dir: Some(dir), capacity: 3, replay_len: 2 }
+    }
+
+    fn entry(room: &str, text: &str) -> HistoryEntry {
+        HistoryEntry { time: Utc::now(), room: room.to_owned(), from: "alice".to_owned(),
+            text: text.to_owned() }
+    }
+
+    fn texts(entries: &[HistoryEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.text.as_str()).collect()
+    }
+
+    #[test]
+    fn reloads_entries() {
+        let opts = options("reload");
+        {
+            let mut history = History::open(&opts).unwrap();
+            for text in &["one", "two", "three", "four"] {
+                history.push(entry(DEFAULT_ROOM, text)).unwrap();
+            }
+            history.push(entry("#ops", "other")).unwrap();
+        }
+        let history = History::open(&opts).unwrap();
+        // Only the most recent entries are kept in memory:
+        assert_eq!(texts(&history.last(DEFAULT_ROOM, 10)), ["three", "four"]);
+        assert_eq!(texts(&history.replay("#ops")), ["other"]);
+        fs::remove_dir_all(opts.dir.unwrap()).ok();
+    }
+
+    #[test]
+    fn cuts_off_unreadable_remainder() {
+        let opts = options("remainder");
+        History::open(&opts).unwrap().push(entry(DEFAULT_ROOM, "one")).unwrap();
+        let path = opts.dir.as_ref().unwrap().join(HISTORY_FILE_NAME);
+        // A partially written entry:
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0xff; 5]).unwrap();
+
+        History::open(&opts).unwrap().push(entry(DEFAULT_ROOM, "two")).unwrap();
+        let history = History::open(&opts).unwrap();
+        assert_eq!(texts(&history.last(DEFAULT_ROOM, 10)), ["one", "two"]);
+        fs::remove_dir_all(opts.dir.unwrap()).ok();
+    }
+
+    #[test]
+    fn converts_legacy_files() {
+        #[derive(Serialize)]
+        struct Legacy<'a> {
+            #[serde(with = "ts_nanoseconds")]
+            time: DateTime<Utc>,
+            from: &'a str,
+            text: &'a str,
+        }
+
+        let opts = options("legacy");
+        let dir = opts.dir.as_ref().unwrap();
+        fs::create_dir_all(dir).unwrap();
+        let mut file = File::create(dir.join(HISTORY_FILE_NAME)).unwrap();
+        for text in &["one", "two"] {
+            bincode::serialize_into(&mut file, &Legacy { time: Utc::now(), from: "bob", text })
+                .unwrap();
+        }
+        drop(file);
+
+        History::open(&opts).unwrap().push(entry(DEFAULT_ROOM, "three")).unwrap();
+        let history = History::open(&opts).unwrap();
+        assert_eq!(texts(&history.last(DEFAULT_ROOM, 10)), ["one", "two", "three"]);
+        fs::remove_dir_all(opts.dir.unwrap()).ok();
+    }
+}
@@ -0,0 +1,167 @@
+//! Lines previously typed at the console prompt.
+
+use std::fs::{self, File, OpenOptions};
+#[cfg(unix)]
+use std::fs::Permissions;
+use std::io::{BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use ::Error;
+
+
+/// The name of the input history file within the config directory.
+pub const INPUT_HISTORY_FILE_NAME: &str = "input_history";
+
+/// The maximum number of lines kept.
+const INPUT_HISTORY_LEN: usize = 500;
+
+
+/// Returns `line` as saved to the history file, omitting the password of
+/// connect commands.
+fn redact(line: &str) -> String {
+    let mut args = line.split_whitespace();
+    match (args.next(), args.next(), args.next()) {
+        (Some(cmd @ "/connect"), Some(url), Some(_)) |
+                (Some(cmd @ "/open"), Some(url), Some(_)) => format!("{} {}", cmd, url),
+        _ => line.to_owned(),
+    }
+}
+
+
+/// A bounded list of input lines, oldest first, backed by a file, and the
+/// position reached when navigating through it.
+#[derive(Debug)]
+pub struct InputHistory {
+    lines: Vec<String>,
+    file: Option<File>,
+    // The index of the line being shown, if navigating:
+    pos: Option<usize>,
+    // The line being edited before navigating:
+    draft: String,
+}
+
+impl InputHistory {
+    /// Returns a new, empty history kept in memory only.
+    pub fn new() -> InputHistory {
+        InputHistory { lines: Vec::new(), file: None, pos: None, draft: String::new() }
+    }
+
+    /// Opens the history file at `path`, creating it if necessary, and
+    /// loads its most recent lines.
+    pub fn open(path: &Path) -> Result<InputHistory, Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut history = InputHistory::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                history.lines.push(line?);
+            }
+        }
+
+        // The typed lines (e.g. private messages) must only be readable by
+        // their owner:
+        let mut options = OpenOptions::new();
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        // Old lines are dropped by rewriting the file:
+        if history.lines.len() > INPUT_HISTORY_LEN {
+            let excess = history.lines.len() - INPUT_HISTORY_LEN;
+            history.lines.drain(..excess);
+            let mut file = options.clone().write(true).truncate(true).create(true).open(path)?;
+            for line in &history.lines {
+                writeln!(file, "{}", line)?;
+            }
+        }
+
+        let file = options.create(true).append(true).open(path)?;
+        // Files created before the mode was set may be readable by others:
+        #[cfg(unix)]
+        file.set_permissions(Permissions::from_mode(0o600))?;
+        history.file = Some(file);
+        Ok(history)
+    }
+
+    /// Records a line and stops navigating. Empty lines and repeats of the
+    /// previous line are skipped.
+    ///
+    /// If writing to the file fails, the file is closed and further lines
+    /// are only kept in memory.
+    pub fn push(&mut self, line: &str) -> Result<(), Error> {
+        self.pos = None;
+        let line = redact(line.trim());
+        if line.is_empty() || self.lines.last() == Some(&line) {
+            return Ok(());
+        }
+        let res = match self.file {
+            Some(ref mut file) => writeln!(file, "{}", line).map_err(Error::from),
+            None => Ok(()),
+        };
+        if res.is_err() { self.file = None; }
+        if self.lines.len() == INPUT_HISTORY_LEN {
+            self.lines.remove(0);
+        }
+        self.lines.push(line);
+        res
+    }
+
+    /// Returns the line before the one being shown, saving `current` if
+    /// navigation has only just begun. Returns `None` at the oldest line.
+    pub fn prev(&mut self, current: &str) -> Option<&str> {
+        let pos = match self.pos {
+            Some(0) => return None,
+            Some(pos) => pos - 1,
+            None if self.lines.is_empty() => return None,
+            None => {
+                self.draft = current.to_owned();
+                self.lines.len() - 1
+            },
+        };
+        self.pos = Some(pos);
+        Some(&self.lines[pos])
+    }
+
+    /// Returns the line after the one being shown or, past the most recent
+    /// line, the line which was being edited. Returns `None` if not
+    /// navigating.
+    pub fn next(&mut self) -> Option<&str> {
+        match self.pos {
+            Some(pos) if pos + 1 < self.lines.len() => {
+                self.pos = Some(pos + 1);
+                Some(&self.lines[pos + 1])
+            },
+            Some(_) => {
+                self.pos = None;
+                Some(&self.draft)
+            },
+            None => None,
+        }
+    }
+
+    /// Returns the urls given to connect commands, most recent first.
+    pub fn connect_targets(&self) -> Vec<String> {
+        let mut targets: Vec<String> = Vec::new();
+        for line in self.lines.iter().rev() {
+            let mut args = line.split_whitespace();
+            match (args.next(), args.next()) {
+                (Some("/connect"), Some(url)) | (Some("/open"), Some(url))
+                        if !targets.iter().any(|t| t == url) => {
+                    targets.push(url.to_owned());
+                },
+                _ => {},
+            }
+        }
+        targets
+    }
+
+    /// Returns the index and contents of the most recent line before index
+    /// `before` (or before the end, if `None`) which contains `query`.
+    pub fn search(&self, query: &str, before: Option<usize>) -> Option<(usize, &str)> {
+        let end = before.unwrap_or(self.lines.len()).min(self.lines.len());
+        self.lines[..end].iter().enumerate().rev()
+            .find(|&(_, line)| line.contains(query))
+            .map(|(idx, line)| (idx, line.as_str()))
+    }
+}
@@ -0,0 +1,123 @@
+//! Round-trip time statistics.
+
+use std::collections::VecDeque;
+use chrono::Duration;
+
+
+/// The number of most recent round-trip times kept.
+const SAMPLE_COUNT: usize = 100;
+
+
+/// The most recent round-trip times measured on a connection.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyStats {
+    samples: VecDeque<Duration>,
+    // The total number of round-trip times measured:
+    count: u64,
+}
+
+impl LatencyStats {
+    /// Records a round-trip time.
+    pub fn record(&mut self, rtt: Duration) {
+        if self.samples.len() == SAMPLE_COUNT {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+        self.count += 1;
+    }
+
+    /// Returns the total number of round-trip times measured.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the most recent round-trip time.
+    pub fn last(&self) -> Option<Duration> {
+        self.samples.back().cloned()
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.samples.iter().min().cloned()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.iter().max().cloned()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.samples.is_empty() { return None; }
+        let total = self.samples.iter().fold(Duration::zero(), |sum, &rtt| sum + rtt);
+        Some(total / self.samples.len() as i32)
+    }
+
+    /// Returns the round-trip time which 95% of samples do not exceed.
+    pub fn p95(&self) -> Option<Duration> {
+        if self.samples.is_empty() { return None; }
+        let mut sorted: Vec<Duration> = self.samples.iter().cloned().collect();
+        sorted.sort();
+        // The nearest rank:
+        let rank = (sorted.len() * 95).div_ceil(100);
+        Some(sorted[rank - 1])
+    }
+
+    /// Returns the mean difference between consecutive round-trip times.
+    pub fn jitter(&self) -> Option<Duration> {
+        if self.samples.len() < 2 { return None; }
+        let total = self.samples.iter().zip(self.samples.iter().skip(1))
+            .fold(Duration::zero(), |sum, (&a, &b)| sum + if b > a { b - a } else { a - b });
+        Some(total / (self.samples.len() - 1) as i32)
+    }
+}
+
+
+/// Formats a round-trip time in milliseconds, e.g. '12.345ms'.
+pub fn format_rtt(rtt: Duration) -> String {
+    match rtt.num_microseconds() {
+        Some(us) => format!("{}.{:03}ms", us / 1000, us % 1000),
+        None => format!("{}ms", rtt.num_milliseconds()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: i64) -> Duration {
+        Duration::milliseconds(ms)
+    }
+
+    #[test]
+    fn summarizes_samples() {
+        let mut stats = LatencyStats::default();
+        assert!(stats.mean().is_none() && stats.p95().is_none() && stats.jitter().is_none());
+        for &rtt in &[10, 30, 20] {
+            stats.record(ms(rtt));
+        }
+        assert_eq!(stats.count(), 3);
+        assert_eq!(stats.last(), Some(ms(20)));
+        assert_eq!((stats.min(), stats.max()), (Some(ms(10)), Some(ms(30))));
+        assert_eq!(stats.mean(), Some(ms(20)));
+        assert_eq!(stats.p95(), Some(ms(30)));
+        // |30 - 10| and |20 - 30|:
+        assert_eq!(stats.jitter(), Some(ms(15)));
+    }
+
+    #[test]
+    fn keeps_only_recent_samples() {
+        let mut stats = LatencyStats::default();
+        for rtt in 1..=(SAMPLE_COUNT as i64 + 20) {
+            stats.record(ms(rtt));
+        }
+        assert_eq!(stats.count(), SAMPLE_COUNT as u64 + 20);
+        assert_eq!(stats.min(), Some(ms(21)));
+        // The 95th of the 100 samples kept:
+        assert_eq!(stats.p95(), Some(ms(115)));
+    }
+
+    #[test]
+    fn formats_in_milliseconds() {
+        assert_eq!(format_rtt(Duration::microseconds(12_345)), "12.345ms");
+        assert_eq!(format_rtt(ms(3)), "3.000ms");
+    }
+}
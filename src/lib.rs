@@ -0,0 +1,276 @@
+//! HeadsUp: a websocket based chat client/server library.
+//!
+//! A `Server` listens for and relays between `Client`s. Both run their
+//! connections on background threads and report what happens on them
+//! (connections, messages, presence changes, errors, ...) as `Event`s passed
+//! to an `EventSink`. Applications embedding chat can implement the trait or
+//! receive events from a channel using `ChannelSink`:
+//!
+//! ```no_run
+//! extern crate headsup;
+//!
+//! use std::sync::Arc;
+//! use headsup::{Client, ClientOptions, Event, ChannelSink};
+//!
+//! let (sink, event_rx) = ChannelSink::channel();
+//! let url = headsup::client::parse_url("localhost:3030").unwrap();
+//! let client = Client::new(url, "bot", ClientOptions::default(), Arc::new(sink)).unwrap();
+//! for event in event_rx {
+//!     if let Event::MessageRecvd(room, from, text, _) = event {
+//!         println!("[{}] {}: {}", room, from, text);
+//!         client.send_chat(&room, "Hello!").ok();
+//!     }
+//! }
+//! ```
+
+// `ws::Handler` methods return `ws::Result`, whose error is large:
+#![allow(clippy::result_large_err)]
+// The `Fail` derive implements its traits inside a constant:
+#![allow(unknown_lints, non_local_definitions)]
+
+#[macro_use] extern crate failure;
+extern crate url;
+extern crate ws;
+#[macro_use] extern crate serde_derive;
+extern crate bincode;
+extern crate chrono;
+extern crate rand;
+extern crate openssl;
+
+pub mod protocol;
+pub mod history;
+pub mod tls;
+pub mod crypto;
+pub mod event;
+pub mod client;
+pub mod server;
+pub mod transfer;
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use std::sync::Arc;
+use failure::Context;
+use openssl::error::ErrorStack;
+
+pub use event::{Event, EventSink, ChannelSink, LogSink, RecordingSink, FanOut};
+pub use client::{Client, ClientOptions, ReconnectOptions};
+pub use server::{Server, ServerOptions};
+pub use transfer::Transfers;
+
+
+/// Error Kinds.
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "{}", _0)]
+    Fmt(fmt::Error),
+    #[fail(display = "{}", _0)]
+    Io(io::Error),
+    #[fail(display = "Websocket error: {}", _0)]
+    Ws(ws::Error),
+    #[fail(display = "Codec error: {}", _0)]
+    Bincode(Box<bincode::ErrorKind>),
+    #[fail(display = "Incompatible protocol version: {}. The peer needs to be upgraded.", _0)]
+    IncompatibleVersion(u16),
+    #[fail(display = "Error parsing url: {}", _0)]
+    UrlParse(url::ParseError),
+    #[fail(display = "Unsupported url scheme: '{}'. Use 'ws' or 'wss'.", _0)]
+    UnsupportedScheme(String),
+    #[fail(display = "OpenSSL error: {}", _0)]
+    OpenSsl(ErrorStack),
+    #[fail(display = "Invalid identity key file: '{}'", _0)]
+    InvalidIdentity(String),
+    #[fail(display = "No encryption key known for '{}'.", _0)]
+    NoKey(String),
+    #[fail(display = "Unable to decrypt message.")]
+    DecryptionFailed,
+    #[fail(display = "The message was sent before one already received.")]
+    Replayed,
+    #[fail(display = "The message is not encrypted.")]
+    NotEncrypted,
+    #[fail(display = "Invalid server address: {}", _0)]
+    BadServerAddr(io::Error),
+    #[fail(display = "Invalid client address: {}", _0)]
+    BadClientAddr(io::Error),
+    #[fail(display = "No server address given.")]
+    NoServerAddr,
+    #[fail(display = "Invalid nickname: '{}'", _0)]
+    InvalidNick(String),
+    #[fail(display = "Nickname '{}' is already in use.", _0)]
+    NickInUse(String),
+    #[fail(display = "Invalid room name: '{}'", _0)]
+    InvalidRoom(String),
+    #[fail(display = "You are not in {}.", _0)]
+    NotInRoom(String),
+    #[fail(display = "No such nickname: '{}'.", _0)]
+    UnknownNick(String),
+    #[fail(display = "Not connected.")]
+    NotConnected,
+    #[fail(display = "Not in a room. Type '/join {{#room}}' to join one.")]
+    NoRoom,
+    #[fail(display = "'{}' is not a file.", _0)]
+    NotAFile(String),
+    #[fail(display = "No file has been offered to you.")]
+    NoFileOffer,
+    #[fail(display = "Unknown command: '/{}'. Type '/help' for a list of commands.", _0)]
+    UnknownCommand(String),
+    #[fail(display = "{} Usage: {}", reason, usage)]
+    CommandUsage { reason: String, usage: String },
+    #[fail(display = "Invalid theme file entry: {}", _0)]
+    InvalidTheme(String),
+}
+
+
+/// Errors. Cloning an error is cheap: clones share the same kind.
+#[derive(Debug, Clone)]
+pub struct Error {
+    inner: Arc<Context<ErrorKind>>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Error {
+        Error { inner: Arc::new(Context::new(kind)) }
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        self.inner.get_context()
+    }
+
+    pub fn bad_server_addr(err: io::Error) -> Error {
+        Error::new(ErrorKind::BadServerAddr(err))
+    }
+
+    pub fn no_server_addr() -> Error {
+        Error::new(ErrorKind::NoServerAddr)
+    }
+
+    pub fn invalid_nick<S: Into<String>>(nick: S) -> Error {
+        Error::new(ErrorKind::InvalidNick(nick.into()))
+    }
+
+    pub fn nick_in_use<S: Into<String>>(nick: S) -> Error {
+        Error::new(ErrorKind::NickInUse(nick.into()))
+    }
+
+    pub fn invalid_room<S: Into<String>>(room: S) -> Error {
+        Error::new(ErrorKind::InvalidRoom(room.into()))
+    }
+
+    pub fn not_in_room<S: Into<String>>(room: S) -> Error {
+        Error::new(ErrorKind::NotInRoom(room.into()))
+    }
+
+    pub fn unknown_nick<S: Into<String>>(nick: S) -> Error {
+        Error::new(ErrorKind::UnknownNick(nick.into()))
+    }
+
+    pub fn not_connected() -> Error {
+        Error::new(ErrorKind::NotConnected)
+    }
+
+    pub fn not_a_file<S: Into<String>>(path: S) -> Error {
+        Error::new(ErrorKind::NotAFile(path.into()))
+    }
+
+    pub fn no_room() -> Error {
+        Error::new(ErrorKind::NoRoom)
+    }
+
+    pub fn no_file_offer() -> Error {
+        Error::new(ErrorKind::NoFileOffer)
+    }
+
+    pub fn incompatible_version(version: u16) -> Error {
+        Error::new(ErrorKind::IncompatibleVersion(version))
+    }
+
+    pub fn unsupported_scheme<S: Into<String>>(scheme: S) -> Error {
+        Error::new(ErrorKind::UnsupportedScheme(scheme.into()))
+    }
+
+    pub fn invalid_identity<S: Into<String>>(path: S) -> Error {
+        Error::new(ErrorKind::InvalidIdentity(path.into()))
+    }
+
+    pub fn no_key<S: Into<String>>(nick: S) -> Error {
+        Error::new(ErrorKind::NoKey(nick.into()))
+    }
+
+    pub fn decryption_failed() -> Error {
+        Error::new(ErrorKind::DecryptionFailed)
+    }
+
+    pub fn replayed() -> Error {
+        Error::new(ErrorKind::Replayed)
+    }
+
+    pub fn not_encrypted() -> Error {
+        Error::new(ErrorKind::NotEncrypted)
+    }
+
+    pub fn unknown_command<S: Into<String>>(name: S) -> Error {
+        Error::new(ErrorKind::UnknownCommand(name.into()))
+    }
+
+    pub fn command_usage<S: Into<String>, U: Into<String>>(reason: S, usage: U) -> Error {
+        Error::new(ErrorKind::CommandUsage { reason: reason.into(), usage: usage.into() })
+    }
+
+    pub fn invalid_theme<S: Into<String>>(entry: S) -> Error {
+        Error::new(ErrorKind::InvalidTheme(entry.into()))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl From<fmt::Error> for Error {
+    fn from(err: fmt::Error) -> Error {
+        Error::new(ErrorKind::Fmt(err))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::new(ErrorKind::Io(err))
+    }
+}
+
+impl From<ws::Error> for Error {
+    fn from(err: ws::Error) -> Error {
+        Error::new(ErrorKind::Ws(err))
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(err: url::ParseError) -> Error {
+        Error::new(ErrorKind::UrlParse(err))
+    }
+}
+
+impl From<ErrorStack> for Error {
+    fn from(err: ErrorStack) -> Error {
+        Error::new(ErrorKind::OpenSsl(err))
+    }
+}
+
+impl From<Box<bincode::ErrorKind>> for Error {
+    fn from(err: Box<bincode::ErrorKind>) -> Error {
+        Error::new(ErrorKind::Bincode(err))
+    }
+}
+
+impl From<Error> for ws::Error {
+    fn from(err: Error) -> ws::Error {
+        ws::Error::new(ws::ErrorKind::Internal, err.to_string())
+    }
+}
+
+
+/// Returns `duration` in whole milliseconds, as taken by `ws::Sender::timeout`.
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
@@ -1,136 +1,120 @@
-//! A websocket based chat client/server.
-//!
-//!
-//!
-//!
+//! The console user interface of the HeadsUp chat client/server, built on
+//! the `headsup` library.
 
+extern crate headsup;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde_json;
 extern crate env_logger;
-#[macro_use] extern crate failure;
 extern crate url;
 extern crate clap;
 extern crate ws;
 extern crate termion;
-#[macro_use] extern crate serde_derive;
-extern crate bincode;
 extern crate chrono;
-
-mod client;
-mod server;
+extern crate signal_hook;
+extern crate unicode_width;
+
+mod headless;
+mod pipe;
+mod scrollback;
+mod editor;
+mod input_history;
+mod completion;
+mod commands;
+mod theme;
+mod latency;
+mod control;
 
 use std::mem;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::env;
+use std::fs;
+use std::process;
 use std::str;
 use std::fmt;
 use std::time::{Duration};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::io::{self, Write,};
 use std::thread;
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Sender as MpscSender, Receiver as MpscReceiver};
-use failure::Context;
-use termion::{raw::{IntoRawMode, RawTerminal}, event::Key, input::TermRead};
+use termion::{raw::{IntoRawMode, RawTerminal}, event::{Event as TermEvent, Key}, input::TermRead};
+use unicode_width::UnicodeWidthStr;
 use clap::{App, Arg};
 use url::Url;
-use ws::{Handshake, CloseCode, util::Token};
-use chrono::{DateTime, Utc, serde::ts_nanoseconds};
-use client::Client;
-use server::Server;
-
-
-/// Error Kinds.
-#[derive(Debug, Fail)]
-pub enum ErrorKind {
-    #[fail(display = "{}", _0)]
-    Fmt(fmt::Error),
-    #[fail(display = "{}", _0)]
-    Io(io::Error),
-    #[fail(display = "Websocket error: {}", _0)]
-    Ws(ws::Error),
-    #[fail(display = "Codec error: {}", _0)]
-    Bincode(Box<bincode::ErrorKind>),
-    #[fail(display = "Error parsing url: {}", _0)]
-    UrlParse(url::ParseError),
-    #[fail(display = "Invalid server address: {}", _0)]
-    BadServerAddr(io::Error),
-    #[fail(display = "Invalid client address: {}", _0)]
-    BadClientAddr(io::Error),
-    #[fail(display = "No server address given.")]
-    NoServerAddr,
+use headsup::{protocol, history, tls, crypto, client, server, transfer, Error, ErrorKind, Event,
+    EventSink, ChannelSink, FanOut};
+use client::{Client, ClientOptions, ReconnectOptions, SERVER_PEER};
+use server::{Server, ServerOptions};
+use crypto::Identity;
+use scrollback::{Scrollback, DEFAULT_SCROLLBACK_LEN};
+use editor::LineEditor;
+use input_history::{InputHistory, INPUT_HISTORY_FILE_NAME};
+use completion::{Completion, Sources};
+use commands::{Registry, Command, Param, ParamKind, Args};
+use theme::{Theme, StyledLine, Role};
+use latency::{LatencyStats, format_rtt};
+use control::{ControlSocket, Request};
+use chrono::{DateTime, Local, Utc};
+use chrono::format::{StrftimeItems, Item};
+use protocol::{Presence, HistoryEntry, RoomInfo, FileMsg, DEFAULT_ROOM, AUTH_FAILED};
+use history::{History, HistoryOptions};
+use transfer::{Transfers, Status, Direction, format_size};
+
+
+/// Returns the directory containing user configuration files (e.g.
+/// '~/.config/headsup').
+fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("headsup"))
 }
 
 
-/// Errors.
-#[derive(Debug)]
-pub struct Error {
-    inner: Context<ErrorKind>,
+/// Returns the first socket address `addr` resolves to.
+fn resolve_server_addr(addr: &str) -> Result<SocketAddr, Error> {
+    addr.to_socket_addrs()
+        .map_err(Error::bad_server_addr)?
+        .next().ok_or(Error::no_server_addr())
 }
 
-impl Error {
-    pub fn new(kind: ErrorKind) -> Error {
-        Error { inner: Context::new(kind) }
-    }
-
-    pub fn kind(&self) -> &ErrorKind {
-        &self.inner.get_context()
-    }
-
-    pub fn bad_server_addr(err: io::Error) -> Error {
-        Error::new(ErrorKind::BadServerAddr(err))
-    }
-
-    pub fn no_server_addr() -> Error {
-        Error::new(ErrorKind::NoServerAddr)
-    }
-}
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Display::fmt(&self.inner, f)
-    }
-}
 
-impl From<fmt::Error> for Error {
-    fn from(err: fmt::Error) -> Error {
-        Error::new(ErrorKind::Fmt(err))
-    }
-}
+/// The interval at which connections are pinged unless '--ping-interval' is
+/// given.
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
 
-impl From<io::Error> for Error {
-    fn from(err: io::Error) -> Error {
-        Error::new(ErrorKind::Io(err))
-    }
-}
+/// The number of unanswered pings after which a connection is closed unless
+/// '--max-missed-pings' is given.
+const DEFAULT_MAX_MISSED_PINGS: u32 = 3;
 
-impl From<ws::Error> for Error {
-    fn from(err: ws::Error) -> Error {
-        Error::new(ErrorKind::Ws(err))
-    }
-}
+/// The number of messages shown by '/history' when no count is given.
+const DEFAULT_HISTORY_COUNT: u32 = 50;
 
-impl From<url::ParseError> for Error {
-    fn from(err: url::ParseError) -> Error {
-        Error::new(ErrorKind::UrlParse(err))
-    }
-}
+/// The format of the times shown before messages unless '--time-format' is
+/// given.
+const DEFAULT_TIME_FORMAT: &str = "%H:%M";
 
-impl From<Box<bincode::ErrorKind>> for Error {
-    fn from(err: Box<bincode::ErrorKind>) -> Error {
-        Error::new(ErrorKind::Bincode(err))
-    }
+/// Returns true if `format` is a valid strftime-like format string.
+fn is_valid_time_format(format: &str) -> bool {
+    StrftimeItems::new(format).all(|item| item != Item::Error)
 }
 
+/// The name of the theme file within the config directory, used unless
+/// '--theme' is given.
+const THEME_FILE_NAME: &str = "theme";
 
+/// The number of columns left at the prompt for the line being typed.
+const MIN_INPUT_WIDTH: usize = 20;
 
-/// Ping timestamp.
-#[derive(Debug, Serialize, Deserialize)]
-pub enum Pingstamp {
-    Ping(#[serde(with = "ts_nanoseconds")] DateTime<Utc>),
-    Pong(#[serde(with = "ts_nanoseconds")] DateTime<Utc>),
-}
+/// Terminal escape sequences enabling and disabling bracketed paste mode.
+const BRACKETED_PASTE_ON: &str = "\x1b[?2004h";
+const BRACKETED_PASTE_OFF: &str = "\x1b[?2004l";
 
-impl Pingstamp {
-    pub fn now() -> Pingstamp {
-        Pingstamp::Ping(Utc::now())
-    }
-}
+/// The (otherwise unrecognized) sequences surrounding pasted text.
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
 
 
 /// The connection state of the ui.
@@ -141,125 +125,261 @@ enum ConnectionState {
     None,
 }
 
-#[derive(Debug)]
-enum UiCommand {
-    ServerOpened(Handshake),
-    ServerClosed(CloseCode, String),
-    ServerError(Error),
-    ServerShutdown,
-    ClientOpened(Handshake),
-    ClientClosed(CloseCode, String),
-    ClientError(Error),
-    ClientShutdown,
-    MessageRecvd(String, Token),
-    PongRecvd(chrono::Duration),
+enum CloseOptions {
+    Decrement,
+    Shutdown,
 }
 
 
-/// A remote control used to send state information to the user interface.
-#[derive(Debug, Clone)]
-pub struct UiRemote {
-    cmd_tx: MpscSender<UiCommand>,
+/// The state of a reverse search through the input history.
+struct HistorySearch {
+    query: String,
+    // The index of the matching history line:
+    found: Option<usize>,
+    failed: bool,
+    // The line being edited before searching:
+    original: String,
 }
 
-impl UiRemote {
-    pub fn server_connected(&self, shake: Handshake) {
-        self.cmd_tx.send(UiCommand::ServerOpened(shake)).unwrap()
-    }
-
-    pub fn server_closed(&self, code: CloseCode, reason: String) {
-        self.cmd_tx.send(UiCommand::ServerClosed(code, reason)).unwrap()
-    }
-
-    pub fn server_error(&self, err: Error) {
-        self.cmd_tx.send(UiCommand::ServerError(err)).unwrap()
-    }
-
-    pub fn server_shutdown(&self) {
-        self.cmd_tx.send(UiCommand::ServerShutdown).unwrap()
-    }
-
-    pub fn client_connected(&self, shake: Handshake) {
-        self.cmd_tx.send(UiCommand::ClientOpened(shake)).unwrap()
-    }
-
-    pub fn client_closed(&self, code: CloseCode, reason: String) {
-        self.cmd_tx.send(UiCommand::ClientClosed(code, reason)).unwrap()
-    }
-
-    pub fn client_error(&self, err: Error) {
-        self.cmd_tx.send(UiCommand::ClientError(err)).unwrap()
-    }
-
-    pub fn client_shutdown(&self) {
-        self.cmd_tx.send(UiCommand::ClientShutdown).unwrap()
-    }
-
-    pub fn message_recvd(&self, msg_text: String, token: Token) {
-        self.cmd_tx.send(UiCommand::MessageRecvd(msg_text, token)).unwrap()
-    }
 
-    pub fn pong_recvd(&self, elapsed: chrono::Duration) {
-        self.cmd_tx.send(UiCommand::PongRecvd(elapsed)).unwrap()
-    }
+/// Returns the commands understood by the console interface, in the order
+/// they are listed by '/help'.
+fn console_commands() -> Registry<ConsoleUi> {
+    let mut commands = Registry::new();
+    commands.register(Command::new("connect", ConsoleUi::connect)
+        .alias("open")
+        .param(Param::required("url", ParamKind::Word))
+        .param(Param::optional("password", ParamKind::Word))
+        .summary("connect to a server. Use a 'wss://' url for an encrypted connection.")
+        .details("The url may also be given as 'host:port'. The password is only needed by \
+            servers started with '--password' and defaults to '--client-password'."));
+    commands.register(Command::new("nick", ConsoleUi::nick)
+        .param(Param::optional("name", ParamKind::Word))
+        .summary("change your nickname.")
+        .details("Without a name, shows your current nickname."));
+    commands.register(Command::new("join", |ui: &mut ConsoleUi, args| {
+            let res = ui.join(args.get(0).unwrap());
+            ui.output_result(res)
+        })
+        .param(Param::required("#room", ParamKind::Word))
+        .summary("join or switch to a room.")
+        .details("Room names start with '#'. Chat messages are sent to the most recently \
+            joined or switched to room."));
+    commands.register(Command::new("part", |ui: &mut ConsoleUi, args| {
+            let res = ui.part(args.get(0));
+            ui.output_result(res)
+        })
+        .param(Param::optional("#room", ParamKind::Word))
+        .summary("leave a room.")
+        .details("Without a room, leaves the active room."));
+    commands.register(Command::new("list", |ui: &mut ConsoleUi, _| ui.list_rooms())
+        .summary("list all rooms."));
+    commands.register(Command::new("msg", |ui: &mut ConsoleUi, args| {
+            let res = ui.send_direct(args.get(0).unwrap(), args.get(1).unwrap().trim());
+            ui.output_result(res)
+        })
+        .param(Param::required("nick", ParamKind::Word))
+        .param(Param::required("message", ParamKind::Text))
+        .summary("send a private message.")
+        .details("Private messages are routed by the server to the participant using the \
+            nickname only."));
+    commands.register(Command::new("send", ConsoleUi::offer_file)
+        .param(Param::required("path", ParamKind::Word))
+        .param(Param::optional("nick", ParamKind::Word))
+        .summary("offer to send a file to a participant.")
+        .details("The nickname may be left out if there is only one other participant. The \
+            file is sent once accepted, with its progress shown in the prompt, and its \
+            SHA-256 digest is checked by the recipient. With '--e2e', files are encrypted \
+            end-to-end and only sent to participants which also use it.")
+        .details("To resume an interrupted transfer, send the file again."));
+    commands.register(Command::new("accept", ConsoleUi::accept_file)
+        .param(Param::optional("nick", ParamKind::Word))
+        .summary("accept the oldest file offered to you.")
+        .details("Accepts the oldest file offered by the participant using the nickname, if \
+            given. Files are saved in the current directory unless '--download-dir' is \
+            given. A partial earlier download of the same file is resumed."));
+    commands.register(Command::new("reject", ConsoleUi::reject_file)
+        .param(Param::optional("nick", ParamKind::Word))
+        .summary("reject the oldest file offered to you."));
+    commands.register(Command::new("fingerprint", ConsoleUi::fingerprint)
+        .param(Param::optional("nick", ParamKind::Word))
+        .summary("show an encryption key fingerprint.")
+        .details("Without a nickname, shows your own fingerprint. Compare fingerprints out of \
+            band to make sure that no one is intercepting encrypted messages."));
+    commands.register(Command::new("whois", ConsoleUi::fingerprint)
+        .param(Param::required("nick", ParamKind::Word))
+        .summary("show the encryption key fingerprint of a participant."));
+    commands.register(Command::new("ping", ConsoleUi::ping)
+        .param(Param::optional("nick", ParamKind::Word))
+        .summary("measure the round-trip time to a participant.")
+        .details("As the server, pings the client using the nickname or, without one, every \
+            client. As a client, pings the server.")
+        .details("See '/stats' for the round-trip times measured so far, including those of \
+            the background pings (see '--ping-interval')."));
+    commands.register(Command::new("stats", |ui: &mut ConsoleUi, _| ui.stats())
+        .summary("show round-trip time statistics for each connection.")
+        .details("Shows the minimum, average, maximum and 95th percentile of the most recent \
+            100 round-trip times, and the jitter: their mean difference from one to the \
+            next."));
+    commands.register(Command::new("history", ConsoleUi::history)
+        .param(Param::optional("count", ParamKind::Count))
+        .summary("show recent messages.")
+        .details("Shows up to 50 of the messages most recently sent to the active room unless \
+            a count is given."));
+    commands.register(Command::new("timestamps", ConsoleUi::timestamps)
+        .param(Param::optional("on|off", ParamKind::Word))
+        .summary("show or hide message times.")
+        .details("Without an argument, toggles whether times are shown. Times are those at \
+            which the server received each message, in local time."));
+    commands.register(Command::new("reconnect", |ui: &mut ConsoleUi, _| ui.reconnect())
+        .summary("reconnect to the most recent server.")
+        .details("If a reconnection is pending, retries immediately."));
+    commands.register(Command::new("cancel", |ui: &mut ConsoleUi, _| ui.cancel())
+        .summary("stop reconnecting."));
+    commands.register(Command::new("close", |ui: &mut ConsoleUi, _| ui.close_all())
+        .summary("close the current connection.")
+        .details("When acting as the server, closes all client connections."));
+    commands.register(Command::new("help", ConsoleUi::help)
+        .param(Param::optional("command", ParamKind::Word))
+        .summary("show this help or the details of a command."));
+    commands.register(Command::new("exit", |ui: &mut ConsoleUi, _| { ui.exit = true; Ok(()) })
+        .alias("quit")
+        .summary("quit."));
+    commands
 }
 
 
-enum CloseOptions {
-    None,
-    Decrement,
-    Shutdown,
+/// Settings of the console interface itself.
+struct UiOptions {
+    /// The maximum number of lines kept in the message pane.
+    scrollback_len: usize,
+    /// The file in which lines typed at the prompt are saved, if any.
+    input_history_path: Option<PathBuf>,
+    theme: Theme,
+    /// The `strftime` format of message times.
+    time_format: String,
+    control: Option<ControlSocket>,
+    /// The directory in which received files are saved.
+    download_dir: PathBuf,
 }
 
 
 /// The console interface.
 struct ConsoleUi {
-    cmd_tx: MpscSender<UiCommand>,
-    cmd_rx: MpscReceiver<UiCommand>,
+    cmd_tx: MpscSender<Event>,
+    cmd_rx: MpscReceiver<Event>,
     conn_state: ConnectionState,
     // If server address is bad it will be set to `None`:
     server_addr: Option<SocketAddr>,
+    // Our own nickname, as last confirmed by the server when connected:
+    nick: String,
+    // Joined rooms. The most recently joined is the active room, to which
+    // chat messages are sent:
+    rooms: Vec<String>,
+    // Used to open the history of each new server:
+    history_opts: HistoryOptions,
+    server_opts: ServerOptions,
+    client_opts: ClientOptions,
+    // The most recent server connected to as a client and its password:
+    last_url: Option<Url>,
+    last_password: Option<String>,
+    // Output lines displayed in the message pane above the prompt:
+    scrollback: RefCell<Scrollback>,
+    // Set when the message pane needs to be redrawn:
+    pane_dirty: Cell<bool>,
+    theme: Theme,
+    // Round-trip times measured on each connection, by peer nickname:
+    latency: BTreeMap<String, LatencyStats>,
+    // Peers pinged with '/ping' whose round-trip times are yet to be shown:
+    pending_pings: BTreeSet<String>,
+    // Files being offered, sent and received:
+    transfers: Transfers,
+    // Whether message times are shown and their format:
+    show_timestamps: bool,
+    time_format: String,
+    // The line being typed at the prompt:
+    editor: LineEditor,
+    input_history: InputHistory,
+    search: Option<HistorySearch>,
+    // Set while pressing Tab repeatedly cycles through completions:
+    completion: Option<Completion>,
+    commands: Registry<ConsoleUi>,
+    // Through which other programs drive the ui, if '--control-socket' is
+    // given:
+    control: Option<ControlSocket>,
     // Must be stored to keep terminal in raw mode:
     stdout: RawTerminal<io::Stdout>,
     term_size: (u16, u16),
+    // Set by SIGWINCH when the terminal has been resized:
+    resized: Arc<AtomicBool>,
     exit: bool,
 }
 
 impl ConsoleUi {
     /// Creates and returns a new console user interface.
-    fn new<'s>(server_addr: &'s str, client_addr: Option<Url>) -> Result<ConsoleUi, Error> {
+    fn new(server_addr: &str, client_addr: Option<Url>, nick: String,
+            history_opts: HistoryOptions, server_opts: ServerOptions,
+            client_opts: ClientOptions, ui_opts: UiOptions) -> Result<ConsoleUi, Error> {
+        let UiOptions { scrollback_len, input_history_path, theme, time_format, control,
+            download_dir } = ui_opts;
         let (cmd_tx, cmd_rx) = mpsc::channel();
 
-        let server_addr = Some(server_addr.to_socket_addrs()
-            .map_err(|err| Error::bad_server_addr(err))?
-            .nth(0).ok_or(Error::no_server_addr())?);
+        let server_addr = Some(resolve_server_addr(server_addr)?);
+        let resized = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::SIGWINCH, resized.clone())?;
 
         let mut ui = ConsoleUi {
             cmd_tx,
             cmd_rx,
             conn_state: ConnectionState::None,
             server_addr,
+            nick,
+            rooms: vec![DEFAULT_ROOM.to_owned()],
+            history_opts,
+            server_opts,
+            client_opts,
+            last_url: client_addr.clone(),
+            last_password: None,
+            scrollback: RefCell::new(Scrollback::new(scrollback_len)),
+            pane_dirty: Cell::new(true),
+            theme,
+            latency: BTreeMap::new(),
+            pending_pings: BTreeSet::new(),
+            transfers: Transfers::new(download_dir),
+            show_timestamps: true,
+            time_format,
+            editor: LineEditor::new(),
+            input_history: InputHistory::new(),
+            search: None,
+            completion: None,
+            commands: console_commands(),
+            control,
             stdout: io::stdout().into_raw_mode()?,
             term_size: termion::terminal_size()?,
+            resized,
             exit: false,
         };
 
         ui.conn_state = match client_addr {
             Some(cl_addr) => {
-                match Client::new(cl_addr.clone(), ui.remote()) {
+                match Client::new(cl_addr.clone(), &ui.nick, ui.client_opts.clone(), ui.remote()) {
                     Ok(c) => ConnectionState::Client(c),
                     Err(err) => {
-                        ui.output_line(format_args!("Error connecting to client address: {} ({})",
+                        ui.output_error(format_args!("Error connecting to client address: {} ({})",
                             cl_addr, err))?;
                         ui.new_server()?
                     },
                 }
             },
             None => {
-                match Server::new(ui.server_addr.clone().unwrap(), ui.remote()) {
+                let server = History::open(&ui.history_opts).and_then(|history| {
+                    Server::new(ui.server_addr.unwrap(), &ui.nick, history,
+                        ui.server_opts.clone(), ui.remote())
+                });
+                match server {
                     Ok(s) => ConnectionState::ServerListening(s),
                     Err(err) => {
-                        ui.output_line(format_args!("Unable to connect to serve address: {} ({})",
+                        ui.output_error(format_args!("Unable to connect to serve address: {} ({})",
                             ui.server_addr.as_ref().unwrap(), err))?;
                         ui.server_addr = None;
                         ConnectionState::None
@@ -269,61 +389,256 @@ impl ConsoleUi {
         };
 
         ui.output_line(format_args!("Welcome to HeadsUp chat!"))?;
-        ui.help()?;
+        ui.help(&Args::default())?;
+        if let Some(path) = input_history_path {
+            match InputHistory::open(&path) {
+                Ok(h) => ui.input_history = h,
+                Err(err) => ui.output_error(format_args!("Unable to open input history file \
+                    '{}': {}", path.display(), err))?,
+            }
+        }
         Ok(ui)
     }
 
-    /// Returns a new `UiRemote` which can send commands and receive events.
-    fn remote(&self) -> UiRemote {
-        UiRemote { cmd_tx: self.cmd_tx.clone() }
+    /// Returns the active room.
+    fn room(&self) -> Option<&str> {
+        self.rooms.last().map(String::as_str)
+    }
+
+    /// Returns a new sink whose events are handled by the ui and, if a
+    /// control socket is open, passed on to the programs connected to it.
+    fn remote(&self) -> Arc<dyn EventSink> {
+        let sink: Arc<dyn EventSink> = Arc::new(ChannelSink::new(self.cmd_tx.clone()));
+        match self.control {
+            Some(ref control) => Arc::new(FanOut::new(vec![sink, control.sink()])),
+            None => sink,
+        }
     }
 
-    /// Outputs a formatted line of text.
+    /// Outputs a line of styled text to the message pane.
+    fn output_styled(&self, line: StyledLine) -> io::Result<()> {
+        self.scrollback.borrow_mut().push(line);
+        self.pane_dirty.set(true);
+        Ok(())
+    }
+
+    /// Outputs a formatted line of text to the message pane.
     fn output_line(&self, args: fmt::Arguments) -> io::Result<()> {
-        write!(io::stdout().into_raw_mode()?, "{}{}{}\r\n",
-            termion::cursor::Goto(0, self.term_size.1),
-            termion::clear::CurrentLine,
-            args,
-        )
+        self.output_styled(StyledLine::with(Role::Text, fmt::format(args)))
+    }
+
+    /// Outputs a formatted system notice.
+    fn output_notice(&self, args: fmt::Arguments) -> io::Result<()> {
+        self.output_styled(StyledLine::with(Role::Notice, fmt::format(args)))
     }
 
-    /// Prints the help message.
-    fn help(&self) -> Result <(), Error> {
+    /// Outputs a formatted error message.
+    fn output_error(&self, args: fmt::Arguments) -> io::Result<()> {
+        let text = fmt::format(args);
+        if let Some(ref control) = self.control {
+            control.error(&text);
+        }
+        self.output_styled(StyledLine::with(Role::Error, text))
+    }
+
+    /// Outputs the error of a command which failed, if any.
+    fn output_result(&self, res: Result<(), Error>) -> Result<(), Error> {
+        if let Err(err) = res {
+            self.output_line(format_args!("{}", err))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the role of `nick`, distinguishing our own nickname.
+    fn nick_role(&self, nick: &str) -> Role {
+        if nick == self.nick { Role::OwnNick } else { Role::nick(nick) }
+    }
+
+    /// Returns a new line starting with `time` in local time, or an empty
+    /// line if times are hidden.
+    fn stamped_line(&self, time: DateTime<Utc>) -> StyledLine {
+        if !self.show_timestamps { return StyledLine::new(); }
+        let time = time.with_timezone(&Local).format(&self.time_format);
+        StyledLine::with(Role::Time, format!("[{}] ", time))
+    }
+
+    /// Outputs a chat message received by the server at `time`, prefixed by
+    /// its room unless sent to the active room.
+    fn output_message(&self, time: DateTime<Utc>, room: Option<&str>, from: &str, text: &str)
+            -> io::Result<()> {
+        let mut line = self.stamped_line(time);
+        if let Some(room) = room {
+            line.append(Role::Text, format!("[{}] ", room));
+        }
+        self.output_styled(line
+            .push(Role::Text, "{")
+            .push(self.nick_role(from), from)
+            .push(Role::Text, format!("}}: {}", text)))
+    }
+
+    /// Returns the height of the message pane, in rows.
+    fn pane_height(&self) -> usize {
+        self.term_size.1.saturating_sub(1) as usize
+    }
+
+    /// Redraws the message pane above the prompt if it has changed.
+    fn output_pane(&mut self) -> Result <(), Error> {
+        if !self.pane_dirty.replace(false) { return Ok(()); }
+        let (width, height) = (self.term_size.0 as usize, self.pane_height());
+        let (rows, indicator) = {
+            let scrollback = self.scrollback.borrow();
+            if scrollback.is_scrolled() {
+                // The bottom row shows where the view is:
+                let indicator = match scrollback.unseen() {
+                    0 => "-- More below. Press End to return. --".to_owned(),
+                    1 => "-- 1 new message below. Press End to return. --".to_owned(),
+                    n => format!("-- {} new messages below. Press End to return. --", n),
+                };
+                (scrollback.rows(width, height.saturating_sub(1)),
+                    Some(StyledLine::with(Role::Notice, indicator)))
+            } else {
+                (scrollback.rows(width, height), None)
+            }
+        };
+
+        // Rows are aligned to the bottom of the pane:
+        let blank = height.saturating_sub(rows.len() + indicator.is_some() as usize);
+        for y in 0..blank {
+            write!(self.stdout, "{}{}", termion::cursor::Goto(1, y as u16 + 1),
+                termion::clear::CurrentLine)?;
+        }
+        for (y, row) in rows.iter().chain(indicator.iter()).enumerate() {
+            let mut text = String::new();
+            self.theme.render(row, &mut text);
+            write!(self.stdout, "{}{}{}", termion::cursor::Goto(1, (blank + y) as u16 + 1),
+                termion::clear::CurrentLine, text)?;
+        }
+        Ok(())
+    }
+
+    /// Updates the terminal size and redraws everything.
+    fn resize(&mut self) -> Result <(), Error> {
+        self.term_size = termion::terminal_size()?;
+        write!(self.stdout, "{}", termion::clear::All)?;
+        self.pane_dirty.set(true);
+        Ok(())
+    }
+
+    /// Scrolls the message pane in response to a navigation key. Home and
+    /// End only scroll while scrolled up and otherwise move the cursor.
+    fn scroll(&mut self, key: Key) {
+        let page = self.pane_height().saturating_sub(1).max(1);
+        {
+            let mut scrollback = self.scrollback.borrow_mut();
+            match key {
+                Key::PageUp => scrollback.scroll_up(page),
+                Key::PageDown => scrollback.scroll_down(page),
+                Key::Home => scrollback.scroll_to_top(),
+                Key::End => scrollback.scroll_to_bottom(),
+                _ => return,
+            }
+        }
+        self.pane_dirty.set(true);
+    }
+
+    /// Prints the help message or, if a command is given, its detailed
+    /// usage.
+    fn help(&mut self, args: &Args) -> Result <(), Error> {
+        if let Some(name) = args.get(0) {
+            let command = match self.commands.find(name.trim_start_matches('/')) {
+                Some(c) => c,
+                None => return self.output_line(format_args!("{}", Error::unknown_command(name)))
+                    .map_err(Error::from),
+            };
+            self.output_line(format_args!("Usage: {}", command.usage()))?;
+            self.output_line(format_args!("{}", command.summary_line()))?;
+            for line in command.details_lines() {
+                self.output_line(format_args!("{}", line))?;
+            }
+            return Ok(());
+        }
+
         self.output_line(format_args!(""))?;
-        self.output_line(format_args!("Type '/open {{url}}' or '/connect {{url}}' \
-            to connect to a server."))?;
-        self.output_line(format_args!("Type '/close' to close the current connection."))?;
-        self.output_line(format_args!("Type '/exit' or press ctrl-q to quit."))?;
+        for command in self.commands.commands() {
+            self.output_line(format_args!("{}", command.summary_line()))?;
+        }
+        self.output_line(format_args!("Press PageUp/PageDown to scroll. While scrolled, press \
+            Home/End to jump to the oldest/newest messages."))?;
+        self.output_line(format_args!("Press Left/Right, Home/End (or ctrl-a/ctrl-e) to move \
+            within the line and ctrl-w, ctrl-u or ctrl-k to delete the previous word, the start \
+            or the rest of the line."))?;
+        self.output_line(format_args!("Press Up/Down to recall previously typed lines and \
+            ctrl-r to search them."))?;
+        self.output_line(format_args!("Press Tab to complete a command, nickname or server \
+            url. Press it again to cycle through the alternatives."))?;
+        self.output_line(format_args!("Press ctrl-q to quit."))?;
         self.output_line(format_args!(""))?;
         Ok(())
     }
 
-    /// Outputs the prompt.
-    fn output_prompt<'l>(&mut self, line_buf: &'l str) -> Result <(), Error> {
-        write!(self.stdout, "{}{}",
-            termion::cursor::Goto(0, self.term_size.1),
+    /// Returns the connection status and active room shown at the prompt.
+    fn status(&self) -> String {
+        let mut status = match self.conn_state {
+            ConnectionState::ServerListening(ref s) if s.is_secure() =>
+                format!("[ Listening on (wss://{}) ", s.url()),
+            ConnectionState::ServerListening(ref s) => format!("[ Listening on ({}) ", s.url()),
+            ConnectionState::ServerConnected(_,  cnt) =>
+                format!("[ Connected as Server to {} clients ", cnt),
+            ConnectionState::Client(ref c) if c.is_connected() =>
+                format!("[ Connected as Client to ({}) ", c.url()),
+            ConnectionState::Client(ref c) => format!("[ Connecting to ({}) ", c.url()),
+            ConnectionState::None => "[ Disconnected ".to_owned(),
+        };
+        match (&self.conn_state, self.rooms.last()) {
+            (&ConnectionState::None, _) => {},
+            (_, Some(room)) => status.push_str(&format!("{} ", room)),
+            (_, None) => status.push_str("(no room) "),
+        }
+        for p in self.transfers.progress() {
+            let arrow = match p.direction { Direction::Sending => '↑', Direction::Receiving => '↓' };
+            let percent = (p.done * 100).checked_div(p.size).unwrap_or(100);
+            status.push_str(&format!("{} {} {}% ", arrow, p.name, percent));
+        }
+        status.push_str("]> ");
+        status
+    }
+
+    /// Outputs the prompt followed by the line being edited, scrolled
+    /// horizontally to fit within the terminal.
+    fn output_prompt(&mut self) -> Result <(), Error> {
+        let prompt = match self.search {
+            Some(HistorySearch { failed: true, ref query, .. }) =>
+                format!("(failed reverse-i-search)`{}': ", query),
+            Some(HistorySearch { ref query, .. }) => format!("(reverse-i-search)`{}': ", query),
+            None => self.status(),
+        };
+
+        // The prompt is cut short if it leaves too little room for the line:
+        let term_width = self.term_size.0 as usize;
+        let min_width = (term_width / 2).min(MIN_INPUT_WIDTH);
+        let prompt = editor::truncate_start(&prompt, term_width - min_width);
+        let width = term_width.saturating_sub(prompt.width());
+        let (line, cursor_col) = self.editor.view(width);
+        write!(self.stdout, "{}{}{}{}{}",
+            termion::cursor::Goto(1, self.term_size.1),
             termion::clear::CurrentLine,
+            prompt,
+            line,
+            termion::cursor::Goto((prompt.width() + cursor_col) as u16 + 1, self.term_size.1),
         )?;
-        match self.conn_state {
-            ConnectionState::ServerListening(ref s) => write!(self.stdout,
-                "[ Listening on ({}) ]> ", s.url()),
-            ConnectionState::ServerConnected(_,  cnt) => write!(self.stdout,
-                "[ Connected as Server to {} clients ]> ", cnt),
-            ConnectionState::Client(ref c) =>  write!(self.stdout,
-                "[ Connected as Client to ({}) ]> ", c.url()),
-            ConnectionState::None => write!(self.stdout, "[ Disconnected ]> "),
-        }?;
-        write!(self.stdout, "{}", line_buf)?;
         self.stdout.flush().map_err(Error::from)
     }
 
     /// If the stored server address is valid, returns a listening connection
     /// state containing a new server.
     fn new_server(&mut self) -> Result <ConnectionState, Error> {
+        // The local user starts out in the default room of a new server:
+        self.rooms = vec![DEFAULT_ROOM.to_owned()];
         Ok(match self.server_addr {
             Some(ref sa) => {
                 ConnectionState::ServerListening(
-                    Server::new(sa.clone(), self.remote())?)
+                    Server::new(*sa, &self.nick, History::open(&self.history_opts)?,
+                        self.server_opts.clone(), self.remote())?)
             },
             None => ConnectionState::None,
         })
@@ -337,14 +652,12 @@ impl ConsoleUi {
                     ConnectionState::ServerListening(s)
                 } else if cnt == 1 {
                     match options {
-                        CloseOptions::None => ConnectionState::ServerConnected(s, cnt),
                         CloseOptions::Decrement => ConnectionState::ServerListening(s),
                         CloseOptions::Shutdown => self.new_server()?,
 
                     }
                 } else {
                     match options {
-                        CloseOptions::None => ConnectionState::ServerConnected(s, cnt),
                         CloseOptions::Decrement => ConnectionState::ServerConnected(s, cnt - 1),
                         CloseOptions::Shutdown => ConnectionState::ServerListening(s),
                     }
@@ -353,7 +666,7 @@ impl ConsoleUi {
             ConnectionState::ServerListening(s) => {
                 match options {
                     CloseOptions::Shutdown => self.new_server()?,
-                    _ => ConnectionState::ServerListening(s),
+                    CloseOptions::Decrement => ConnectionState::ServerListening(s),
                 }
             }
             ConnectionState::Client(_c) => {
@@ -366,30 +679,75 @@ impl ConsoleUi {
         Ok(())
     }
 
-    /// Connects to a server.
-    fn connect<'l>(&mut self, l: &'l str) -> Result <(), Error> {
+    /// Connects to a server, optionally giving a password.
+    fn connect(&mut self, args: &Args) -> Result <(), Error> {
+        let url_str = args.get(0).unwrap();
+        match client::parse_url(url_str) {
+            Ok(url) => self.connect_url(url, args.get(1).map(str::to_owned))?,
+            Err(err) => self.output_line(format_args!("Invalid url: '{}' ({})", url_str, err))?,
+        }
+        Ok(())
+    }
+
+    /// Connects to a server at a parsed url. Uses the password given on the
+    /// command line, if any, unless `password` is given.
+    fn connect_url(&mut self, url: Url, password: Option<String>) -> Result <(), Error> {
         if let ConnectionState::ServerListening(ref s) = self.conn_state {
             // Squelch any errors:
             s.shutdown().ok();
         }
         match self.conn_state {
             ConnectionState::ServerListening(_) | ConnectionState::None => {
-                if let Some(url_str) = l.split(" ").nth(1) {
-                    let url = match Url::parse(&format!("ws:{}", url_str)) {
-                        Ok(u) => u,
-                        Err(_) => {
-                            self.output_line(format_args!("Invalid url: '{}'", url_str))?;
-                            return Ok(());
-                        },
-                    };
-                    let client = Client::new(url.clone(), self.remote())?;
-                    self.conn_state = ConnectionState::Client(client);
-                    self.output_line(format_args!("Connecting to: {}...", url))?;
+                let password = password.or_else(|| self.client_opts.password.clone());
+                let opts = ClientOptions { password: password.clone(), ..self.client_opts.clone() };
+                let client = Client::new(url.clone(), &self.nick, opts, self.remote())?;
+                self.conn_state = ConnectionState::Client(client);
+                self.last_url = Some(url.clone());
+                self.last_password = password;
+                // Rooms joined are confirmed by the server:
+                self.rooms.clear();
+                self.output_notice(format_args!("Connecting to: {}...", url))?;
+            },
+            _ => self.output_line(format_args!("Already connected."))?,
+        }
+        Ok(())
+    }
+
+    /// Retries a pending reconnection immediately or, if no longer connected
+    /// as a client, reconnects to the most recent server.
+    fn reconnect(&mut self) -> Result <(), Error> {
+        match self.conn_state {
+            ConnectionState::Client(ref c) => {
+                if c.reconnect() {
+                    self.output_notice(format_args!("Reconnecting to: {}...", c.url()))?;
                 } else {
-                    self.output_line(format_args!("Invalid server URL."))?;
+                    self.output_line(format_args!("No reconnection pending."))?;
+                }
+            },
+            ConnectionState::ServerConnected(..) => self.output_line(format_args!("Already connected."))?,
+            ConnectionState::ServerListening(_) | ConnectionState::None => {
+                match self.last_url.clone() {
+                    Some(url) => {
+                        let password = self.last_password.clone();
+                        self.connect_url(url, password)?
+                    },
+                    None => self.output_line(format_args!("No previous connection."))?,
                 }
             },
-            _ => self.output_line(format_args!("Already connected."))?,
+        }
+        Ok(())
+    }
+
+    /// Cancels a pending reconnection.
+    fn cancel(&mut self) -> Result <(), Error> {
+        let cancelled = match self.conn_state {
+            ConnectionState::Client(ref c) => c.cancel(),
+            _ => false,
+        };
+        if cancelled {
+            self.output_line(format_args!("Reconnection cancelled."))?;
+        } else {
+            self.output_line(format_args!("No reconnection pending."))?;
         }
         Ok(())
     }
@@ -398,11 +756,11 @@ impl ConsoleUi {
     fn close_all(&mut self) -> Result <(), Error> {
         match self.conn_state {
             ConnectionState::Client(ref c) => {
-                self.output_line(format_args!("Closing connection to server..."))?;
+                self.output_notice(format_args!("Closing connection to server..."))?;
                 c.close()?;
             },
             ConnectionState::ServerConnected(ref s, cnt) => {
-                self.output_line(format_args!("Closing {} client connections...", cnt))?;
+                self.output_notice(format_args!("Closing {} client connections...", cnt))?;
                 s.close_all()?;
             },
             _ => self.output_line(format_args!("Not connected."))?,
@@ -410,82 +768,496 @@ impl ConsoleUi {
         Ok(())
     }
 
-    /// Sends a chat message.
-    fn send_message<'l>(&mut self, l: &'l str) -> Result <(), Error> {
-        let mut close_connection = false;
+    /// Changes our nickname or, if no name is given, prints the current one.
+    fn nick(&mut self, args: &Args) -> Result <(), Error> {
+        match args.get(0) {
+            Some(nick) => {
+                let res = self.change_nick(nick);
+                self.output_result(res)
+            },
+            None => self.output_line(format_args!("Your nickname is: {}", self.nick))
+                .map_err(Error::from),
+        }
+    }
+
+    /// Changes our nickname.
+    fn change_nick(&mut self, nick: &str) -> Result <(), Error> {
+        if !protocol::is_valid_nick(nick) {
+            return Err(Error::invalid_nick(nick));
+        }
+        match self.conn_state {
+            // The server confirms the change with a `NickChanged` command:
+            ConnectionState::Client(ref c) => return c.set_nick(nick),
+            ConnectionState::ServerListening(ref s) | ConnectionState::ServerConnected(ref s, _) => {
+                s.set_nick(nick)?
+            },
+            ConnectionState::None => {},
+        }
+        self.output_notice(format_args!("You are now known as {}.", nick))?;
+        self.nick = nick.to_owned();
+        Ok(())
+    }
+
+    /// Outputs recorded chat messages.
+    fn output_history(&self, entries: &[HistoryEntry]) -> Result <(), Error> {
+        if entries.is_empty() {
+            return self.output_line(format_args!("No message history.")).map_err(Error::from);
+        }
+        self.output_line(format_args!("--- Last {} messages in {} ---", entries.len(),
+            entries[0].room))?;
+        for entry in entries {
+            self.output_styled(self.stamped_line(entry.time)
+                .push(self.nick_role(&entry.from), format!("{{{}}}", entry.from))
+                .push(Role::Text, format!(": {}", entry.text)))?;
+        }
+        self.output_line(format_args!("--- End of history ---")).map_err(Error::from)
+    }
+
+    /// Pings a participant, showing the round-trip time once it replies.
+    fn ping(&mut self, args: &Args) -> Result <(), Error> {
+        let res = match self.conn_state {
+            ConnectionState::Client(ref c) => match args.get(0) {
+                Some(nick) if nick != SERVER_PEER => {
+                    return self.output_error(format_args!("Cannot ping {}: as a client, only \
+                        the server can be pinged.", nick)).map_err(Error::from);
+                },
+                _ => c.ping().map(|_| vec![SERVER_PEER.to_owned()]),
+            },
+            ConnectionState::ServerConnected(ref s, _) => s.ping(args.get(0)),
+            ConnectionState::None | ConnectionState::ServerListening(..) => {
+                return self.output_line(format_args!("Cannot ping: not connected."))
+                    .map_err(Error::from);
+            },
+        };
+        match res {
+            Ok(pinged) => self.pending_pings.extend(pinged),
+            Err(err) => self.output_error(format_args!("{}", err))?,
+        }
+        Ok(())
+    }
+
+    /// Shows the round-trip time statistics of each open connection.
+    fn stats(&mut self) -> Result <(), Error> {
+        let peers = match self.conn_state {
+            ConnectionState::Client(ref c) if c.is_connected() => vec![SERVER_PEER.to_owned()],
+            ConnectionState::ServerConnected(ref s, _) => {
+                s.nicks().into_iter().filter(|n| n != &self.nick).collect()
+            },
+            _ => Vec::new(),
+        };
+        if peers.is_empty() {
+            return self.output_line(format_args!("Not connected.")).map_err(Error::from);
+        }
+
+        self.output_line(format_args!("--- Round-trip times ---"))?;
+        for peer in peers {
+            let stats = match self.latency.get(&peer) {
+                Some(stats) => stats,
+                None => {
+                    self.output_line(format_args!("{}: not measured, type '/ping' to measure.",
+                        peer))?;
+                    continue;
+                },
+            };
+            let fmt = |rtt: Option<chrono::Duration>| rtt.map(format_rtt)
+                .unwrap_or_else(|| "-".to_owned());
+            self.output_line(format_args!("{}: {} pings, last {}, min {}, avg {}, max {}, \
+                p95 {}, jitter {}", peer, stats.count(), fmt(stats.last()), fmt(stats.min()),
+                fmt(stats.mean()), fmt(stats.max()), fmt(stats.p95()), fmt(stats.jitter())))?;
+        }
+        Ok(())
+    }
+
+    /// Shows or hides the times of messages.
+    fn timestamps(&mut self, args: &Args) -> Result <(), Error> {
+        self.show_timestamps = match args.get(0) {
+            Some("on") => true,
+            Some("off") => false,
+            Some(arg) => {
+                let err = Error::command_usage(format!("Invalid argument: '{}'.", arg),
+                    "/timestamps [on|off]");
+                return self.output_error(format_args!("{}", err)).map_err(Error::from);
+            },
+            None => !self.show_timestamps,
+        };
+        let state = if self.show_timestamps { "shown" } else { "hidden" };
+        self.output_notice(format_args!("Message times are now {}.", state)).map_err(Error::from)
+    }
+
+    /// Shows the most recent messages, requesting them from the server if
+    /// connected as a client.
+    fn history(&mut self, args: &Args) -> Result <(), Error> {
+        let count = args.count(0).unwrap_or(DEFAULT_HISTORY_COUNT);
+        let room = match self.room() {
+            Some(r) => r.to_owned(),
+            None => return self.output_line(format_args!("Not in a room.")).map_err(Error::from),
+        };
+        match self.conn_state {
+            ConnectionState::Client(ref c) => c.request_history(&room, count)?,
+            ConnectionState::ServerListening(ref s) | ConnectionState::ServerConnected(ref s, _) => {
+                self.output_history(&s.history(&room, count as usize))?;
+            },
+            ConnectionState::None => self.output_line(format_args!("Not connected."))?,
+        }
+        Ok(())
+    }
+
+    /// Joins a room or, if already joined, makes it the active room.
+    fn join(&mut self, room: &str) -> Result <(), Error> {
+        if !protocol::is_valid_room(room) {
+            return Err(Error::invalid_room(room));
+        }
+        match self.conn_state {
+            // The server confirms with a `RoomJoined` command:
+            ConnectionState::Client(ref c) => c.join(room),
+            ConnectionState::ServerListening(ref s) | ConnectionState::ServerConnected(ref s, _) => {
+                s.join(room)?;
+                self.remote().room_joined(room.to_owned());
+                Ok(())
+            },
+            ConnectionState::None => Err(Error::not_connected()),
+        }
+    }
+
+    /// Leaves a room, defaulting to the active room.
+    fn part(&mut self, room: Option<&str>) -> Result <(), Error> {
+        let room = room.or(self.room()).map(str::to_owned).ok_or_else(Error::no_room)?;
+        match self.conn_state {
+            // The server confirms with a `RoomParted` command:
+            ConnectionState::Client(ref c) => c.part(&room),
+            ConnectionState::ServerListening(ref s) | ConnectionState::ServerConnected(ref s, _) => {
+                s.part(&room)?;
+                self.remote().room_parted(room);
+                Ok(())
+            },
+            ConnectionState::None => Err(Error::not_connected()),
+        }
+    }
+
+    /// Lists all rooms.
+    fn list_rooms(&mut self) -> Result <(), Error> {
+        match self.conn_state {
+            ConnectionState::Client(ref c) => c.list_rooms()?,
+            ConnectionState::ServerListening(ref s) | ConnectionState::ServerConnected(ref s, _) => {
+                self.output_room_list(&s.rooms())?;
+            },
+            ConnectionState::None => self.output_line(format_args!("Not connected."))?,
+        }
+        Ok(())
+    }
+
+    /// Outputs a list of rooms.
+    fn output_room_list(&self, rooms: &[RoomInfo]) -> Result <(), Error> {
+        self.output_line(format_args!("--- {} rooms ---", rooms.len()))?;
+        for room in rooms {
+            let active = if Some(room.name.as_str()) == self.room() { " (active)" } else { "" };
+            self.output_line(format_args!("{} ({} members){}", room.name, room.members, active))?;
+        }
+        Ok(())
+    }
+
+    /// Sends a chat message to the active room.
+    fn send_chat(&mut self, text: &str) -> Result <(), Error> {
+        let room = self.room().map(str::to_owned).ok_or_else(Error::no_room)?;
         match self.conn_state {
             ConnectionState::ServerConnected(ref server, _) => {
-                self.output_line(format_args!("{{You (Server)}}: {}", l))?;
-                if let Err(err) = server.send(l) {
-                    self.output_line(format_args!("Error sending message to client: {}", err))?;
-                    close_connection = true;
+                self.output_message(Utc::now(), None, &self.nick, text)?;
+                if let Err(err) = server.send_chat(&room, text) {
+                    self.output_error(format_args!("Error sending message to client: {}", err))?;
+                    self.close_connection(CloseOptions::Decrement)?;
+                    return Err(err);
                 }
             },
+            // The client itself reports when its connection is lost:
             ConnectionState::Client(ref client) => {
-                self.output_line(format_args!("{{You (Client)}}: {}", l))?;
-                if let Err(err) = client.send(l) {
-                    self.output_line(format_args!("Error sending message to server: {}", err))?;
-                    close_connection = true;
-                }
+                client.send_chat(&room, text)?;
+                self.output_message(Utc::now(), None, &self.nick, text)?;
             },
             ConnectionState::None | ConnectionState::ServerListening(..) => {
-                self.output_line(format_args!("Cannot send message: '{}'. Not connected.", l))?;
+                return Err(Error::not_connected());
+            },
+        }
+        Ok(())
+    }
+
+    /// Sends a private message to a single participant.
+    fn send_direct(&mut self, to: &str, text: &str) -> Result <(), Error> {
+        match self.conn_state {
+            // Unknown nicknames are reported by the server:
+            ConnectionState::Client(ref c) => c.send_direct(to, text)?,
+            ConnectionState::ServerConnected(ref s, _) => s.send_direct(to, text)?,
+            ConnectionState::None | ConnectionState::ServerListening(..) => {
+                return Err(Error::not_connected());
+            },
+        }
+        self.output_styled(self.stamped_line(Utc::now()).push(Role::Direct, "-> *")
+            .push(Role::OwnNick, to)
+            .push(Role::Direct, format!("*: {}", text)))?;
+        Ok(())
+    }
+
+    /// Returns the nicknames of all participants.
+    fn participants(&self) -> Vec<String> {
+        match self.conn_state {
+            ConnectionState::ServerListening(ref s) | ConnectionState::ServerConnected(ref s, _) =>
+                s.nicks(),
+            ConnectionState::Client(ref c) => c.nicks(),
+            ConnectionState::None => Vec::new(),
+        }
+    }
+
+    /// Sends a file transfer message to the participant using the nickname
+    /// `to`.
+    fn send_file_msg(&self, to: &str, id: u64, msg: FileMsg) -> Result <(), Error> {
+        match self.conn_state {
+            ConnectionState::Client(ref c) => c.send_file(to, id, msg),
+            ConnectionState::ServerListening(ref s) | ConnectionState::ServerConnected(ref s, _) => {
+                s.send_file(to, id, msg)
+            },
+            ConnectionState::None => Err(Error::not_connected()),
+        }
+    }
+
+    /// Offers to send a file to a participant, defaulting to the only other
+    /// participant.
+    fn offer_file(&mut self, args: &Args) -> Result <(), Error> {
+        let path = PathBuf::from(args.get(0).unwrap());
+        let to = match args.get(1) {
+            Some(nick) => nick.to_owned(),
+            None => {
+                let mut others: Vec<_> = self.participants().into_iter()
+                    .filter(|n| n != &self.nick).collect();
+                match others.len() {
+                    1 => others.remove(0),
+                    0 => return self.output_line(format_args!("Cannot send file: no one else \
+                        is connected.")).map_err(Error::from),
+                    _ => return self.output_line(format_args!("Cannot send file: give the \
+                        nickname of the recipient.")).map_err(Error::from),
+                }
+            },
+        };
+        if to == self.nick {
+            return self.output_line(format_args!("Cannot send a file to yourself."))
+                .map_err(Error::from);
+        }
+        let res = self.transfers.offer(&to, &path)
+            .and_then(|(id, offer)| self.send_file_msg(&to, id, offer));
+        match res {
+            Ok(()) => self.output_notice(format_args!("Offered '{}' to {}. Waiting for an \
+                answer...", path.display(), to))?,
+            Err(err) => self.output_error(format_args!("Cannot send '{}': {}", path.display(),
+                err))?,
+        }
+        Ok(())
+    }
+
+    /// Accepts the oldest file offered, by the participant using the
+    /// nickname given if any.
+    fn accept_file(&mut self, args: &Args) -> Result <(), Error> {
+        let res = self.transfers.accept(args.get(0))
+            .and_then(|(peer, id, reply)| self.send_file_msg(&peer, id, reply));
+        if let Err(err) = res {
+            self.output_error(format_args!("{}", err))?;
+        }
+        Ok(())
+    }
+
+    /// Rejects the oldest file offered, by the participant using the
+    /// nickname given if any.
+    fn reject_file(&mut self, args: &Args) -> Result <(), Error> {
+        let res = self.transfers.reject(args.get(0))
+            .and_then(|(peer, id, reply)| self.send_file_msg(&peer, id, reply));
+        if let Err(err) = res {
+            self.output_error(format_args!("{}", err))?;
+        }
+        Ok(())
+    }
+
+    /// Abandons the file transfers with participants who have left.
+    fn check_transfers(&mut self) -> Result <(), Error> {
+        if self.transfers.is_empty() { return Ok(()); }
+        let nicks = self.participants();
+        let (cancels, statuses) = self.transfers.interrupt(|peer| nicks.iter().any(|n| n == peer),
+            "The participant has left.");
+        // The participant may only have changed their nickname. Otherwise
+        // the cancellation cannot be delivered, which is not an error:
+        for (peer, id, cancel) in cancels {
+            self.send_file_msg(&peer, id, cancel).ok();
+        }
+        for status in statuses {
+            self.output_transfer(status)?;
+        }
+        Ok(())
+    }
+
+    /// Outputs a change in the state of a file transfer.
+    fn output_transfer(&self, status: Status) -> Result <(), Error> {
+        match status {
+            Status::Offered { peer, name, size } => self.output_notice(format_args!("{} offers \
+                to send you '{}' ({}). Type '/accept {}' or '/reject {}'.", peer, name,
+                format_size(size), peer, peer))?,
+            Status::Accepted { peer, name, offset: 0 } => {
+                self.output_notice(format_args!("{} has accepted '{}'. Sending...", peer, name))?
+            },
+            Status::Accepted { peer, name, offset } => self.output_notice(format_args!("{} has \
+                accepted '{}'. Resuming after {}...", peer, name, format_size(offset)))?,
+            Status::Rejected { peer, name } => {
+                self.output_notice(format_args!("{} has rejected '{}'.", peer, name))?
+            },
+            Status::Sent { peer, name } => {
+                self.output_notice(format_args!("'{}' has been sent to {}.", name, peer))?
+            },
+            Status::Received { peer, path } => self.output_notice(format_args!("Received '{}' \
+                from {}. Its SHA-256 digest matches.", path.display(), peer))?,
+            Status::Failed { direction: Direction::Sending, peer, name, reason } => {
+                self.output_error(format_args!("Sending '{}' to {} failed: {}", name, peer,
+                    reason))?
+            },
+            Status::Failed { direction: Direction::Receiving, peer, name, reason } => {
+                self.output_error(format_args!("Receiving '{}' from {} failed: {}", name, peer,
+                    reason))?
             },
         }
-        if close_connection { self.close_connection(CloseOptions::Decrement)?; }
+        Ok(())
+    }
+
+    /// Shows the encryption key fingerprint of a participant or, if no
+    /// nickname is given, our own.
+    fn fingerprint(&mut self, args: &Args) -> Result <(), Error> {
+        let nick = args.get(0).unwrap_or(&self.nick).to_owned();
+        let fingerprint = if nick == self.nick {
+            self.client_opts.identity.as_ref().map(|id| id.fingerprint())
+        } else {
+            match self.conn_state {
+                ConnectionState::Client(ref c) => c.fingerprint(&nick),
+                ConnectionState::ServerListening(ref s) | ConnectionState::ServerConnected(ref s, _) => {
+                    s.fingerprint(&nick)
+                },
+                ConnectionState::None => None,
+            }
+        };
+        match fingerprint {
+            Some(fp) => self.output_line(format_args!("Key fingerprint of {}: {}", nick, fp))?,
+            None if nick == self.nick => self.output_line(format_args!("End-to-end encryption \
+                is disabled. Restart with '--e2e' to enable it."))?,
+            None => self.output_line(format_args!("{}", Error::no_key(nick)))?,
+        }
         Ok(())
     }
 
     /// Handles user input.
-    fn handle_input<'l>(&mut self, line: &'l str) -> Result <(), Error> {
+    fn handle_input(&mut self, line: &str) -> Result <(), Error> {
         match line {
             "" => {},
-            l @ _ => {
-                if l.starts_with("/") {
-                    if l.starts_with("/open") || l.starts_with("/connect") {
-                        self.connect(l)?;
-                    } else if l.starts_with("/close") {
-                        self.close_all()?;
-                    } else if l.starts_with("/exit") {
-                        self.exit = true;
-                    } else if l.starts_with("/help") {
-                        self.help()?;
-                    } else {
-                        self.output_line(format_args!("Unknown command."))?;
-                    }
-                } else {
-                    self.send_message(l)?;
-                }
-            }
+            l if l.starts_with('/') => match self.commands.parse(l) {
+                Ok((handler, args)) => handler(self, &args)?,
+                Err(err) => self.output_error(format_args!("{}", err))?,
+            },
+            l => {
+                let res = self.send_chat(l);
+                self.output_result(res)?
+            },
         }
         self.stdout.flush().map_err(Error::from)
     }
 
+    /// Handles the requests received on the control socket, replying with
+    /// the outcome of each.
+    fn handle_requests(&mut self) {
+        while let Some((req, reply)) = self.control.as_ref().and_then(ControlSocket::try_recv) {
+            let res = match req {
+                Request::Connect { url, password } => {
+                    client::parse_url(&url).and_then(|url| self.connect_url(url, password))
+                },
+                Request::Send { text } => self.send_chat(&text),
+                Request::Msg { to, text } => self.send_direct(&to, &text),
+                Request::Join { room } => self.join(&room),
+                Request::Part { room } => self.part(room.as_deref()),
+                Request::Nick { nick } => self.change_nick(&nick),
+                Request::Close => self.close_all(),
+                Request::Input { line } => self.handle_input(&line),
+            };
+            reply.send(&res);
+        }
+    }
+
     /// Handles commands sent from server or client.
     fn handle_commands(&mut self) -> Result <(), Error> {
         while let Ok(cmd) = self.cmd_rx.try_recv() {
             match cmd {
-                UiCommand::MessageRecvd(m, t) => {
-                    match self.conn_state {
-                        ConnectionState::ServerConnected(_, _) => {
-                            self.output_line(format_args!("{{Client<{}>}}: {}", usize::from(t), m))?;
-                        },
-                        ConnectionState::Client(_) => {
-                            self.output_line(format_args!("{{Server<{}>}}: {}", usize::from(t), m))?;
-                        },
-                        ConnectionState::None | ConnectionState::ServerListening(..) => {
-                            self.output_line(format_args!("{{Unknown}}: {}", m))?;
-                        },
+                Event::MessageRecvd(room, from, m, times) => {
+                    if Some(room.as_str()) == self.room() {
+                        self.output_message(times.time(), None, &from, &m)?;
+                    } else {
+                        self.output_message(times.time(), Some(&room), &from, &m)?;
+                    }
+                },
+                Event::RoomJoined(room) => {
+                    self.rooms.retain(|r| r != &room);
+                    self.output_notice(format_args!("Now talking in {}.", room))?;
+                    self.rooms.push(room);
+                },
+                Event::RoomParted(room) => {
+                    self.rooms.retain(|r| r != &room);
+                    self.output_notice(format_args!("You have left {}.", room))?;
+                },
+                Event::RoomListRecvd(rooms) => {
+                    self.output_room_list(&rooms)?;
+                },
+                Event::DirectRecvd(from, m, times) => {
+                    self.output_styled(self.stamped_line(times.time()).push(Role::Direct, "*")
+                        .push(self.nick_role(&from), from)
+                        .push(Role::Direct, format!("*: {}", m)))?;
+                },
+                Event::NickChanged(nick) => {
+                    if nick != self.nick {
+                        self.output_notice(format_args!("You are now known as {}.", nick))?;
+                        self.nick = nick;
                     }
                 },
-                UiCommand::PongRecvd(elapsed) => {
-                    let s = elapsed.num_seconds();
-                    let ms = elapsed.num_milliseconds() - (s * 1000);
-                    let us = elapsed.num_microseconds().map(|us| us - (s * 1000000)).unwrap_or(ms * 1000);
-                    self.output_line(format_args!("    Round-trip: {}.{:06}s", s, us))?;
+                Event::HistoryRecvd(entries) => {
+                    self.output_history(&entries)?;
+                },
+                Event::NoticeRecvd(text) => {
+                    self.output_notice(format_args!("*** {}", text))?;
+                },
+                Event::PresenceRecvd(room, who, presence) => {
+                    match presence {
+                        Presence::Joined => self.output_notice(format_args!("*** {} has joined {}.",
+                            who, room))?,
+                        Presence::Left => self.output_notice(format_args!("*** {} has left {}.",
+                            who, room))?,
+                    }
+                },
+                Event::ReplyRecvd(ok, text) => {
+                    if ok {
+                        self.output_line(format_args!("{}", text))?;
+                    } else {
+                        self.output_error(format_args!("Error: {}", text))?;
+                    }
+                },
+                Event::UnknownRecvd(desc) => {
+                    self.output_line(format_args!("Ignoring unrecognized message: {}", desc))?;
+                },
+                Event::PongRecvd(from, elapsed) => {
+                    // Replies to background pings are only recorded:
+                    if self.pending_pings.remove(&from) {
+                        self.output_notice(format_args!("Round-trip to {}: {}", from,
+                            format_rtt(elapsed)))?;
+                    }
+                    self.latency.entry(from).or_default().record(elapsed);
+                },
+                Event::FileRecvd(from, id, msg) => {
+                    let (replies, status) = self.transfers.handle(&from, id, msg);
+                    for reply in replies {
+                        self.send_file_msg(&from, id, reply)?;
+                    }
+                    if let Some(status) = status {
+                        self.output_transfer(status)?;
+                    }
                 },
-                UiCommand::ServerOpened(shake) => {
-                    if let Some(peer_addr) = shake.peer_addr {
+                Event::ServerOpened(peer_addr, nick) => {
+                    if let Some(peer_addr) = peer_addr {
                         match mem::replace(&mut self.conn_state, ConnectionState::None) {
                             ConnectionState::ServerListening(s) => {
                                 self.conn_state = ConnectionState::ServerConnected(s, 1);
@@ -495,49 +1267,76 @@ impl ConsoleUi {
                             },
                             _ => panic!("Invalid connection state."),
                         }
-                        self.output_line(format_args!("Server connected to: {}",
-                            peer_addr.to_string()))?;
+                        self.output_notice(format_args!("{} connected from: {}",
+                            nick, peer_addr))?;
                     } else {
-                        self.output_line(format_args!("Server connected.", ))?;
+                        self.output_notice(format_args!("{} connected.", nick))?;
                     }
                 },
-                UiCommand::ClientOpened(shake) => {
-                    if let Some(peer_addr) = shake.peer_addr {
-                        self.output_line(format_args!("Client connected to: {}",
-                            peer_addr.to_string()))?;
+                Event::ClientOpened(peer_addr) => {
+                    self.latency.clear();
+                    self.pending_pings.clear();
+                    if let Some(peer_addr) = peer_addr {
+                        self.output_notice(format_args!("Client connected to: {}",
+                            peer_addr))?;
                     } else {
                         panic!("No peer address found.");
                     }
                 },
-                UiCommand::ClientClosed(_code, reason) => {
-                    self.output_line(format_args!("Server connection closed. {}", reason))?;
-                    self.close_connection(CloseOptions::None)?;
+                Event::ClientClosed(code, reason) => {
+                    // There is no connection to send the cancellations on:
+                    let (_, statuses) = self.transfers.interrupt(|_| false,
+                        "The connection was lost.");
+                    for status in statuses {
+                        self.output_transfer(status)?;
+                    }
+                    // The client reports `ClientDisconnected` once it stops
+                    // trying to reconnect:
+                    if code == AUTH_FAILED {
+                        self.output_error(format_args!("The server rejected the connection: {} \
+                            Type '/connect {{url}} {{password}}' to retry with a password.",
+                            reason))?;
+                    } else {
+                        self.output_notice(format_args!("Server connection closed. {}", reason))?;
+                    }
+                },
+                Event::ClientReconnecting(delay, attempt) => {
+                    self.output_notice(format_args!("Reconnecting in {}s (attempt {})... \
+                        Type '/reconnect' to retry now or '/cancel' to stop.",
+                        delay.as_secs() + (delay.subsec_nanos() >= 500_000_000) as u64, attempt))?;
                 },
-                UiCommand::ServerClosed(_code, reason) => {
-                    self.output_line(format_args!("Client connection closed. {}", reason))?;
+                Event::ClientDisconnected => {
+                    if let ConnectionState::Client(_) = self.conn_state {
+                        self.close_connection(CloseOptions::Shutdown)?;
+                    }
+                },
+                Event::ServerClosed(_code, reason) => {
+                    self.output_notice(format_args!("Client connection closed. {}", reason))?;
                     self.close_connection(CloseOptions::Decrement)?;
                 },
-                UiCommand::ClientError(err) => {
-                    self.output_line(format_args!("The client has encountered an error: {}", err))?;
-                    self.close_connection(CloseOptions::Shutdown)?;
+                Event::ClientError(err) => {
+                    self.output_error(format_args!("The client has encountered an error: {}", err))?;
                 },
-                UiCommand::ServerError(err) => {
-                    match err.kind() {
-                        ErrorKind::Ws(ref err) => match err.kind {
-                            ws::ErrorKind::Io(ref err) => match err.kind() {
-                                io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable => {
-                                    self.server_addr = None;
-                                },
-                                _ => {}
-                            },
-                            _ => {}
-                        },
-                        _ => {},
+                Event::ServerError(err) => {
+                    if let ErrorKind::Ws(ws::Error { kind: ws::ErrorKind::Io(ref err), .. })
+                            = *err.kind() {
+                        if matches!(err.kind(),
+                                io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable) {
+                            self.server_addr = None;
+                        }
+                    }
+                    self.output_error(format_args!("The server has encountered an error: {}", err))?;
+                    // Errors on a single client connection leave the server running:
+                    let listening = match self.conn_state {
+                        ConnectionState::ServerListening(ref s) |
+                        ConnectionState::ServerConnected(ref s, _) => s.is_listening(),
+                        _ => false,
+                    };
+                    if !listening {
+                        self.close_connection(CloseOptions::Shutdown)?;
                     }
-                    self.output_line(format_args!("The server has encountered an error: {}", err))?;
-                    self.close_connection(CloseOptions::Shutdown)?;
                 },
-                UiCommand::ServerShutdown | UiCommand::ClientShutdown => {
+                Event::ServerShutdown => {
                     self.close_connection(CloseOptions::Shutdown)?;
                 },
             }
@@ -545,38 +1344,145 @@ impl ConsoleUi {
         Ok(())
     }
 
+    /// Completes the word before the cursor or, if just completed, replaces
+    /// it with the next candidate.
+    fn complete(&mut self) {
+        if let Some(ref mut completion) = self.completion {
+            self.editor.replace_before_cursor(completion.start(), completion.next());
+            return;
+        }
+
+        let nicks = self.participants();
+        let nicks: Vec<String> = nicks.into_iter().filter(|n| n != &self.nick).collect();
+        let targets = self.input_history.connect_targets();
+        let commands = self.commands.names();
+        let sources = Sources { commands: &commands, nicks: &nicks, targets: &targets };
+        if let Some(completion) = Completion::new(self.editor.before_cursor(), &sources) {
+            self.editor.replace_before_cursor(completion.start(), completion.current());
+            self.completion = Some(completion);
+        }
+    }
+
+    /// Handles a key pressed at the prompt.
+    fn handle_key(&mut self, key: Key) -> Result <(), Error> {
+        if self.search.is_some() {
+            return self.handle_search_key(key);
+        }
+        if key != Key::Char('\t') {
+            self.completion = None;
+        }
+        match key {
+            Key::Ctrl('q') | Key::Ctrl('c') => self.exit = true,
+            Key::Char('\n') => {
+                let line = self.editor.take();
+                if let Err(err) = self.input_history.push(&line) {
+                    self.output_error(format_args!("Unable to save input history: {}", err))?;
+                }
+                self.handle_input(&line)?;
+            },
+            Key::Char('\t') => self.complete(),
+            Key::Char(c) => self.editor.insert(c),
+            Key::Backspace => self.editor.backspace(),
+            Key::Delete => self.editor.delete(),
+            Key::Left => self.editor.left(),
+            Key::Right => self.editor.right(),
+            Key::Home | Key::End if self.scrollback.borrow().is_scrolled() => self.scroll(key),
+            Key::Home | Key::Ctrl('a') => self.editor.home(),
+            Key::End | Key::Ctrl('e') => self.editor.end(),
+            Key::Ctrl('w') => self.editor.delete_word(),
+            Key::Ctrl('u') => self.editor.kill_to_start(),
+            Key::Ctrl('k') => self.editor.kill_to_end(),
+            Key::Up => if let Some(line) = self.input_history.prev(self.editor.as_str()) {
+                self.editor.set(line);
+            },
+            Key::Down => if let Some(line) = self.input_history.next() {
+                self.editor.set(line);
+            },
+            Key::Ctrl('r') => {
+                self.search = Some(HistorySearch {
+                    query: String::new(),
+                    found: None,
+                    failed: false,
+                    original: self.editor.as_str().to_owned(),
+                });
+            },
+            Key::PageUp | Key::PageDown => self.scroll(key),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Handles a key pressed while searching the input history. Keys other
+    /// than those editing the query end the search, keeping the line found.
+    fn handle_search_key(&mut self, key: Key) -> Result <(), Error> {
+        {
+            let search = self.search.as_mut().unwrap();
+            // The line found is searched again first in case it still matches:
+            let before = match key {
+                Key::Ctrl('r') => Some(search.found),
+                Key::Char(c) if c != '\n' => {
+                    search.query.push(c);
+                    Some(search.found.map(|idx| idx + 1))
+                },
+                Key::Backspace => {
+                    search.query.pop();
+                    Some(None)
+                },
+                Key::Esc | Key::Ctrl('g') => {
+                    self.editor.set(mem::take(&mut search.original));
+                    None
+                },
+                _ => None,
+            };
+            if let Some(before) = before {
+                match self.input_history.search(&search.query, before) {
+                    Some((idx, line)) => {
+                        search.found = Some(idx);
+                        search.failed = false;
+                        self.editor.set(line);
+                    },
+                    None => search.failed = true,
+                }
+                return Ok(());
+            }
+        }
+        self.search = None;
+        match key {
+            Key::Esc | Key::Ctrl('g') => Ok(()),
+            _ => self.handle_key(key),
+        }
+    }
+
     /// Loops, handling events until exit.
     fn run(&mut self) -> Result <(), Error> {
-        // Hide cursor and move to lower left of terminal:
+        // Clear the terminal and enable bracketed paste, so that pasted line
+        // breaks are not taken as Enter:
         write!(self.stdout.lock().into_raw_mode()?, "{}{}",
-            termion::cursor::Hide,
-            termion::cursor::Goto(self.term_size.0, 0))?;
-        self.output_prompt("")?;
+            termion::clear::All,
+            BRACKETED_PASTE_ON)?;
+        self.output_pane()?;
+        self.output_prompt()?;
 
-        let mut line_buf = String::new();
-        let mut stdin = termion::async_stdin().keys();
+        let mut stdin = termion::async_stdin().events();
+        let mut pasting = false;
 
         loop {
             if let Err(err) = self.handle_commands() {
-                self.output_line(format_args!("Error: {}", err))?;
+                self.output_error(format_args!("Error: {}", err))?;
             }
+            self.handle_requests();
+            self.check_transfers()?;
 
             match stdin.next() {
-                Some(Ok(Key::Ctrl(c))) => {
-                    if c == 'q' || c == 'c' {
-                        self.exit = true;
-                    }
+                Some(Ok(TermEvent::Unsupported(ref seq))) if seq.as_slice() == PASTE_START => {
+                    pasting = true;
                 },
-                Some(Ok(Key::Char('\n'))) => {
-                    self.handle_input(&line_buf)?;
-                    line_buf.clear();
+                Some(Ok(TermEvent::Unsupported(ref seq))) if seq.as_slice() == PASTE_END => {
+                    pasting = false;
                 },
-                Some(Ok(Key::Char(c))) => {
-                    line_buf.push(c);
-                },
-                Some(Ok(Key::Backspace)) => {
-                    line_buf.pop();
-                }
+                // Pasted text is inserted as is:
+                Some(Ok(TermEvent::Key(Key::Char(c)))) if pasting => self.editor.insert(c),
+                Some(Ok(TermEvent::Key(key))) => self.handle_key(key)?,
                 Some(_) => {},
                 None => {},
             }
@@ -584,16 +1490,20 @@ impl ConsoleUi {
             if self.exit {
                 break;
             } else {
-                self.output_prompt(&line_buf)?;
+                if self.resized.swap(false, Ordering::SeqCst) {
+                    self.resize()?;
+                }
+                self.output_pane()?;
+                self.output_prompt()?;
             }
 
             thread::sleep(Duration::from_millis(10));
         }
 
         // Reset cursor before exiting:
-        write!(self.stdout, "{}{}\n",
-                termion::cursor::Goto(0, self.term_size.1),
-                termion::cursor::Show)
+        writeln!(self.stdout, "{}{}",
+                BRACKETED_PASTE_OFF,
+                termion::cursor::Goto(1, self.term_size.1))
             .map_err(Error::from)
     }
 }
@@ -621,14 +1531,158 @@ fn main() {
                 .long("client")
                 .value_name("CLIENT")
                 .help("Set the remote address to connect to upon startup."))
+        .arg(Arg::with_name("NICK")
+                .required(false)
+                .short("n")
+                .long("nick")
+                .value_name("NICK")
+                .help("Set your nickname. Defaults to the current user name."))
+        .arg(Arg::with_name("HISTORY_DIR")
+                .required(false)
+                .long("history-dir")
+                .value_name("DIR")
+                .help("Set the directory in which the server saves message history. \
+                    History is kept in memory only if not set."))
+        .arg(Arg::with_name("HISTORY_REPLAY")
+                .required(false)
+                .long("history-replay")
+                .value_name("COUNT")
+                .help("Set the number of recent messages sent to newly connected clients. \
+                    Defaults to 20."))
+        .arg(Arg::with_name("RECONNECT")
+                .required(false)
+                .long("reconnect")
+                .help("Automatically reconnect to the server when the connection is lost."))
+        .arg(Arg::with_name("PING_INTERVAL")
+                .required(false)
+                .long("ping-interval")
+                .value_name("SECONDS")
+                .help("Ping the server or each client every SECONDS in the background, \
+                    recording round-trip times for '/stats' and detecting lost connections. \
+                    Defaults to 30. Disabled if 0."))
+        .arg(Arg::with_name("MAX_MISSED_PINGS")
+                .required(false)
+                .long("max-missed-pings")
+                .value_name("COUNT")
+                .help("Close a connection once COUNT consecutive background pings have gone \
+                    unanswered. Defaults to 3. Never closes if 0."))
+        .arg(Arg::with_name("CERT")
+                .required(false)
+                .long("cert")
+                .value_name("FILE")
+                .requires("KEY")
+                .help("Set the PEM encoded certificate (chain) file used to accept only \
+                    encrypted ('wss') connections. Requires '--key'."))
+        .arg(Arg::with_name("KEY")
+                .required(false)
+                .long("key")
+                .value_name("FILE")
+                .requires("CERT")
+                .help("Set the PEM encoded private key file of the server certificate."))
+        .arg(Arg::with_name("E2E")
+                .required(false)
+                .long("e2e")
+                .help("Encrypt chat messages end-to-end using the keypair saved in the \
+                    user configuration directory, generating it if necessary. Unencrypted \
+                    messages are ignored."))
+        .arg(Arg::with_name("IDENTITY")
+                .required(false)
+                .long("identity")
+                .value_name("FILE")
+                .help("Encrypt chat messages end-to-end using the keypair saved in FILE \
+                    instead, generating it if necessary. Implies '--e2e'."))
+        .arg(Arg::with_name("PASSWORD")
+                .required(false)
+                .long("password")
+                .value_name("PASSWORD")
+                .conflicts_with("PASSWORD_FILE")
+                .help("Require clients to give this password to connect to the server."))
+        .arg(Arg::with_name("PASSWORD_FILE")
+                .required(false)
+                .long("password-file")
+                .value_name("FILE")
+                .help("Require clients to give the password contained in the first line of \
+                    FILE to connect to the server."))
+        .arg(Arg::with_name("CLIENT_PASSWORD")
+                .required(false)
+                .long("client-password")
+                .value_name("PASSWORD")
+                .help("Set the password given when connecting to a server."))
+        .arg(Arg::with_name("SCROLLBACK")
+                .required(false)
+                .long("scrollback")
+                .value_name("LINES")
+                .help("Set the number of output lines kept for scrolling back with PageUp. \
+                    Defaults to 1000."))
+        .arg(Arg::with_name("THEME")
+                .required(false)
+                .long("theme")
+                .value_name("THEME")
+                .help("Set the output colors: 'dark' (the default), 'light', 'none' or the path \
+                    of a theme file. Colors are disabled by default if 'NO_COLOR' is set."))
+        .arg(Arg::with_name("TIME_FORMAT")
+                .required(false)
+                .long("time-format")
+                .value_name("FORMAT")
+                .help("Set the strftime format of the times shown before messages. Defaults \
+                    to '%H:%M'."))
+        .arg(Arg::with_name("CONTROL_SOCKET")
+                .required(false)
+                .long("control-socket")
+                .value_name("PATH")
+                .conflicts_with("HEADLESS")
+                .help("Accept JSON requests (one per line) from other programs on the Unix \
+                    socket at PATH, writing every event back to them as JSON."))
+        .arg(Arg::with_name("DOWNLOAD_DIR")
+                .required(false)
+                .long("download-dir")
+                .value_name("DIR")
+                .help("Set the directory in which received files are saved. Defaults to the \
+                    current directory."))
+        .arg(Arg::with_name("HEADLESS")
+                .required(false)
+                .long("headless")
+                .conflicts_with("CLIENT")
+                .help("Run only a server, without the terminal user interface, logging \
+                    connections and messages until terminated."))
+        .arg(Arg::with_name("PIPE")
+                .required(false)
+                .long("pipe")
+                .requires("CLIENT")
+                .conflicts_with_all(&["HEADLESS", "CONTROL_SOCKET"])
+                .help("Run without the terminal user interface, sending each line read from \
+                    stdin as a chat message and writing each message received to stdout. \
+                    Exits once stdin has ended, or with a non-zero status if the connection \
+                    fails or is closed first."))
+        .arg(Arg::with_name("ROOM")
+                .required(false)
+                .long("room")
+                .value_name("ROOM")
+                .requires("PIPE")
+                .help("Join ROOM and send messages to it instead of the default room."))
+        .arg(Arg::with_name("JSON")
+                .required(false)
+                .long("json")
+                .requires("PIPE")
+                .help("Write the messages received as JSON, one per line."))
+        .arg(Arg::with_name("LOG_FILE")
+                .required(false)
+                .long("log-file")
+                .value_name("FILE")
+                .requires("HEADLESS")
+                .help("Append the log of a headless server to FILE instead of printing it."))
+        .arg(Arg::with_name("INSECURE")
+                .required(false)
+                .long("insecure")
+                .help("Do not verify server certificates when connecting to 'wss' urls \
+                    (e.g. for self-signed certificates)."))
         .get_matches();
 
     // Address to listen on upon startup:
     let server_addr = matches.value_of("SERVER").unwrap_or("localhost:3030").to_owned();
 
     // Address to connect to upon startup:
-    let client_addr = match matches.value_of("CLIENT").as_ref()
-            .map(|c| Url::parse(&format!("ws:{}", c))) {
+    let client_addr = match matches.value_of("CLIENT").map(client::parse_url) {
         Some(Ok(ca)) => Some(ca),
         Some(Err(err)) => {
             println!("Unable to parse client address: {}", err);
@@ -637,8 +1691,202 @@ fn main() {
         None => None,
     };
 
+    // Nickname to use:
+    let nick = match matches.value_of("NICK").map(str::to_owned)
+            .or_else(|| env::var("USER").ok())
+            .unwrap_or_else(|| "anonymous".to_owned()) {
+        ref n if !protocol::is_valid_nick(n) => {
+            println!("Invalid nickname: '{}'", n);
+            return;
+        },
+        n => n,
+    };
+
+    // Message history settings for the server:
+    let mut history_opts = HistoryOptions {
+        dir: matches.value_of("HISTORY_DIR").map(PathBuf::from),
+        ..HistoryOptions::default()
+    };
+    if let Some(replay) = matches.value_of("HISTORY_REPLAY") {
+        match replay.parse() {
+            Ok(r) => history_opts.replay_len = r,
+            Err(_) => {
+                println!("Invalid history replay count: '{}'", replay);
+                return;
+            },
+        }
+    }
+
+    // End-to-end encryption keypair:
+    let identity_path = matches.value_of("IDENTITY").map(PathBuf::from).or_else(|| {
+        if matches.is_present("E2E") { config_dir().map(|d| d.join("identity.pem")) } else { None }
+    });
+    let identity = match identity_path {
+        Some(path) => match Identity::load_or_generate(&path) {
+            Ok(id) => Some(Arc::new(id)),
+            Err(err) => {
+                println!("Unable to load identity '{}': {}", path.display(), err);
+                return;
+            },
+        },
+        None if matches.is_present("E2E") => {
+            println!("Unable to locate the user configuration directory. Use '--identity'.");
+            return;
+        },
+        None => None,
+    };
+
+    // Server certificate:
+    let tls = match (matches.value_of("CERT"), matches.value_of("KEY")) {
+        (Some(cert), Some(key)) => match tls::acceptor(Path::new(cert), Path::new(key)) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                println!("Unable to load certificate '{}' or key '{}': {}", cert, key, err);
+                return;
+            },
+        },
+        _ => None,
+    };
+
+    // Background pings, which detect lost connections:
+    let ping_interval = match matches.value_of("PING_INTERVAL").map(str::parse) {
+        Some(Ok(0)) => None,
+        Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+        Some(Err(_)) => {
+            println!("Invalid ping interval: '{}'", matches.value_of("PING_INTERVAL").unwrap());
+            return;
+        },
+        None => Some(DEFAULT_PING_INTERVAL),
+    };
+    let max_missed_pings = match matches.value_of("MAX_MISSED_PINGS").map(str::parse) {
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            println!("Invalid missed ping count: '{}'",
+                matches.value_of("MAX_MISSED_PINGS").unwrap());
+            return;
+        },
+        None => DEFAULT_MAX_MISSED_PINGS,
+    };
+
+    // Client connection settings:
+    let client_opts = ClientOptions {
+        reconnect: ReconnectOptions {
+            enabled: matches.is_present("RECONNECT"),
+            ..ReconnectOptions::default()
+        },
+        insecure: matches.is_present("INSECURE"),
+        identity: identity.clone(),
+        password: matches.value_of("CLIENT_PASSWORD").map(str::to_owned),
+        ping_interval,
+        max_missed_pings,
+    };
+
+    // Run a client connected to stdin and stdout:
+    if matches.is_present("PIPE") {
+        let room = matches.value_of("ROOM");
+        if let Some(r) = room.filter(|r| !protocol::is_valid_room(r)) {
+            eprintln!("{}", Error::invalid_room(r));
+            process::exit(pipe::EXIT_ERROR);
+        }
+        let res = pipe::run(client_addr.unwrap(), &nick, client_opts, room,
+            matches.is_present("JSON"));
+        match res {
+            Ok(status) => process::exit(status),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                process::exit(pipe::EXIT_ERROR);
+            },
+        }
+    }
+
+    // Server password:
+    let password = match matches.value_of("PASSWORD_FILE") {
+        Some(file) => match fs::read_to_string(file) {
+            Ok(contents) => Some(contents.lines().next().unwrap_or("").to_owned()),
+            Err(err) => {
+                println!("Unable to read password file '{}': {}", file, err);
+                return;
+            },
+        },
+        None => matches.value_of("PASSWORD").map(str::to_owned),
+    };
+    let server_opts = ServerOptions { tls, identity, password, observer: false, ping_interval,
+        max_missed_pings };
+
+    // Run a server without the user interface:
+    if matches.is_present("HEADLESS") {
+        let res = resolve_server_addr(&server_addr).and_then(|addr| {
+            headless::run(addr, &nick, &history_opts, server_opts,
+                matches.value_of("LOG_FILE").map(Path::new))
+        });
+        if let Err(err) = res {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // Number of output lines kept by the user interface:
+    let scrollback_len = match matches.value_of("SCROLLBACK").map(str::parse) {
+        Some(Ok(n)) if n > 0 => n,
+        Some(_) => {
+            println!("Invalid scrollback length: '{}'", matches.value_of("SCROLLBACK").unwrap());
+            return;
+        },
+        None => DEFAULT_SCROLLBACK_LEN,
+    };
+
+    // Output colors. An explicitly chosen theme overrides 'NO_COLOR':
+    let theme_name = matches.value_of("THEME").map(str::to_owned).or_else(|| {
+        config_dir().map(|d| d.join(THEME_FILE_NAME)).filter(|path| path.exists())
+            .map(|path| path.to_string_lossy().into_owned())
+    });
+    let theme = match theme_name {
+        Some(name) => match Theme::load(&name) {
+            Ok(theme) => theme,
+            Err(err) => {
+                println!("Unable to load theme '{}': {}", name, err);
+                return;
+            },
+        },
+        None if env::var_os("NO_COLOR").map(|v| !v.is_empty()).unwrap_or(false) => Theme::none(),
+        None => Theme::dark(),
+    };
+
+    let time_format = matches.value_of("TIME_FORMAT").unwrap_or(DEFAULT_TIME_FORMAT).to_owned();
+    if !is_valid_time_format(&time_format) {
+        println!("Invalid time format: '{}'", time_format);
+        return;
+    }
+
+    // Control socket:
+    let control = match matches.value_of("CONTROL_SOCKET") {
+        Some(path) => match ControlSocket::bind(Path::new(path)) {
+            Ok(control) => Some(control),
+            Err(err) => {
+                println!("Unable to open control socket '{}': {}", path, err);
+                return;
+            },
+        },
+        None => None,
+    };
+
+    // Where received files are saved:
+    let download_dir = match matches.value_of("DOWNLOAD_DIR") {
+        Some(dir) if Path::new(dir).is_dir() => PathBuf::from(dir),
+        Some(dir) => {
+            println!("Invalid download directory: '{}'", dir);
+            return;
+        },
+        None => env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+    };
+
     // The user interface:
-    let mut ui = match ConsoleUi::new(&server_addr, client_addr) {
+    let input_history_path = config_dir().map(|d| d.join(INPUT_HISTORY_FILE_NAME));
+    let ui_opts = UiOptions { scrollback_len, input_history_path, theme, time_format, control,
+        download_dir };
+    let mut ui = match ConsoleUi::new(&server_addr, client_addr, nick, history_opts,
+            server_opts, client_opts, ui_opts) {
         Ok(c) => c,
         Err(err) => {
             match err.kind() {
@@ -0,0 +1,291 @@
+//! A non-interactive client which sends each line read from stdin as a chat
+//! message and writes each message received to stdout.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver as MpscReceiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+use headsup::{ChannelSink, Event, Error, ErrorKind};
+use protocol::{FileMsg, DEFAULT_ROOM, AUTH_FAILED};
+use client::{Client, ClientOptions};
+use control;
+
+
+/// The exit status once stdin has ended and the connection has been closed.
+pub const EXIT_OK: i32 = 0;
+/// The exit status if the connection could not be made or failed.
+pub const EXIT_ERROR: i32 = 1;
+/// The exit status if the server closed or lost the connection before stdin
+/// ended.
+pub const EXIT_DISCONNECTED: i32 = 2;
+/// The exit status if the server rejected our password.
+pub const EXIT_AUTH_FAILED: i32 = 3;
+
+
+/// A client connected to stdin and stdout.
+struct Pipe {
+    client: Client,
+    cmd_rx: MpscReceiver<Event>,
+    // Lines read from stdin, `None` once it has ended:
+    lines: MpscReceiver<Option<String>>,
+    // The room messages are sent to and the rooms joined so far:
+    room: String,
+    rooms: Vec<String>,
+    // Lines waiting for the room to be joined:
+    pending: VecDeque<String>,
+    json: bool,
+    opened: bool,
+    eof: bool,
+    closing: bool,
+    auth_failed: bool,
+}
+
+impl Pipe {
+    /// Returns a pipe sending `lines` through `client` to `room`, which
+    /// reports its events to `cmd_rx`.
+    fn new(client: Client, cmd_rx: MpscReceiver<Event>, lines: MpscReceiver<Option<String>>,
+            room: &str, json: bool) -> Pipe {
+        Pipe {
+            client,
+            cmd_rx,
+            lines,
+            room: room.to_owned(),
+            rooms: Vec::new(),
+            pending: VecDeque::new(),
+            json,
+            opened: false,
+            eof: false,
+            closing: false,
+            auth_failed: false,
+        }
+    }
+
+    /// Writes a received message to stdout.
+    fn output(&self, event: &Event) -> Result<(), Error> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        if self.json {
+            writeln!(out, "{}", control::event_json(event))?;
+        } else {
+            writeln!(out, "{}", event)?;
+        }
+        out.flush().map_err(Error::from)
+    }
+
+    /// Handles an event from the client. Returns the exit status once the
+    /// client has stopped.
+    fn handle_command(&mut self, cmd: Event) -> Result<Option<i32>, Error> {
+        match cmd {
+            Event::ClientOpened(_) => self.opened = true,
+            Event::RoomJoined(room) => {
+                // The server joins us to the default room automatically:
+                if room == DEFAULT_ROOM && self.room != DEFAULT_ROOM
+                        && !self.rooms.contains(&self.room) {
+                    self.client.join(&self.room)?;
+                }
+                self.rooms.push(room);
+            },
+            Event::RoomParted(room) => self.rooms.retain(|r| r != &room),
+            Event::MessageRecvd(..) | Event::DirectRecvd(..) => self.output(&cmd)?,
+            Event::NoticeRecvd(_) | Event::ReplyRecvd(false, _) => eprintln!("{}", cmd),
+            // Files cannot be received through a pipe:
+            Event::FileRecvd(from, id, FileMsg::Offer { name, .. }) => {
+                eprintln!("Rejected '{}' offered by {}.", name, from);
+                self.client.send_file(&from, id, FileMsg::Reject)?;
+            },
+            Event::ClientClosed(code, reason) => {
+                // Lines wait for the rooms to be joined again upon reconnecting:
+                self.rooms.clear();
+                if code == AUTH_FAILED {
+                    self.auth_failed = true;
+                    eprintln!("The server rejected the connection: {}", reason);
+                } else if !self.closing {
+                    eprintln!("Server connection closed. {}", reason);
+                }
+            },
+            Event::ClientReconnecting(..) | Event::ClientError(_) => eprintln!("{}", cmd),
+            Event::ClientDisconnected => {
+                let status = if self.closing {
+                    EXIT_OK
+                } else if self.auth_failed {
+                    EXIT_AUTH_FAILED
+                } else if self.opened {
+                    EXIT_DISCONNECTED
+                } else {
+                    EXIT_ERROR
+                };
+                return Ok(Some(status));
+            },
+            _ => {},
+        }
+        Ok(None)
+    }
+
+    /// Sends the lines read so far once the room has been joined, closing
+    /// the connection once stdin has ended.
+    ///
+    /// Lines which cannot be sent because the connection has just been lost
+    /// stay queued until the room has been joined again after reconnecting.
+    /// The client reports when it gives up.
+    fn send_pending(&mut self) -> Result<(), Error> {
+        if !self.rooms.contains(&self.room) { return Ok(()); }
+        while let Some(line) = self.pending.pop_front() {
+            if let Err(err) = self.client.send_chat(&self.room, &line) {
+                match *err.kind() {
+                    ErrorKind::NotConnected | ErrorKind::Ws(_) => {
+                        self.pending.push_front(line);
+                        self.rooms.clear();
+                        return Ok(());
+                    },
+                    _ => return Err(err),
+                }
+            }
+        }
+        if self.eof && !self.closing {
+            self.closing = true;
+            self.client.close()?;
+        }
+        Ok(())
+    }
+
+    /// Runs until the client stops, returning the exit status.
+    fn run(&mut self) -> Result<i32, Error> {
+        loop {
+            match self.cmd_rx.recv_timeout(Duration::from_millis(20)) {
+                Ok(cmd) => if let Some(status) = self.handle_command(cmd)? {
+                    return Ok(status);
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => return Ok(EXIT_ERROR),
+            }
+
+            loop {
+                match self.lines.try_recv() {
+                    Ok(Some(line)) => if !line.trim().is_empty() { self.pending.push_back(line) },
+                    Ok(None) | Err(TryRecvError::Disconnected) => { self.eof = true; break; },
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+            self.send_pending()?;
+        }
+    }
+}
+
+
+/// Connects to the server at `url` and sends each line read from stdin to
+/// `room` (or the default room) until stdin ends, writing the messages
+/// received to stdout, as JSON if `json` is set. Returns the exit status.
+pub fn run(url: Url, nick: &str, opts: ClientOptions, room: Option<&str>, json: bool)
+        -> Result<i32, Error> {
+    let (sink, cmd_rx) = ChannelSink::channel();
+    let client = Client::new(url, nick, opts, Arc::new(sink))?;
+
+    let (tx, lines) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(l) => if tx.send(Some(l)).is_err() { return; },
+                Err(_) => break,
+            }
+        }
+        tx.send(None).ok();
+    });
+
+    Pipe::new(client, cmd_rx, lines, room.unwrap_or(DEFAULT_ROOM), json).run()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::mpsc::Sender;
+    use headsup::{Server, ServerOptions, ReconnectOptions};
+    use client;
+    use headsup::history::{History, HistoryOptions};
+
+    /// Returns a local address which nothing listens on.
+    fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    /// Starts a server at `addr`.
+    fn server(addr: SocketAddr) -> Server {
+        let history = History::open(&HistoryOptions::default()).unwrap();
+        let (sink, _) = ChannelSink::channel();
+        Server::new(addr, "host", history, ServerOptions::default(), Arc::new(sink)).unwrap()
+    }
+
+    /// Returns a pipe to the server at `addr` sending to `room`, and the
+    /// sender of its input lines.
+    fn pipe(addr: SocketAddr, room: &str, opts: ClientOptions) -> (Pipe, Sender<Option<String>>) {
+        let (sink, cmd_rx) = ChannelSink::channel();
+        let url = client::parse_url(&addr.to_string()).unwrap();
+        let client = Client::new(url, "alice", opts, Arc::new(sink)).unwrap();
+        let (tx, lines) = mpsc::channel();
+        (Pipe::new(client, cmd_rx, lines, room, false), tx)
+    }
+
+    /// Returns the texts of the messages recorded in `room`.
+    fn texts(server: &Server, room: &str) -> Vec<String> {
+        server.history(room, 10).into_iter().map(|e| e.text).collect()
+    }
+
+    #[test]
+    fn sends_lines_to_the_room_until_stdin_ends() {
+        let addr = free_addr();
+        let server = server(addr);
+        let (mut pipe, tx) = pipe(addr, "#ops", ClientOptions::default());
+        for line in &["one", " ", "two"] {
+            tx.send(Some(line.to_string())).unwrap();
+        }
+        tx.send(None).unwrap();
+
+        assert_eq!(pipe.run().unwrap(), EXIT_OK);
+        assert_eq!(texts(&server, "#ops"), ["one", "two"]);
+    }
+
+    #[test]
+    fn keeps_lines_until_reconnected() {
+        let addr = free_addr();
+        let reconnect = ReconnectOptions { enabled: true, initial_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(20), max_attempts: 0 };
+        let (mut pipe, tx) = pipe(addr, DEFAULT_ROOM,
+            ClientOptions { reconnect, ..ClientOptions::default() });
+        tx.send(Some("one".to_owned())).unwrap();
+        tx.send(None).unwrap();
+
+        // The first attempts fail:
+        let th = thread::spawn(move || pipe.run().unwrap());
+        thread::sleep(Duration::from_millis(100));
+        let server = server(addr);
+        assert_eq!(th.join().unwrap(), EXIT_OK);
+        assert_eq!(texts(&server, DEFAULT_ROOM), ["one"]);
+    }
+
+    #[test]
+    fn keeps_lines_sent_as_the_connection_is_lost() {
+        let reconnect = ReconnectOptions { enabled: true, ..ReconnectOptions::default() };
+        let (mut pipe, _tx) = pipe(free_addr(), DEFAULT_ROOM,
+            ClientOptions { reconnect, ..ClientOptions::default() });
+        // As if the room had been joined before the connection was lost:
+        pipe.rooms.push(DEFAULT_ROOM.to_owned());
+        pipe.pending.push_back("one".to_owned());
+
+        pipe.send_pending().unwrap();
+        assert_eq!(pipe.pending, ["one"]);
+        assert!(pipe.rooms.is_empty());
+        pipe.client.cancel();
+    }
+
+    #[test]
+    fn fails_once_the_client_gives_up() {
+        let (mut pipe, tx) = pipe(free_addr(), DEFAULT_ROOM, ClientOptions::default());
+        tx.send(Some("one".to_owned())).unwrap();
+        assert_eq!(pipe.run().unwrap(), EXIT_ERROR);
+    }
+}
@@ -0,0 +1,434 @@
+//! The wire protocol shared by the chat client and server.
+//!
+//! Every websocket frame exchanged between peers is a single binary message
+//! containing a bincode encoded `Envelope` prefixed by the protocol version.
+
+use std::fmt;
+use bincode;
+use ws::{Message, CloseCode};
+use chrono::{DateTime, Utc, Duration, serde::{ts_nanoseconds, ts_nanoseconds_option}};
+use ::{Error, ErrorKind};
+
+
+/// The current protocol version.
+///
+/// Bump this whenever `Envelope` changes. Because variants are appended,
+/// frames from newer peers still decode as long as they use a kind known to
+/// this peer. Changing the layout of an existing variant also requires
+/// raising `MIN_PROTOCOL_VERSION`.
+pub const PROTOCOL_VERSION: u16 = 12;
+
+/// The oldest protocol version whose frames this peer can decode.
+///
+/// Raise this to the current version whenever the layout or meaning of an
+/// existing variant changes, so that frames of older peers are rejected
+/// rather than misread. Version 4 added rooms to `Chat`, `Presence` and
+/// `HistoryRequest`. Version 10 authenticates the context of `Sealed`
+/// messages, which older peers cannot open. Version 12 sends the kind and
+/// offset of sealed file messages along with them.
+pub const MIN_PROTOCOL_VERSION: u16 = 12;
+
+/// The number of `Envelope` variants. Frames of other kinds are sent by
+/// newer peers.
+const ENVELOPE_KINDS: u32 = 20;
+
+/// The maximum length of a nickname or room name, in characters.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// The room every client joins upon connecting.
+pub const DEFAULT_ROOM: &str = "#lobby";
+
+/// The handshake request header containing the server password.
+pub const PASSWORD_HEADER: &str = "X-HeadsUp-Password";
+
+/// The close code sent by a server to reject a client which has given a
+/// missing or wrong password.
+pub const AUTH_FAILED: CloseCode = CloseCode::Other(4001);
+
+
+/// Returns true if `name` is non-empty, not too long and contains only
+/// printable, non-whitespace characters.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().count() <= MAX_NAME_LEN &&
+        name.chars().all(|c| !c.is_whitespace() && !c.is_control())
+}
+
+/// Returns true if `nick` is a valid nickname.
+pub fn is_valid_nick(nick: &str) -> bool {
+    is_valid_name(nick) && !nick.starts_with('#')
+}
+
+/// Returns true if `room` is a valid room name (e.g. '#ops').
+pub fn is_valid_room(room: &str) -> bool {
+    is_valid_name(room) && room.starts_with('#') && room.len() > 1
+}
+
+
+/// Returns true if `err` reports a frame of a protocol version older than
+/// `MIN_PROTOCOL_VERSION`.
+pub fn is_incompatible(err: &Error) -> bool {
+    matches!(*err.kind(), ErrorKind::IncompatibleVersion(_))
+}
+
+
+/// Ping timestamp.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Pingstamp(#[serde(with = "ts_nanoseconds")] DateTime<Utc>);
+
+impl Pingstamp {
+    pub fn now() -> Pingstamp {
+        Pingstamp(Utc::now())
+    }
+
+    /// Returns the time elapsed since this stamp was created.
+    pub fn elapsed(&self) -> Duration {
+        Utc::now().signed_duration_since(self.0)
+    }
+}
+
+
+/// The times at which a message was sent and received by the server.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Timestamps {
+    /// The time the sender sent the message, by the sender's clock.
+    #[serde(with = "ts_nanoseconds")]
+    pub sent: DateTime<Utc>,
+    /// The time the server received the message, filled in by the server.
+    #[serde(with = "ts_nanoseconds_option")]
+    pub received: Option<DateTime<Utc>>,
+}
+
+impl Timestamps {
+    /// Returns stamps for a message sent now.
+    pub fn now() -> Timestamps {
+        Timestamps { sent: Utc::now(), received: None }
+    }
+
+    /// Returns these stamps with the receive time set to now.
+    pub fn received_now(self) -> Timestamps {
+        Timestamps { received: Some(Utc::now()), ..self }
+    }
+
+    /// Returns the time the server received the message or, if unknown,
+    /// the time it was sent.
+    pub fn time(&self) -> DateTime<Utc> {
+        self.received.unwrap_or(self.sent)
+    }
+}
+
+
+/// A chat message as recorded by the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// The time the server received the message.
+    #[serde(with = "ts_nanoseconds")]
+    pub time: DateTime<Utc>,
+    pub room: String,
+    pub from: String,
+    pub text: String,
+}
+
+
+/// A room and its number of members, as listed by the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: u32,
+}
+
+
+/// The public encryption key of a participant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerKey {
+    pub nick: String,
+    pub key: Vec<u8>,
+}
+
+
+/// A message encrypted for a single recipient.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedPart {
+    pub to: String,
+    pub data: Vec<u8>,
+}
+
+
+/// A presence change of a chat participant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Presence {
+    Joined,
+    Left,
+}
+
+
+/// A step of a file transfer between two participants.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileMsg {
+    /// Offers a file: its name, size in bytes and SHA-256 digest.
+    Offer { name: String, size: u64, sha256: Vec<u8> },
+    /// Accepts an offer, asking for the file from `offset` on (the length of
+    /// a partial earlier download, to resume it).
+    Accept { offset: u64 },
+    /// Declines an offer.
+    Reject,
+    /// A part of the file, starting at `offset`.
+    Chunk { offset: u64, data: Vec<u8> },
+    /// Acknowledges receipt of the file up to `offset`.
+    Ack { offset: u64 },
+    /// Abandons the transfer, giving the reason.
+    Cancel(String),
+    /// Another message, encrypted end-to-end for the recipient, along with
+    /// its kind and offset (see `header`), which are authenticated with it.
+    /// Sent instead of any other message by senders with an identity.
+    Sealed { kind: u8, offset: u64, data: Vec<u8> },
+}
+
+impl FileMsg {
+    /// Returns the kind of the message, numbered in the order of the
+    /// variants, and the offset of a chunk or acknowledgement (zero for
+    /// other messages).
+    pub fn header(&self) -> (u8, u64) {
+        match *self {
+            FileMsg::Offer { .. } => (0, 0),
+            FileMsg::Accept { offset } => (1, offset),
+            FileMsg::Reject => (2, 0),
+            FileMsg::Chunk { offset, .. } => (3, offset),
+            FileMsg::Ack { offset } => (4, offset),
+            FileMsg::Cancel(_) => (5, 0),
+            FileMsg::Sealed { kind, offset, .. } => (kind, offset),
+        }
+    }
+}
+
+impl fmt::Display for FileMsg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FileMsg::Offer { ref name, size, .. } => write!(f, "offer of '{}' ({} bytes)", name, size),
+            FileMsg::Accept { offset } => write!(f, "accepted from byte {}", offset),
+            FileMsg::Reject => write!(f, "rejected"),
+            FileMsg::Chunk { offset, ref data } => {
+                write!(f, "{} bytes at byte {}", data.len(), offset)
+            },
+            FileMsg::Ack { offset } => write!(f, "received up to byte {}", offset),
+            FileMsg::Cancel(ref reason) => write!(f, "cancelled: {}", reason),
+            FileMsg::Sealed { ref data, .. } => write!(f, "{} encrypted bytes", data.len()),
+        }
+    }
+}
+
+
+/// A self-describing protocol message.
+///
+/// New variants must only ever be appended to the end of this list so that
+/// older peers can still decode the header of newer messages. See
+/// `PROTOCOL_VERSION` and `MIN_PROTOCOL_VERSION` before changing a variant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Envelope {
+    /// A chat line sent to a room. `from` is filled in by the server when
+    /// relaying.
+    Chat { room: String, from: Option<String>, text: String },
+    /// A round-trip time request which should be answered with a `Pong`.
+    Ping(Pingstamp),
+    /// A reply to a `Ping`, containing the original stamp.
+    Pong(Pingstamp),
+    /// A system notice from the server.
+    Notice(String),
+    /// A participant has joined or left a room.
+    Presence { room: String, who: String, presence: Presence },
+    /// A reply to a command sent by a client.
+    Reply { ok: bool, text: String },
+    /// A nickname change request from a client or, when sent by the server,
+    /// the nickname assigned to the receiving client.
+    Nick(String),
+    /// Recorded chat messages, oldest first, sent by the server upon joining
+    /// or in response to a `HistoryRequest`.
+    History(Vec<HistoryEntry>),
+    /// A request for up to `count` of the most recent messages in a room.
+    HistoryRequest { room: String, count: u32 },
+    /// A request to join a room or, when sent by the server, confirmation
+    /// that the receiving client has joined.
+    Join(String),
+    /// A request to leave a room or, when sent by the server, confirmation
+    /// that the receiving client has left.
+    Part(String),
+    /// A request for the list of rooms.
+    ListRequest,
+    /// The list of rooms, sent in response to a `ListRequest`.
+    RoomList(Vec<RoomInfo>),
+    /// A private message to a single participant, routed by the server.
+    /// `from` is filled in by the server when relaying.
+    Direct { from: Option<String>, to: String, text: String },
+    /// The public encryption key of the sending client.
+    Key(Vec<u8>),
+    /// The public keys of all participants which have announced one, sent by
+    /// the server whenever they change.
+    Keys(Vec<PeerKey>),
+    /// An end-to-end encrypted chat message, sealed separately for each
+    /// recipient. Sent to a room or, if `room` is `None`, privately to the
+    /// single recipient. The server delivers each part to its recipient
+    /// only, filling in `from`.
+    Sealed { room: Option<String>, from: Option<String>, parts: Vec<SealedPart> },
+    /// The nicknames of all participants, sent by the server whenever they
+    /// change.
+    Nicks(Vec<String>),
+    /// A `Chat`, `Direct` or `Sealed` message and the times it was sent
+    /// and, once relayed by the server, received.
+    Stamped { times: Timestamps, envelope: Box<Envelope> },
+    /// A file transfer message to a single participant, routed by the server
+    /// as it arrives without being stored. `id` identifies the transfer and
+    /// is chosen by the sender of the file. `from` is filled in by the
+    /// server when relaying.
+    File { from: Option<String>, to: String, id: u64, msg: FileMsg },
+}
+
+impl Envelope {
+    /// Returns a new chat message to `room` without a sender.
+    pub fn chat<R: Into<String>, S: Into<String>>(room: R, text: S) -> Envelope {
+        Envelope::Chat { room: room.into(), from: None, text: text.into() }
+    }
+
+    /// Returns this envelope wrapped with `times`.
+    pub fn stamped(self, times: Timestamps) -> Envelope {
+        Envelope::Stamped { times, envelope: Box::new(self) }
+    }
+
+    /// Returns the contents of a `Stamped` envelope and its stamps or, for
+    /// any other envelope, the envelope itself.
+    pub fn unstamp(self) -> (Envelope, Option<Timestamps>) {
+        match self {
+            Envelope::Stamped { times, envelope } => (*envelope, Some(times)),
+            env => (env, None),
+        }
+    }
+
+    /// Encodes this envelope into a websocket message.
+    pub fn to_message(&self) -> Result<Message, Error> {
+        Ok(Message::Binary(bincode::serialize(&(PROTOCOL_VERSION, self))?))
+    }
+
+    /// Decodes a websocket message.
+    ///
+    /// Returns `Decoded::Unknown` for frames which are well formed but which
+    /// contain a message kind this peer does not understand. Frames of a
+    /// protocol version older than `MIN_PROTOCOL_VERSION` are rejected.
+    pub fn decode(msg: Message) -> Result<Decoded, Error> {
+        let bytes = match msg {
+            Message::Binary(b) => b,
+            Message::Text(_) => return Ok(Decoded::Unknown(
+                "unframed text message".to_owned())),
+        };
+        // The version and the envelope variant index lead every frame:
+        let (version, kind) = bincode::deserialize::<(u16, u32)>(&bytes)?;
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(Error::incompatible_version(version));
+        }
+        if kind >= ENVELOPE_KINDS {
+            return Ok(Decoded::Unknown(format!("message kind #{} (protocol version {})",
+                kind, version)));
+        }
+        let (_, envelope) = bincode::deserialize::<(u16, Envelope)>(&bytes)?;
+        Ok(Decoded::Envelope(envelope))
+    }
+}
+
+
+/// The result of decoding a websocket message.
+#[derive(Debug)]
+pub enum Decoded {
+    Envelope(Envelope),
+    Unknown(String),
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes and decodes `envelope`.
+    fn round_trip(envelope: &Envelope) -> Envelope {
+        match Envelope::decode(envelope.to_message().unwrap()).unwrap() {
+            Decoded::Envelope(envelope) => envelope,
+            Decoded::Unknown(desc) => panic!("unknown envelope: {}", desc),
+        }
+    }
+
+    /// Returns a frame of `version` containing `kind` followed by `payload`.
+    fn frame(version: u16, kind: u32, payload: &[u8]) -> Message {
+        let mut bytes = bincode::serialize(&(version, kind)).unwrap();
+        bytes.extend_from_slice(payload);
+        Message::Binary(bytes)
+    }
+
+    #[test]
+    fn envelopes_round_trip() {
+        let times = Timestamps::now().received_now();
+        match round_trip(&Envelope::chat("#ops", "hi").stamped(times)) {
+            Envelope::Stamped { times: decoded, envelope } => {
+                assert_eq!((decoded.sent, decoded.received), (times.sent, times.received));
+                match *envelope {
+                    Envelope::Chat { room, from: None, text } => {
+                        assert_eq!((room.as_str(), text.as_str()), ("#ops", "hi"));
+                    },
+                    other => panic!("unexpected envelope: {:?}", other),
+                }
+            },
+            other => panic!("unexpected envelope: {:?}", other),
+        }
+
+        let file = Envelope::File { from: Some("bob".to_owned()), to: "alice".to_owned(), id: 7,
+            msg: FileMsg::Chunk { offset: 64, data: vec![1, 2, 3] } };
+        match round_trip(&file) {
+            Envelope::File { from, to, id: 7, msg: FileMsg::Chunk { offset: 64, data } } => {
+                assert_eq!((from.as_deref(), to.as_str(), data), (Some("bob"), "alice",
+                    vec![1, 2, 3]));
+            },
+            other => panic!("unexpected envelope: {:?}", other),
+        }
+
+        match round_trip(&Envelope::HistoryRequest { room: DEFAULT_ROOM.to_owned(), count: 5 }) {
+            Envelope::HistoryRequest { ref room, count: 5 } if room == DEFAULT_ROOM => {},
+            other => panic!("unexpected envelope: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_old_versions() {
+        let msg = frame(MIN_PROTOCOL_VERSION - 1, 0, &[]);
+        let err = Envelope::decode(msg).unwrap_err();
+        assert!(is_incompatible(&err));
+    }
+
+    #[test]
+    fn unknown_kinds_are_not_errors() {
+        match Envelope::decode(frame(PROTOCOL_VERSION + 1, ENVELOPE_KINDS, &[0; 8])).unwrap() {
+            Decoded::Unknown(desc) => assert!(desc.contains(&ENVELOPE_KINDS.to_string())),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match Envelope::decode(Message::Text("hello".to_owned())).unwrap() {
+            Decoded::Unknown(_) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_frames_are_codec_errors() {
+        // A `Chat` envelope cut off within its room name:
+        let err = Envelope::decode(frame(PROTOCOL_VERSION, 0, &[10, 0])).unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::Bincode(_)));
+        let err = Envelope::decode(Message::Binary(vec![1])).unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::Bincode(_)));
+    }
+
+    #[test]
+    fn validates_names() {
+        assert!(is_valid_nick("alice"));
+        assert!(!is_valid_nick("#alice"));
+        assert!(!is_valid_nick("al ice"));
+        assert!(!is_valid_nick(""));
+        assert!(is_valid_room("#ops"));
+        assert!(!is_valid_room("#"));
+        assert!(!is_valid_room("ops"));
+        assert!(!is_valid_room(&format!("#{}", "a".repeat(MAX_NAME_LEN))));
+    }
+}
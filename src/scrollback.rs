@@ -0,0 +1,146 @@
+//! The scrollback buffer of the console message pane.
+
+use std::collections::VecDeque;
+use std::mem;
+use unicode_width::UnicodeWidthChar;
+use theme::StyledLine;
+
+
+/// The default maximum number of lines kept.
+pub const DEFAULT_SCROLLBACK_LEN: usize = 1000;
+
+
+/// Splits `line` into rows at most `width` columns wide.
+fn wrap(line: &StyledLine, width: usize) -> Vec<StyledLine> {
+    let mut rows = vec![StyledLine::new()];
+    let mut col = 0;
+    for span in line.spans() {
+        let mut text = String::new();
+        for c in span.text.chars() {
+            let w = c.width().unwrap_or(0);
+            if col + w > width && col > 0 {
+                rows.last_mut().unwrap().append(span.role, mem::take(&mut text));
+                rows.push(StyledLine::new());
+                col = 0;
+            }
+            text.push(c);
+            col += w;
+        }
+        rows.last_mut().unwrap().append(span.role, text);
+    }
+    rows
+}
+
+
+/// Returns `line` with the control characters it contains, which could move
+/// the cursor or change the state of the terminal, made visible (e.g. '^['
+/// for escape). Tabs are replaced by spaces.
+fn escape_controls(line: StyledLine) -> StyledLine {
+    if !line.spans().iter().any(|span| span.text.chars().any(char::is_control)) {
+        return line;
+    }
+    let mut escaped = StyledLine::new();
+    for span in line.spans() {
+        let mut text = String::with_capacity(span.text.len());
+        for c in span.text.chars() {
+            match c {
+                '\t' => text.push(' '),
+                '\u{0}'..='\u{1f}' => {
+                    text.push('^');
+                    text.push((c as u8 + b'@') as char);
+                },
+                '\u{7f}' => text.push_str("^?"),
+                c if c.is_control() => text.push(char::REPLACEMENT_CHARACTER),
+                c => text.push(c),
+            }
+        }
+        escaped.append(span.role, text);
+    }
+    escaped
+}
+
+
+/// A bounded buffer of output lines and the position the user has scrolled
+/// to.
+#[derive(Debug)]
+pub struct Scrollback {
+    lines: VecDeque<StyledLine>,
+    capacity: usize,
+    // The number of lines scrolled up from the bottom:
+    offset: usize,
+    // The number of lines added below the view since scrolling up:
+    unseen: usize,
+}
+
+impl Scrollback {
+    /// Returns a new, empty buffer keeping up to `capacity` lines.
+    pub fn new(capacity: usize) -> Scrollback {
+        Scrollback {
+            lines: VecDeque::new(),
+            capacity: capacity.max(1),
+            offset: 0,
+            unseen: 0,
+        }
+    }
+
+    /// Adds a line, escaping control characters. If scrolled up, the view
+    /// stays on the same lines.
+    pub fn push(&mut self, line: StyledLine) {
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(escape_controls(line));
+        if self.offset > 0 {
+            self.offset = (self.offset + 1).min(self.lines.len() - 1);
+            self.unseen += 1;
+        }
+    }
+
+    /// Returns the number of lines added below the view since scrolling up.
+    pub fn unseen(&self) -> usize {
+        self.unseen
+    }
+
+    /// Returns true if scrolled up from the most recent line.
+    pub fn is_scrolled(&self) -> bool {
+        self.offset > 0
+    }
+
+    /// Scrolls towards older lines.
+    pub fn scroll_up(&mut self, n: usize) {
+        self.offset = (self.offset + n).min(self.lines.len().saturating_sub(1));
+    }
+
+    /// Scrolls towards newer lines.
+    pub fn scroll_down(&mut self, n: usize) {
+        self.offset = self.offset.saturating_sub(n);
+        self.unseen = self.unseen.min(self.offset);
+    }
+
+    /// Scrolls to the oldest line.
+    pub fn scroll_to_top(&mut self) {
+        self.scroll_up(self.lines.len());
+    }
+
+    /// Scrolls to the most recent line.
+    pub fn scroll_to_bottom(&mut self) {
+        self.offset = 0;
+        self.unseen = 0;
+    }
+
+    /// Returns the rows to display in a pane of `width` columns by `height`
+    /// rows, oldest first, ending with the line scrolled to.
+    pub fn rows(&self, width: usize, height: usize) -> Vec<StyledLine> {
+        let end = self.lines.len() - self.offset.min(self.lines.len());
+        let mut rows = Vec::with_capacity(height);
+        for line in self.lines.iter().take(end).rev() {
+            if rows.len() >= height { break; }
+            for row in wrap(line, width).into_iter().rev() {
+                if rows.len() >= height { break; }
+                rows.push(row);
+            }
+        }
+        rows.reverse();
+        rows
+    }
+}
@@ -1,95 +1,742 @@
 //! A websocket chat server.
 
 use std::sync::{Arc, Mutex};
-use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{BTreeMap, BTreeSet};
 use std::str;
+use std::io;
+use std::mem;
 use std::net::{SocketAddr};
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use url::Url;
+use openssl::ssl::{SslAcceptor, SslStream};
 use ws::{self, Sender as WsSender, Message, Handler, Handshake, CloseCode, Factory,
-	util::Token, Builder as WsBuilder, Settings};
-use bincode;
-use chrono::Utc;
-use ::{UiRemote, Pingstamp, Error};
+	util::{Token, TcpStream}, Builder as WsBuilder, Settings};
+use ::{EventSink, Error, millis};
+use chrono::{DateTime, Utc};
+use protocol::{self, Envelope, Decoded, Pingstamp, Presence, HistoryEntry, RoomInfo,
+    PeerKey, SealedPart, Timestamps, FileMsg, DEFAULT_ROOM, PASSWORD_HEADER, AUTH_FAILED};
+use history::History;
+use crypto::{self, Identity, Context};
+use tls;
+
+
+/// The timeout token of the background ping.
+const PING_TIMEOUT: Token = Token(1);
+
+
+/// Server settings.
+#[derive(Clone, Default)]
+pub struct ServerOptions {
+    /// Accept only encrypted ('wss') connections if set.
+    pub tls: Option<Arc<SslAcceptor>>,
+    /// The keypair of the local user. Chat messages sent by the local user
+    /// are end-to-end encrypted if set.
+    pub identity: Option<Arc<Identity>>,
+    /// Clients must give this password to connect, if set.
+    pub password: Option<String>,
+    /// If set, the local user does not take part in the chat: it joins no
+    /// rooms and the chat and presence events of every room are reported
+    /// instead.
+    pub observer: bool,
+    /// The interval at which to ping each client in the background, if any.
+    pub ping_interval: Option<Duration>,
+    /// The number of consecutive background pings left unanswered after
+    /// which a client is considered lost and disconnected. Never
+    /// disconnects if zero.
+    pub max_missed_pings: u32,
+}
+
+
+/// A connected client.
+struct Peer {
+    sender: WsSender,
+    nick: String,
+    // The public encryption key announced by the client, if any:
+    key: Option<Vec<u8>>,
+}
+
+
+/// A member of a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Member {
+    /// The local user operating the server.
+    Local,
+    Client(Token),
+}
+
+
+/// State shared between the server and all of its connections.
+struct ServerState {
+    // The nickname of the local user operating the server:
+    nick: String,
+    // `BTreeMap` because it's faster for a small N.
+    clients: BTreeMap<Token, Peer>,
+    // The room registry. Rooms other than the default room are removed once
+    // they become empty.
+    rooms: BTreeMap<String, BTreeSet<Member>>,
+    history: History,
+    identity: Option<Arc<Identity>>,
+    // The send time of the latest encrypted message to the local user from
+    // each participant, by public key, to recognize replayed messages:
+    latest_sealed: BTreeMap<Vec<u8>, DateTime<Utc>>,
+    observer: bool,
+}
+
+impl ServerState {
+    /// Returns true if `nick` is used by the server or any client.
+    fn nick_taken(&self, nick: &str) -> bool {
+        self.nick == nick || self.clients.values().any(|p| p.nick == nick)
+    }
+
+    /// Returns `nick` if it is not in use, otherwise `nick` with the lowest
+    /// available numeric suffix.
+    fn unique_nick(&self, nick: &str) -> String {
+        if !self.nick_taken(nick) { return nick.to_owned(); }
+        (2..).map(|i| format!("{}_{}", nick, i))
+            .find(|n| !self.nick_taken(n))
+            .unwrap()
+    }
+
+    /// Records a chat message received at `time`, returning a description
+    /// of the error if the history file could not be written.
+    fn record(&mut self, room: &str, from: &str, text: &str, time: DateTime<Utc>)
+            -> Option<String> {
+        let entry = HistoryEntry { time, room: room.to_owned(),
+            from: from.to_owned(), text: text.to_owned() };
+        self.history.push(entry).err()
+            .map(|err| format!("Unable to save message history ({}). History will \
+                no longer be saved.", err))
+    }
+
+    /// Adds `member` to `room`, creating the room if necessary. Returns
+    /// false if `member` had already joined.
+    fn join(&mut self, room: &str, member: Member) -> bool {
+        self.rooms.entry(room.to_owned()).or_default().insert(member)
+    }
+
+    /// Removes `member` from `room`. Returns false if `member` had not
+    /// joined.
+    fn part(&mut self, room: &str, member: Member) -> bool {
+        let (removed, empty) = match self.rooms.get_mut(room) {
+            Some(members) => (members.remove(&member), members.is_empty()),
+            None => (false, false),
+        };
+        if empty && room != DEFAULT_ROOM {
+            self.rooms.remove(room);
+        }
+        removed
+    }
+
+    /// Returns the sender of the client using `nick`.
+    fn client_by_nick(&self, nick: &str) -> Option<&WsSender> {
+        self.clients.values().find(|p| p.nick == nick).map(|p| &p.sender)
+    }
+
+    /// Returns the room membership identity of the participant using `nick`.
+    fn member_by_nick(&self, nick: &str) -> Option<Member> {
+        if nick == self.nick { return Some(Member::Local); }
+        self.clients.iter().find(|&(_, p)| p.nick == nick).map(|(&token, _)| Member::Client(token))
+    }
+
+    /// Returns the nicknames of the members of `room` other than `except`.
+    fn nicks_in(&self, room: &str, except: Member) -> Vec<String> {
+        self.rooms.get(room).into_iter().flat_map(|m| m.iter())
+            .filter(|&&member| member != except)
+            .filter_map(|member| match *member {
+                Member::Local => Some(self.nick.clone()),
+                Member::Client(ref token) => self.clients.get(token).map(|p| p.nick.clone()),
+            })
+            .collect()
+    }
+
+    /// Returns the public key of the participant using `nick`.
+    fn key_of(&self, nick: &str) -> Option<&[u8]> {
+        if nick == self.nick {
+            return self.identity.as_ref().map(|id| id.public_key());
+        }
+        self.clients.values().find(|p| p.nick == nick).and_then(|p| p.key.as_ref())
+            .map(Vec::as_slice)
+    }
+
+    /// Returns the public keys of all participants which have one.
+    fn key_directory(&self) -> Vec<PeerKey> {
+        let local = self.identity.as_ref()
+            .map(|id| PeerKey { nick: self.nick.clone(), key: id.public_key().to_vec() });
+        local.into_iter()
+            .chain(self.clients.values().filter_map(|p| p.key.as_ref()
+                .map(|key| PeerKey { nick: p.nick.clone(), key: key.clone() })))
+            .collect()
+    }
+
+    /// Sends the public keys of all participants to every client.
+    fn broadcast_keys(&self) -> Result<(), Error> {
+        let msg = Envelope::Keys(self.key_directory()).to_message()?;
+        for peer in self.clients.values() {
+            peer.sender.send(msg.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the nicknames of all participants, including the local user
+    /// unless only observing.
+    fn nicks(&self) -> Vec<String> {
+        let local = if self.observer { None } else { Some(self.nick.clone()) };
+        local.into_iter().chain(self.clients.values().map(|p| p.nick.clone())).collect()
+    }
+
+    /// Sends the nicknames of all participants to every client.
+    fn broadcast_nicks(&self) -> Result<(), Error> {
+        let msg = Envelope::Nicks(self.nicks()).to_message()?;
+        for peer in self.clients.values() {
+            peer.sender.send(msg.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if `member` has joined `room`.
+    fn is_member(&self, room: &str, member: Member) -> bool {
+        self.rooms.get(room).map(|m| m.contains(&member)).unwrap_or(false)
+    }
+
+    /// Returns the names of all rooms `member` has joined.
+    fn rooms_of(&self, member: Member) -> Vec<String> {
+        self.rooms.iter()
+            .filter(|&(_, members)| members.contains(&member))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Returns all rooms and their member counts.
+    fn room_list(&self) -> Vec<RoomInfo> {
+        self.rooms.iter()
+            .map(|(name, members)| RoomInfo { name: name.clone(), members: members.len() as u32 })
+            .collect()
+    }
+
+    /// Sends a message to every client in `room` other than `except`.
+    ///
+    /// Returns true if the local user is a member of `room` (and is not
+    /// `except`) or is an observer, in which case it is up to the caller to
+    /// report the event.
+    fn send_to_room(&self, room: &str, env: &Envelope, except: Member) -> Result<bool, Error> {
+        let msg = env.to_message()?;
+        let mut local = false;
+        for &member in self.rooms.get(room).into_iter().flat_map(|m| m.iter()) {
+            match member {
+                _ if member == except => {},
+                Member::Local => local = true,
+                Member::Client(ref token) => if let Some(peer) = self.clients.get(token) {
+                    peer.sender.send(msg.clone())?;
+                },
+            }
+        }
+        Ok(local || self.observer)
+    }
+}
+
+
+/// Returns the nickname requested within the query string of a handshake
+/// resource (e.g. '/?nick=bob').
+fn requested_nick(resource: &str) -> Option<String> {
+    let url = Url::parse("ws://localhost").and_then(|base| base.join(resource)).ok()?;
+    let nick = url.query_pairs().find(|(k, _)| k == "nick").map(|(_, v)| v.into_owned());
+    nick.filter(|n| protocol::is_valid_nick(n))
+}
 
 
 /// A chat server handler.
 struct ServerHandler {
-	ui_remote: UiRemote,
+	events: Arc<dyn EventSink>,
     output: WsSender,
-    clients: Arc<Mutex<BTreeMap<Token, WsSender>>>,
+    state: Arc<Mutex<ServerState>>,
+    tls: Option<Arc<SslAcceptor>>,
+    password: Option<String>,
+    ping_interval: Option<Duration>,
+    max_missed_pings: u32,
+    // The number of background pings sent since anything was received:
+    missed_pings: u32,
+    // Set once the connection is dropped for not answering pings:
+    timed_out: bool,
+}
+
+impl ServerHandler {
+    /// Returns the room membership identity of this connection.
+    fn member(&self) -> Member {
+        Member::Client(self.output.token())
+    }
+
+    /// Returns the nickname of the client on this connection.
+    fn nick(&self) -> String {
+        self.state.lock().unwrap().clients.get(&self.output.token())
+            .map(|p| p.nick.clone())
+            .unwrap_or_else(|| format!("guest{}", usize::from(self.output.token())))
+    }
+
+    /// Sends a message to every connected client other than this one.
+    fn relay(&self, env: &Envelope) -> Result<(), ws::Error> {
+        let msg = env.to_message()?;
+        let state = self.state.lock().unwrap();
+        for (token, peer) in state.clients.iter() {
+            if token != &self.output.token() {
+                peer.sender.send(msg.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a message to the client on this connection.
+    fn reply(&self, env: &Envelope) -> Result<(), ws::Error> {
+        self.output.send(env.to_message()?)
+    }
+
+    /// Sends an error reply to the client on this connection.
+    fn reply_err(&self, text: String) -> Result<(), ws::Error> {
+        self.reply(&Envelope::Reply { ok: false, text })
+    }
+
+    /// Changes the nickname of the client on this connection.
+    fn change_nick(&mut self, new_nick: String) -> Result<(), ws::Error> {
+        if !protocol::is_valid_nick(&new_nick) {
+            return self.reply_err(format!("Invalid nickname: '{}'.", new_nick));
+        }
+        let old_nick = {
+            let mut state = self.state.lock().unwrap();
+            if state.nick_taken(&new_nick) {
+                return self.reply_err(format!("Nickname '{}' is already in use.", new_nick));
+            }
+            match state.clients.get_mut(&self.output.token()) {
+                Some(peer) => mem::replace(&mut peer.nick, new_nick.clone()),
+                None => return Ok(()),
+            }
+        };
+        let notice = format!("{} is now known as {}.", old_nick, new_nick);
+        self.reply(&Envelope::Nick(new_nick))?;
+        self.relay(&Envelope::Notice(notice.clone()))?;
+        self.events.notice_recvd(notice);
+        let state = self.state.lock().unwrap();
+        state.broadcast_nicks()?;
+        state.broadcast_keys()?;
+        Ok(())
+    }
+
+    /// Adds the client on this connection to a room, replaying its recent
+    /// history.
+    fn join_room(&mut self, room: String) -> Result<(), ws::Error> {
+        if !protocol::is_valid_room(&room) {
+            return self.reply_err(format!("Invalid room name: '{}'.", room));
+        }
+        let nick = self.nick();
+        let mut state = self.state.lock().unwrap();
+        let joined = state.join(&room, self.member());
+        self.reply(&Envelope::Join(room.clone()))?;
+        if joined {
+            let replay = state.history.replay(&room);
+            if !replay.is_empty() {
+                self.reply(&Envelope::History(replay))?;
+            }
+            let presence = Envelope::Presence { room: room.clone(), who: nick.clone(),
+                presence: Presence::Joined };
+            if state.send_to_room(&room, &presence, self.member())? {
+                self.events.presence_recvd(room, nick, Presence::Joined);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends up to `count` of the most recent messages in a room to the
+    /// client on this connection, which must have joined it.
+    fn history(&mut self, room: String, count: u32) -> Result<(), ws::Error> {
+        let state = self.state.lock().unwrap();
+        if !state.is_member(&room, self.member()) {
+            return self.reply_err(format!("You are not in {}.", room));
+        }
+        self.reply(&Envelope::History(state.history.last(&room, count as usize)))
+    }
+
+    /// Removes the client on this connection from a room.
+    fn part_room(&mut self, room: String) -> Result<(), ws::Error> {
+        let nick = self.nick();
+        let mut state = self.state.lock().unwrap();
+        if !state.part(&room, self.member()) {
+            return self.reply_err(format!("You are not in {}.", room));
+        }
+        self.reply(&Envelope::Part(room.clone()))?;
+        let presence = Envelope::Presence { room: room.clone(), who: nick.clone(),
+            presence: Presence::Left };
+        if state.send_to_room(&room, &presence, self.member())? {
+            self.events.presence_recvd(room, nick, Presence::Left);
+        }
+        Ok(())
+    }
+
+    /// Routes a private message to a single participant.
+    fn direct(&mut self, to: String, text: String, times: Timestamps) -> Result<(), ws::Error> {
+        let nick = self.nick();
+        let state = self.state.lock().unwrap();
+        if to == state.nick {
+            self.events.direct_recvd(nick, text, times);
+            return Ok(());
+        }
+        match state.client_by_nick(&to) {
+            Some(sender) => sender.send(Envelope::Direct { from: Some(nick), to,
+                text }.stamped(times).to_message()?),
+            None => self.reply_err(format!("No such nickname: '{}'.", to)),
+        }
+    }
+
+    /// Routes a file transfer message to a single participant. Chunks are
+    /// passed on as they arrive and never stored.
+    fn file(&mut self, to: String, id: u64, msg: FileMsg) -> Result<(), ws::Error> {
+        let nick = self.nick();
+        let state = self.state.lock().unwrap();
+        if to == state.nick && !state.observer {
+            return self.open_file(&state, nick, id, msg);
+        }
+        let reason = match state.client_by_nick(&to) {
+            Some(sender) => {
+                return sender.send(Envelope::File { from: Some(nick), to, id, msg }.to_message()?);
+            },
+            None if to == state.nick => format!("{} does not accept files.", to),
+            None => format!("No such nickname: '{}'.", to),
+        };
+        // The transfer is cancelled on behalf of the missing recipient:
+        match msg {
+            FileMsg::Cancel(_) => Ok(()),
+            _ => self.reply(&Envelope::File { from: Some(to), to: nick, id,
+                msg: FileMsg::Cancel(reason) }),
+        }
+    }
+
+    /// Reports a file transfer message from the client using `nick` to the
+    /// local user, decrypting it if the local user has an identity.
+    /// Unencrypted messages are then ignored, except for cancellations.
+    /// Unencrypted offers are cancelled.
+    fn open_file(&self, state: &ServerState, nick: String, id: u64, msg: FileMsg)
+            -> Result<(), ws::Error> {
+        let identity = match state.identity {
+            Some(ref identity) => identity,
+            None => {
+                self.events.file_recvd(nick, id, msg);
+                return Ok(());
+            },
+        };
+        let msg = match msg {
+            FileMsg::Sealed { kind, offset, data } => {
+                state.key_of(&nick).ok_or_else(|| Error::no_key(nick.clone())).and_then(|key| {
+                    let context = Context::File { from: &nick, to: &state.nick, id, kind, offset };
+                    identity.open_file(key, &context, &data)
+                })
+            },
+            msg @ FileMsg::Cancel(_) => Ok(msg),
+            FileMsg::Offer { .. } => {
+                let cancel = FileMsg::Cancel("The recipient only accepts encrypted files."
+                    .to_owned());
+                self.reply(&Envelope::File { from: Some(state.nick.clone()), to: nick.clone(),
+                    id, msg: cancel })?;
+                Err(Error::not_encrypted())
+            },
+            _ => Err(Error::not_encrypted()),
+        };
+        match msg {
+            Ok(msg) => self.events.file_recvd(nick, id, msg),
+            Err(err) => self.events.notice_recvd(format!("Ignored a file transfer message from \
+                {}: {}", nick, err)),
+        }
+        Ok(())
+    }
+
+    /// Records the public encryption key of the client on this connection.
+    fn set_key(&mut self, key: Vec<u8>) -> Result<(), ws::Error> {
+        let mut state = self.state.lock().unwrap();
+        match state.clients.get_mut(&self.output.token()) {
+            Some(peer) => peer.key = Some(key),
+            None => return Ok(()),
+        }
+        state.broadcast_keys()?;
+        Ok(())
+    }
+
+    /// Delivers each part of an encrypted message to its recipient, within
+    /// `room` or, if `None`, privately.
+    ///
+    /// Messages to the local user which were not sent after the latest one
+    /// from the same sender are replays and are ignored.
+    fn sealed(&mut self, room: Option<String>, parts: Vec<SealedPart>, times: Timestamps)
+            -> Result<(), ws::Error> {
+        let nick = self.nick();
+        let mut state = self.state.lock().unwrap();
+        let mut undelivered = match room {
+            Some(ref room) if !state.is_member(room, self.member()) => {
+                return self.reply_err(format!("You are not in {}.", room));
+            },
+            Some(ref room) => state.nicks_in(room, self.member()),
+            None => Vec::new(),
+        };
+
+        for part in parts {
+            let member = match state.member_by_nick(&part.to) {
+                Some(m) if room.as_ref().map(|r| state.is_member(r, m)).unwrap_or(true) => m,
+                Some(_) => continue,
+                None if room.is_none() => {
+                    self.reply_err(format!("No such nickname: '{}'.", part.to))?;
+                    continue;
+                },
+                None => continue,
+            };
+            undelivered.retain(|n| n != &part.to);
+            match member {
+                Member::Local => {
+                    let key = state.key_of(&nick).map(<[u8]>::to_vec);
+                    let state = &mut *state;
+                    let text = match (state.identity.as_ref(), key) {
+                        (Some(id), Some(key)) => {
+                            let context = Context::Message { room: room.as_deref(), from: &nick,
+                                to: &state.nick, sent: times.sent };
+                            let latest = &mut state.latest_sealed;
+                            id.open(&key, &context, &part.data).and_then(|text| {
+                                match latest.get(&key) {
+                                    Some(&sent) if sent >= times.sent => Err(Error::replayed()),
+                                    _ => {
+                                        latest.insert(key, times.sent);
+                                        Ok(text)
+                                    },
+                                }
+                            })
+                        },
+                        _ => Err(Error::decryption_failed()),
+                    };
+                    match (text, room.clone()) {
+                        (Ok(text), Some(room)) => {
+                            self.events.message_recvd(room, nick.clone(), text, times)
+                        },
+                        (Ok(text), None) => self.events.direct_recvd(nick.clone(), text, times),
+                        (Err(err), _) => self.events.notice_recvd(format!("Ignored a message \
+                            from {}: {}", nick, err)),
+                    }
+                },
+                Member::Client(ref token) => if let Some(peer) = state.clients.get(token) {
+                    peer.sender.send(Envelope::Sealed { room: room.clone(), from: Some(nick.clone()),
+                        parts: vec![part] }.stamped(times).to_message()?)?;
+                },
+            }
+        }
+
+        if !undelivered.is_empty() {
+            self.reply_err(format!("Not delivered to {} (no encryption key).",
+                undelivered.join(", ")))?;
+        }
+        Ok(())
+    }
+
+    /// Relays a chat message to the other members of a room.
+    fn chat(&mut self, room: String, text: String, times: Timestamps) -> Result<(), ws::Error> {
+        let nick = self.nick();
+        let mut state = self.state.lock().unwrap();
+        if !state.is_member(&room, self.member()) {
+            return self.reply_err(format!("You are not in {}.", room));
+        }
+        if let Some(err) = state.record(&room, &nick, &text, times.time()) {
+            self.events.notice_recvd(err);
+        }
+        let chat = Envelope::Chat { room: room.clone(), from: Some(nick.clone()), text: text.clone() }
+            .stamped(times);
+        if state.send_to_room(&room, &chat, self.member())? {
+            self.events.message_recvd(room, nick, text, times);
+        }
+        Ok(())
+    }
 }
 
 impl Handler for ServerHandler {
     fn on_shutdown(&mut self) {
-        self.ui_remote.server_shutdown();
+        self.events.server_shutdown();
     }
 
     fn on_open(&mut self, shake: Handshake) -> Result<(), ws::Error> {
-        self.ui_remote.server_connected(shake);
-        Ok(())
+        if let Some(ref password) = self.password {
+            let given = shake.request.header(PASSWORD_HEADER);
+            let reason = match given {
+                Some(p) if crypto::password_matches(password, p) => None,
+                Some(_) => Some("Invalid password."),
+                None => Some("A password is required."),
+            };
+            if let Some(reason) = reason {
+                self.events.notice_recvd(format!("Rejected a connection from {}: {}",
+                    shake.peer_addr.map(|a| a.to_string()).unwrap_or_default(), reason));
+                return self.output.close_with_reason(AUTH_FAILED, reason);
+            }
+        }
+
+        let nick = {
+            let mut state = self.state.lock().unwrap();
+            let requested = requested_nick(shake.request.resource())
+                .unwrap_or_else(|| format!("guest{}", usize::from(self.output.token())));
+            let nick = state.unique_nick(&requested);
+            state.clients.insert(self.output.token(),
+                Peer { sender: self.output.clone(), nick: nick.clone(), key: None });
+            nick
+        };
+        self.reply(&Envelope::Nick(nick.clone()))?;
+        self.state.lock().unwrap().broadcast_nicks()?;
+        self.events.server_connected(shake.peer_addr, nick);
+        if let Some(interval) = self.ping_interval {
+            self.output.timeout(millis(interval), PING_TIMEOUT)?;
+        }
+        self.join_room(DEFAULT_ROOM.to_owned())
     }
 
     fn on_message(&mut self, msg: Message) -> Result<(), ws::Error> {
-        match msg {
-            Message::Text(s) => {
-                // Relay message to other connected clients:
-                let cls = self.clients.lock().unwrap();
-                for (token, sender) in cls.iter() {
-            		if token != &self.output.token() {
-            			let send = format!("Client<{}>: {}", usize::from(self.output.token()), s);
-            			sender.send(send)?;
-            		}
-            	}
-            	self.ui_remote.message_recvd(s, self.output.token());
-                Ok(())
-            },
-            Message::Binary(b) => {
-                match bincode::deserialize::<Pingstamp>(&b) {
-                    Ok(Pingstamp::Ping(ts)) => {
-                        self.output.send(bincode::serialize(&Pingstamp::Pong(ts)).unwrap())
+        self.missed_pings = 0;
+        match Envelope::decode(msg) {
+            Ok(Decoded::Envelope(env)) => {
+                // Messages from older clients are stamped upon receipt:
+                let (env, times) = env.unstamp();
+                let times = times.unwrap_or_else(Timestamps::now).received_now();
+                match env {
+                    Envelope::Chat { room, text, .. } => {
+                        self.chat(room, text, times)
                     },
-                    Ok(Pingstamp::Pong(ts)) => {
-                    	let elapsed = Utc::now().signed_duration_since(ts);
-                        self.ui_remote.pong_recvd(elapsed);
+                    Envelope::Direct { to, text, .. } => {
+                        self.direct(to, text, times)
+                    },
+                    Envelope::Sealed { room, parts, .. } => {
+                        self.sealed(room, parts, times)
+                    },
+                    Envelope::Ping(ts) => self.reply(&Envelope::Pong(ts)),
+                    Envelope::Pong(ts) => {
+                        self.events.pong_recvd(self.nick(), ts.elapsed());
                         Ok(())
-                    }
-                    Err(err) => {
-                        self.ui_remote.server_error(err.into());
+                    },
+                    Envelope::Nick(nick) => self.change_nick(nick),
+                    Envelope::HistoryRequest { room, count } => self.history(room, count),
+                    Envelope::Join(room) => self.join_room(room),
+                    Envelope::Part(room) => self.part_room(room),
+                    Envelope::ListRequest => {
+                        let rooms = self.state.lock().unwrap().room_list();
+                        self.reply(&Envelope::RoomList(rooms))
+                    },
+                    Envelope::Key(key) => self.set_key(key),
+                    Envelope::File { to, id, msg, .. } => self.file(to, id, msg),
+                    env @ Envelope::Notice(..) | env @ Envelope::Presence { .. } |
+                            env @ Envelope::Reply { .. } | env @ Envelope::History(..) |
+                            env @ Envelope::RoomList(..) | env @ Envelope::Keys(..) |
+                            env @ Envelope::Nicks(..) | env @ Envelope::Stamped { .. } => {
+                        self.events.unknown_recvd(format!("unexpected message from {}: {:?}",
+                            self.nick(), env));
                         Ok(())
                     },
                 }
             },
+            Ok(Decoded::Unknown(desc)) => {
+                self.events.unknown_recvd(desc);
+                Ok(())
+            },
+            // Nothing sent by an incompatible client can be understood:
+            Err(ref err) if protocol::is_incompatible(err) => {
+                self.events.notice_recvd(format!("Closing the connection of {}: {}",
+                    self.nick(), err));
+                self.output.close_with_reason(CloseCode::Protocol, err.to_string())
+            },
+            Err(err) => {
+                self.events.server_error(err);
+                Ok(())
+            },
+        }
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<(), ws::Error> {
+        match (event, self.ping_interval) {
+            (PING_TIMEOUT, Some(_)) if self.max_missed_pings != 0 &&
+                    self.missed_pings >= self.max_missed_pings => {
+                // A close frame would go unanswered, so the connection is
+                // dropped with an error instead. `on_close` follows, removing
+                // the client:
+                self.timed_out = true;
+                self.events.notice_recvd(format!("{} has not answered {} pings. Closing the \
+                    connection.", self.nick(), self.missed_pings));
+                Err(io::Error::new(io::ErrorKind::TimedOut, "No reply to pings.").into())
+            },
+            (PING_TIMEOUT, Some(interval)) => {
+                self.reply(&Envelope::Ping(Pingstamp::now()))?;
+                self.missed_pings += 1;
+                self.output.timeout(millis(interval), PING_TIMEOUT)
+            },
+            _ => Ok(()),
         }
     }
 
     fn on_close(&mut self, code: CloseCode, reason: &str) {
-        let mut cls = self.clients.lock().unwrap();
-        // Remove sender for this connection from the master list:
-        cls.remove(&self.output.token());
-    	self.ui_remote.server_closed(code, reason.to_owned());
+        {
+            let mut state = self.state.lock().unwrap();
+            // Remove this connection from the master list and from every room.
+            // Rejected connections were never added:
+            let peer = match state.clients.remove(&self.output.token()) {
+                Some(peer) => peer,
+                None => return,
+            };
+            for room in state.rooms_of(self.member()) {
+                state.part(&room, self.member());
+                let presence = Envelope::Presence { room: room.clone(), who: peer.nick.clone(),
+                    presence: Presence::Left };
+                if let Ok(true) = state.send_to_room(&room, &presence, self.member()) {
+                    self.events.presence_recvd(room, peer.nick.clone(), Presence::Left);
+                }
+            }
+            state.broadcast_nicks().ok();
+            if peer.key.is_some() {
+                state.broadcast_keys().ok();
+            }
+        }
+    	self.events.server_closed(code, reason.to_owned());
     }
 
     fn on_error(&mut self, err: ws::Error) {
-        self.ui_remote.server_error(err.into());
+        if self.timed_out { return; }
+        // Errors before the handshake completes (e.g. a failed TLS
+        // negotiation) only affect this connection:
+        if !self.state.lock().unwrap().clients.contains_key(&self.output.token()) {
+            self.events.notice_recvd(format!("Rejected a connection: {}", err));
+            return;
+        }
+        self.events.server_error(err.into());
+    }
+
+    fn upgrade_ssl_server(&mut self, stream: TcpStream) -> Result<SslStream<TcpStream>, ws::Error> {
+        match self.tls {
+            Some(ref acceptor) => tls::accept(acceptor, stream),
+            None => Err(ws::Error::new(ws::ErrorKind::Internal, "TLS is not configured.")),
+        }
     }
 }
 
 
 struct ServerHandlerFactory {
-    ui_remote: UiRemote,
-    // `BTreeSet` because it's faster for a small N.
-    clients: Arc<Mutex<BTreeMap<Token, WsSender>>>,
+    events: Arc<dyn EventSink>,
+    state: Arc<Mutex<ServerState>>,
+    tls: Option<Arc<SslAcceptor>>,
+    password: Option<String>,
+    ping_interval: Option<Duration>,
+    max_missed_pings: u32,
 }
 
 impl Factory for ServerHandlerFactory {
     type Handler = ServerHandler;
 
     fn connection_made(&mut self, output: WsSender) -> Self::Handler {
-    	self.clients.lock().unwrap().insert(output.token(), output.clone());
+        // The connection is added to the client list once its handshake
+        // (containing the requested nickname) has completed.
         ServerHandler {
-        	ui_remote: self.ui_remote.clone(),
+        	events: self.events.clone(),
         	output,
-        	clients: self.clients.clone(),
+        	state: self.state.clone(),
+            tls: self.tls.clone(),
+            password: self.password.clone(),
+            ping_interval: self.ping_interval,
+            max_missed_pings: self.max_missed_pings,
+            missed_pings: 0,
+            timed_out: false,
         }
     }
 }
@@ -100,29 +747,61 @@ pub struct Server {
     _th: JoinHandle<()>,
     sender: WsSender,
     url: SocketAddr,
+    secure: bool,
+    state: Arc<Mutex<ServerState>>,
+    events: Arc<dyn EventSink>,
+    // Cleared once the listener has failed:
+    listening: Arc<AtomicBool>,
 }
 
 impl Server {
-    pub fn new(url: SocketAddr, ui_remote: UiRemote) -> Result<Server, Error> {
+    /// Listens on `url`.
+    pub fn new(url: SocketAddr, nick: &str, history: History, opts: ServerOptions,
+            events: Arc<dyn EventSink>) -> Result<Server, Error> {
+        let ServerOptions { tls, identity, password, observer, ping_interval, max_missed_pings } =
+            opts;
+        let secure = tls.is_some();
+        let state = Arc::new(Mutex::new(ServerState {
+            nick: nick.to_owned(),
+            clients: BTreeMap::new(),
+            rooms: BTreeMap::new(),
+            history,
+            identity,
+            latest_sealed: BTreeMap::new(),
+            observer,
+        }));
+        // The local user starts out in the default room:
+        if !observer {
+            state.lock().unwrap().join(DEFAULT_ROOM, Member::Local);
+        }
         let factory = ServerHandlerFactory {
-        	ui_remote: ui_remote.clone(),
-        	clients: Arc::new(Mutex::new(BTreeMap::new())),
+        	events: events.clone(),
+        	state: state.clone(),
+            tls,
+            password,
+            ping_interval,
+            max_missed_pings,
     	};
         let ws = WsBuilder::new()
             .with_settings(Settings {
                 // Defaults to true:
                 panic_on_internal: false,
+                encrypt_server: secure,
                 ..Settings::default()
             })
             .build(factory)?;
-        let url_clone = url.clone();
+        let url_clone = url;
         let sender = ws.broadcaster();
+        let th_events = events.clone();
+        let listening = Arc::new(AtomicBool::new(true));
+        let th_listening = listening.clone();
 
         let _th = thread::Builder::new()
                 .name("chat-server".to_owned())
                 .spawn(move || {
-            if let Err(err) = ws.listen(&url_clone) {
-            	ui_remote.server_error(err.into());
+            if let Err(err) = ws.listen(url_clone) {
+                th_listening.store(false, Ordering::SeqCst);
+            	th_events.server_error(err.into());
             }
         })?;
 
@@ -130,6 +809,10 @@ impl Server {
             _th,
             sender,
             url,
+            secure,
+            state,
+            events,
+            listening,
         })
     }
 
@@ -137,9 +820,181 @@ impl Server {
     	&self.url
     }
 
-    pub fn send<M: Into<Message>>(&self, msg: M) -> Result<(), Error> {
-        let ts: Vec<u8> = bincode::serialize(&Pingstamp::now())?;
-        self.sender.send(msg).and(self.sender.send(ts)).map_err(Error::from)
+    /// Returns true if connections are encrypted.
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// Returns false once the server has stopped listening because of an
+    /// error. Other errors only affect a single connection.
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+
+    /// Changes the nickname of the local user, notifying all clients.
+    pub fn set_nick(&self, nick: &str) -> Result<(), Error> {
+        let old_nick = {
+            let mut state = self.state.lock().unwrap();
+            if state.nick == nick { return Ok(()); }
+            if state.nick_taken(nick) { return Err(Error::nick_in_use(nick)); }
+            mem::replace(&mut state.nick, nick.to_owned())
+        };
+        self.send(&Envelope::Notice(format!("{} is now known as {}.", old_nick, nick)))?;
+        let state = self.state.lock().unwrap();
+        state.broadcast_nicks()?;
+        state.broadcast_keys()
+    }
+
+    /// Returns the nicknames of all participants.
+    pub fn nicks(&self) -> Vec<String> {
+        self.state.lock().unwrap().nicks()
+    }
+
+    /// Returns the key fingerprint of the participant using `nick`.
+    pub fn fingerprint(&self, nick: &str) -> Option<String> {
+        self.state.lock().unwrap().key_of(nick).map(crypto::fingerprint)
+    }
+
+    /// Broadcasts a protocol message to all connected clients.
+    pub fn send(&self, env: &Envelope) -> Result<(), Error> {
+        self.sender.send(env.to_message()?).map_err(Error::from)
+    }
+
+    /// Returns up to `n` of the most recent chat messages in `room`, oldest
+    /// first.
+    pub fn history(&self, room: &str, n: usize) -> Vec<HistoryEntry> {
+        self.state.lock().unwrap().history.last(room, n)
+    }
+
+    /// Returns all rooms and their member counts.
+    pub fn rooms(&self) -> Vec<RoomInfo> {
+        self.state.lock().unwrap().room_list()
+    }
+
+    /// Adds the local user to `room`, notifying its members.
+    pub fn join(&self, room: &str) -> Result<(), Error> {
+        if !protocol::is_valid_room(room) { return Err(Error::invalid_room(room)); }
+        let mut state = self.state.lock().unwrap();
+        if state.join(room, Member::Local) {
+            let presence = Envelope::Presence { room: room.to_owned(), who: state.nick.clone(),
+                presence: Presence::Joined };
+            state.send_to_room(room, &presence, Member::Local)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the local user from `room`, notifying its members.
+    pub fn part(&self, room: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if !state.part(room, Member::Local) { return Err(Error::not_in_room(room)); }
+        let presence = Envelope::Presence { room: room.to_owned(), who: state.nick.clone(),
+            presence: Presence::Left };
+        state.send_to_room(room, &presence, Member::Local).map(|_| ())
+    }
+
+    /// Sends a private message to the client using the nickname `to`,
+    /// encrypted if the local user has an identity.
+    pub fn send_direct(&self, to: &str, text: &str) -> Result<(), Error> {
+        let state = self.state.lock().unwrap();
+        let sender = state.client_by_nick(to).ok_or_else(|| Error::unknown_nick(to))?;
+        let times = Timestamps::now().received_now();
+        let direct = match state.identity {
+            Some(ref id) => {
+                let key = state.key_of(to).ok_or_else(|| Error::no_key(to))?;
+                let context = Context::Message { room: None, from: &state.nick, to,
+                    sent: times.sent };
+                Envelope::Sealed { room: None, from: Some(state.nick.clone()),
+                    parts: vec![SealedPart { to: to.to_owned(),
+                        data: id.seal(key, &context, text)? }] }
+            },
+            None => Envelope::Direct { from: Some(state.nick.clone()), to: to.to_owned(),
+                text: text.to_owned() },
+        };
+        let direct = direct.stamped(times);
+        sender.send(direct.to_message()?).map_err(Error::from)
+    }
+
+    /// Sends a file transfer message about transfer `id` to the client using
+    /// the nickname `to`, encrypted if the local user has an identity.
+    pub fn send_file(&self, to: &str, id: u64, msg: FileMsg) -> Result<(), Error> {
+        let state = self.state.lock().unwrap();
+        let sender = state.client_by_nick(to).ok_or_else(|| Error::unknown_nick(to))?;
+        let msg = match state.identity {
+            Some(ref identity) => {
+                let key = state.key_of(to).ok_or_else(|| Error::no_key(to))?;
+                identity.seal_file(key, &state.nick, to, id, &msg)?
+            },
+            None => msg,
+        };
+        sender.send(Envelope::File { from: Some(state.nick.clone()), to: to.to_owned(), id, msg }
+            .to_message()?).map_err(Error::from)
+    }
+
+    /// Sends a chat message to the members of `room`.
+    ///
+    /// Returns an error if the message could not be sent. Failure to record
+    /// the message in the history is reported as a notice instead.
+    pub fn send_chat(&self, room: &str, text: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if !state.is_member(room, Member::Local) { return Err(Error::not_in_room(room)); }
+        let nick = state.nick.clone();
+        if let Some(id) = state.identity.clone() {
+            return self.send_sealed(&state, &id, room, text);
+        }
+        let times = Timestamps::now().received_now();
+        if let Some(err) = state.record(room, &nick, text, times.time()) {
+            self.events.notice_recvd(err);
+        }
+        let chat = Envelope::Chat { room: room.to_owned(), from: Some(nick),
+            text: text.to_owned() }.stamped(times);
+        state.send_to_room(room, &chat, Member::Local).map(|_| ())
+    }
+
+    /// Sends a ping to the client using the nickname `nick` or, if `None`,
+    /// to every client. Returns the nicknames of the clients pinged. Each
+    /// round-trip time is reported once the client replies.
+    pub fn ping(&self, nick: Option<&str>) -> Result<Vec<String>, Error> {
+        let state = self.state.lock().unwrap();
+        let msg = Envelope::Ping(Pingstamp::now()).to_message()?;
+        let mut pinged = Vec::new();
+        for peer in state.clients.values().filter(|p| nick.map(|n| p.nick == n).unwrap_or(true)) {
+            peer.sender.send(msg.clone())?;
+            pinged.push(peer.nick.clone());
+        }
+        match nick {
+            Some(nick) if pinged.is_empty() => Err(Error::unknown_nick(nick)),
+            _ => Ok(pinged),
+        }
+    }
+
+    /// Sends an encrypted chat message to each member of `room` which has a
+    /// key. Encrypted messages are not recorded in the history.
+    fn send_sealed(&self, state: &ServerState, id: &Identity, room: &str, text: &str)
+            -> Result<(), Error> {
+        let times = Timestamps::now().received_now();
+        let mut undelivered = Vec::new();
+        for nick in state.nicks_in(room, Member::Local) {
+            let sender = match state.client_by_nick(&nick) {
+                Some(s) => s,
+                None => continue,
+            };
+            match state.key_of(&nick) {
+                Some(key) => {
+                    let context = Context::Message { room: Some(room), from: &state.nick,
+                        to: &nick, sent: times.sent };
+                    let part = SealedPart { to: nick.clone(), data: id.seal(key, &context, text)? };
+                    sender.send(Envelope::Sealed { room: Some(room.to_owned()),
+                        from: Some(state.nick.clone()), parts: vec![part] }.stamped(times)
+                        .to_message()?)?;
+                },
+                None => undelivered.push(nick),
+            }
+        }
+        if !undelivered.is_empty() {
+            self.events.notice_recvd(format!("Not delivered to {} (no encryption key).",
+                undelivered.join(", ")));
+        }
+        Ok(())
     }
 
     pub fn close_all(&self) -> Result<(), Error>  {
@@ -0,0 +1,222 @@
+//! Styled output text and the colors used to render it.
+
+use std::fs;
+use std::path::Path;
+use termion::color::{Fg, AnsiValue, Reset};
+use ::Error;
+
+
+/// The role of a piece of output text, which determines its color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Text,
+    Time,
+    /// The nickname of another participant, with a hash of the nickname
+    /// which picks its color. Use `Role::nick`.
+    Nick(u32),
+    OwnNick,
+    Notice,
+    Error,
+    Direct,
+}
+
+impl Role {
+    /// Returns the role of the nickname of another participant, so that
+    /// the same nickname is always given the same color.
+    pub fn nick(nick: &str) -> Role {
+        // djb2:
+        Role::Nick(nick.bytes().fold(5381u32, |h, b| h.wrapping_mul(33) ^ u32::from(b)))
+    }
+}
+
+
+/// A piece of output text and its role.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub role: Role,
+    pub text: String,
+}
+
+
+/// A line of output text made up of spans.
+#[derive(Debug, Clone, Default)]
+pub struct StyledLine {
+    spans: Vec<Span>,
+}
+
+impl StyledLine {
+    /// Returns a new, empty line.
+    pub fn new() -> StyledLine {
+        StyledLine::default()
+    }
+
+    /// Returns a line containing a single span.
+    pub fn with<S: Into<String>>(role: Role, text: S) -> StyledLine {
+        StyledLine::new().push(role, text)
+    }
+
+    /// Appends a span, merging it with the last span if they share a role.
+    pub fn append<S: Into<String>>(&mut self, role: Role, text: S) {
+        let text = text.into();
+        match self.spans.last_mut() {
+            Some(ref mut last) if last.role == role => last.text.push_str(&text),
+            _ => self.spans.push(Span { role, text }),
+        }
+    }
+
+    /// Returns this line with a span appended.
+    pub fn push<S: Into<String>>(mut self, role: Role, text: S) -> StyledLine {
+        self.append(role, text);
+        self
+    }
+
+    /// Returns the spans of the line.
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+}
+
+
+/// Returns a color from its name or ANSI 256 color palette index.
+fn parse_color(name: &str) -> Option<Option<u8>> {
+    const NAMES: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan",
+        "white"];
+    if name == "none" || name == "default" {
+        return Some(None);
+    }
+    if let Ok(value) = name.parse::<u8>() {
+        return Some(Some(value));
+    }
+    let (name, offset) = match name.strip_prefix("bright-") {
+        Some(name) => (name, 8),
+        None => (name, 0),
+    };
+    NAMES.iter().position(|&n| n == name).map(|i| Some(i as u8 + offset))
+}
+
+
+/// The colors of each role, as ANSI 256 color palette indexes. `None`
+/// leaves the terminal's default color.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    text: Option<u8>,
+    time: Option<u8>,
+    own_nick: Option<u8>,
+    notice: Option<u8>,
+    error: Option<u8>,
+    direct: Option<u8>,
+    // The colors other participants' nicknames are picked from:
+    nicks: Vec<u8>,
+}
+
+impl Theme {
+    /// Returns the default theme, suited to dark backgrounds.
+    pub fn dark() -> Theme {
+        Theme {
+            text: None,
+            time: Some(8),
+            own_nick: Some(15),
+            notice: Some(6),
+            error: Some(9),
+            direct: Some(13),
+            nicks: vec![1, 2, 3, 4, 5, 6, 9, 10, 11, 12, 13, 14],
+        }
+    }
+
+    /// Returns a theme suited to light backgrounds.
+    pub fn light() -> Theme {
+        Theme {
+            text: None,
+            time: Some(8),
+            own_nick: Some(0),
+            notice: Some(4),
+            error: Some(1),
+            direct: Some(5),
+            nicks: vec![1, 2, 3, 4, 5, 6, 88, 22, 94, 18, 90, 30],
+        }
+    }
+
+    /// Returns a theme without colors.
+    pub fn none() -> Theme {
+        Theme {
+            text: None,
+            time: None,
+            own_nick: None,
+            notice: None,
+            error: None,
+            direct: None,
+            nicks: Vec::new(),
+        }
+    }
+
+    /// Returns the built-in theme named `name` ('dark', 'light' or 'none')
+    /// or, otherwise, the theme defined by the file at path `name`.
+    ///
+    /// Theme files contain lines such as 'notice = cyan' or
+    /// 'nicks = 1, 2, 3' which override the colors of the dark theme.
+    /// Colors are given by name (e.g. 'red' or 'bright-red'), by ANSI 256
+    /// color palette index, or as 'none'.
+    pub fn load(name: &str) -> Result<Theme, Error> {
+        match name {
+            "dark" => return Ok(Theme::dark()),
+            "light" => return Ok(Theme::light()),
+            "none" => return Ok(Theme::none()),
+            _ => {},
+        }
+
+        let mut theme = Theme::dark();
+        let contents = fs::read_to_string(Path::new(name))?;
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let invalid = || Error::invalid_theme(format!("{}, line {}: '{}'", name, idx + 1, line));
+            let mut parts = line.splitn(2, '=').map(str::trim);
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) => (k, v),
+                _ => return Err(invalid()),
+            };
+            if key == "nicks" {
+                theme.nicks = value.split(',')
+                    .map(|c| parse_color(c.trim()).and_then(|c| c))
+                    .collect::<Option<_>>()
+                    .ok_or_else(invalid)?;
+                continue;
+            }
+            let color = parse_color(value).ok_or_else(invalid)?;
+            match key {
+                "text" => theme.text = color,
+                "time" => theme.time = color,
+                "own_nick" => theme.own_nick = color,
+                "notice" => theme.notice = color,
+                "error" => theme.error = color,
+                "direct" => theme.direct = color,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(theme)
+    }
+
+    /// Returns the color of `role`.
+    fn color(&self, role: Role) -> Option<u8> {
+        match role {
+            Role::Text => self.text,
+            Role::Time => self.time,
+            Role::OwnNick => self.own_nick,
+            Role::Notice => self.notice,
+            Role::Error => self.error,
+            Role::Direct => self.direct,
+            Role::Nick(_) if self.nicks.is_empty() => None,
+            Role::Nick(hash) => Some(self.nicks[hash as usize % self.nicks.len()]),
+        }
+    }
+
+    /// Appends `line` to `out` with the color escape sequences of each span.
+    pub fn render(&self, line: &StyledLine, out: &mut String) {
+        for span in line.spans() {
+            match self.color(span.role) {
+                Some(c) => out.push_str(&format!("{}{}{}", Fg(AnsiValue(c)), span.text, Fg(Reset))),
+                None => out.push_str(&span.text),
+            }
+        }
+    }
+}
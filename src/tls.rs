@@ -0,0 +1,45 @@
+//! TLS (`wss://`) support for the chat client and server.
+
+use std::path::Path;
+use std::sync::Arc;
+use openssl::ssl::{SslAcceptor, SslConnector, SslMethod, SslFiletype, SslStream, SslVerifyMode};
+use url::Url;
+use ws::{self, util::TcpStream};
+use ::Error;
+
+
+/// Returns a server-side TLS acceptor using the PEM encoded certificate
+/// chain and private key files.
+pub fn acceptor(cert: &Path, key: &Path) -> Result<Arc<SslAcceptor>, Error> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_certificate_chain_file(cert)?;
+    builder.set_private_key_file(key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    Ok(Arc::new(builder.build()))
+}
+
+
+/// Wraps a server-side stream, performing the TLS handshake.
+pub fn accept(acceptor: &SslAcceptor, stream: TcpStream) -> Result<SslStream<TcpStream>, ws::Error> {
+    acceptor.accept(stream).map_err(ws::Error::from)
+}
+
+
+/// Wraps a client-side stream, performing the TLS handshake.
+///
+/// The server certificate is verified against the system's trusted
+/// certificates and the host name of `url` unless `insecure` is set.
+pub fn connect(stream: TcpStream, url: &Url, insecure: bool)
+        -> Result<SslStream<TcpStream>, ws::Error> {
+    let host = url.host_str().ok_or_else(|| ws::Error::new(ws::ErrorKind::Protocol,
+        format!("Unable to parse host from {}. Needed for TLS.", url)))?;
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(Error::from)?;
+    if insecure {
+        builder.set_verify(SslVerifyMode::NONE);
+    }
+    let mut config = builder.build().configure().map_err(Error::from)?;
+    if insecure {
+        config.set_verify_hostname(false);
+    }
+    config.connect(host, stream).map_err(ws::Error::from)
+}
@@ -0,0 +1,573 @@
+//! Chunked file transfers between participants.
+//!
+//! A file is offered to a single participant along with its size and SHA-256
+//! digest. Once accepted, it is sent in chunks which the recipient
+//! acknowledges. No more than `WINDOW` bytes are sent ahead of the last
+//! acknowledgement, so that neither the connections nor the server relaying
+//! the chunks hold more than that of any file.
+//!
+//! The recipient writes to a partial file named after the file and its
+//! digest. The file is renamed once its digest has been verified. A partial
+//! file left by an interrupted transfer is resumed from its length when the
+//! same file is offered and accepted again.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use openssl::memcmp;
+use openssl::sha::Sha256;
+use rand;
+use ::Error;
+use protocol::FileMsg;
+
+
+/// The size of the chunks a file is sent in.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The number of bytes which may be sent ahead of the last acknowledgement.
+pub const WINDOW: u64 = 8 * CHUNK_SIZE as u64;
+
+/// The suffix of partial files.
+pub const PART_SUFFIX: &str = ".part";
+
+
+/// Formats a number of bytes, e.g. '1.5 MiB'.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 { return format!("{} bytes", bytes); }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+
+/// Feeds the contents of `file`, read from its current position to its end,
+/// to `hasher`.
+fn hash_file(file: &mut File, hasher: &mut Sha256) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut len = 0;
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(len),
+            n => {
+                hasher.update(&buf[..n]);
+                len += n as u64;
+            },
+        }
+    }
+}
+
+/// Returns an offered file name stripped of any directories, so that files
+/// are only ever written within the download directory.
+fn safe_name(name: &str) -> String {
+    let name: String = Path::new(name).file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+        .chars().map(|c| if c.is_control() { '_' } else { c })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "download".to_owned(),
+        _ => name,
+    }
+}
+
+/// Returns a path within `dir` for a file named `name` which does not yet
+/// exist, adding a numeric suffix to the name if necessary.
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() { return path; }
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i..]),
+        _ => (name, ""),
+    };
+    (1..).map(|i| dir.join(format!("{}-{}{}", stem, i, ext)))
+        .find(|p| !p.exists())
+        .unwrap()
+}
+
+
+/// Whether a transfer sends or receives a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sending,
+    Receiving,
+}
+
+
+/// The progress of a transfer which has been accepted.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub direction: Direction,
+    pub peer: String,
+    pub name: String,
+    /// The number of bytes acknowledged or received so far.
+    pub done: u64,
+    pub size: u64,
+}
+
+
+/// A change in the state of a transfer, to be reported to the user.
+#[derive(Debug, Clone)]
+pub enum Status {
+    /// `peer` offers to send us a file.
+    Offered { peer: String, name: String, size: u64 },
+    /// `peer` has accepted our file, resuming from `offset` if not zero.
+    Accepted { peer: String, name: String, offset: u64 },
+    /// `peer` has rejected our file.
+    Rejected { peer: String, name: String },
+    /// Our file has been received and verified by `peer`.
+    Sent { peer: String, name: String },
+    /// A file from `peer` has been received, verified and saved at `path`.
+    Received { peer: String, path: PathBuf },
+    /// A transfer has been cancelled by `peer` or has failed.
+    Failed { direction: Direction, peer: String, name: String, reason: String },
+}
+
+
+/// A file we are sending.
+struct Outgoing {
+    peer: String,
+    id: u64,
+    name: String,
+    file: File,
+    size: u64,
+    // Set once the offer has been accepted:
+    accepted: bool,
+    // The number of bytes sent and acknowledged:
+    sent: u64,
+    acked: u64,
+}
+
+/// A file we are offered or are receiving.
+struct Incoming {
+    peer: String,
+    id: u64,
+    name: String,
+    size: u64,
+    sha256: Vec<u8>,
+    // The partial file and the digest of its contents, once accepted:
+    part: Option<(PathBuf, File, Sha256)>,
+    received: u64,
+}
+
+impl Incoming {
+    /// Returns the path of the partial file of this transfer within `dir`.
+    fn part_path(&self, dir: &Path) -> PathBuf {
+        let digest: String = self.sha256.iter().take(4).map(|b| format!("{:02x}", b)).collect();
+        dir.join(format!("{}.{}{}", self.name, digest, PART_SUFFIX))
+    }
+}
+
+
+/// The file transfers of the local user, in the order they were offered.
+pub struct Transfers {
+    outgoing: Vec<Outgoing>,
+    incoming: Vec<Incoming>,
+    // Where received files are saved:
+    download_dir: PathBuf,
+}
+
+impl Transfers {
+    /// Returns a new set of transfers saving received files in
+    /// `download_dir`.
+    pub fn new(download_dir: PathBuf) -> Transfers {
+        Transfers { outgoing: Vec::new(), incoming: Vec::new(), download_dir }
+    }
+
+    /// Prepares to offer the file at `path` to `peer`, returning the
+    /// identifier of the transfer and the offer to send.
+    pub fn offer(&mut self, peer: &str, path: &Path) -> Result<(u64, FileMsg), Error> {
+        let mut file = File::open(path)?;
+        if !file.metadata()?.is_file() {
+            return Err(Error::not_a_file(path.to_string_lossy()));
+        }
+        let mut hasher = Sha256::new();
+        let size = hash_file(&mut file, &mut hasher)?;
+        let name = safe_name(&path.to_string_lossy());
+        let id = rand::random();
+        self.outgoing.push(Outgoing { peer: peer.to_owned(), id, name: name.clone(), file, size,
+            accepted: false, sent: 0, acked: 0 });
+        Ok((id, FileMsg::Offer { name, size, sha256: hasher.finish().to_vec() }))
+    }
+
+    /// Returns the index of the oldest offer yet to be answered, from `peer`
+    /// if given.
+    fn pending_offer(&self, peer: Option<&str>) -> Result<usize, Error> {
+        self.incoming.iter()
+            .position(|t| t.part.is_none() && peer.map(|p| t.peer == p).unwrap_or(true))
+            .ok_or_else(Error::no_file_offer)
+    }
+
+    /// Accepts the oldest offer yet to be answered, from `peer` if given,
+    /// resuming a partial earlier download of the same file. Returns the
+    /// peer, the identifier of the transfer and the reply to send.
+    pub fn accept(&mut self, peer: Option<&str>) -> Result<(String, u64, FileMsg), Error> {
+        let idx = self.pending_offer(peer)?;
+        let path = self.incoming[idx].part_path(&self.download_dir);
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+        let mut hasher = Sha256::new();
+        let mut offset = hash_file(&mut file, &mut hasher)?;
+        let t = &mut self.incoming[idx];
+        if offset >= t.size {
+            // Not a partial download of this file after all:
+            file.set_len(0)?;
+            hasher = Sha256::new();
+            offset = 0;
+        }
+        t.part = Some((path, file, hasher));
+        t.received = offset;
+        Ok((t.peer.clone(), t.id, FileMsg::Accept { offset }))
+    }
+
+    /// Rejects the oldest offer yet to be answered, from `peer` if given.
+    /// Returns the peer, the identifier of the transfer and the reply to
+    /// send.
+    pub fn reject(&mut self, peer: Option<&str>) -> Result<(String, u64, FileMsg), Error> {
+        let idx = self.pending_offer(peer)?;
+        let t = self.incoming.remove(idx);
+        Ok((t.peer, t.id, FileMsg::Reject))
+    }
+
+    /// Handles a message received from `peer` about transfer `id`. Returns
+    /// the replies to send to `peer` and any change to report.
+    pub fn handle(&mut self, peer: &str, id: u64, msg: FileMsg) -> (Vec<FileMsg>, Option<Status>) {
+        match msg {
+            FileMsg::Offer { name, size, sha256 } => {
+                let name = safe_name(&name);
+                self.incoming.push(Incoming { peer: peer.to_owned(), id, name: name.clone(), size,
+                    sha256, part: None, received: 0 });
+                (Vec::new(), Some(Status::Offered { peer: peer.to_owned(), name, size }))
+            },
+            FileMsg::Accept { offset } => self.accepted(peer, id, offset),
+            FileMsg::Ack { offset } => self.acknowledged(peer, id, offset),
+            FileMsg::Chunk { offset, data } => self.chunk(peer, id, offset, &data),
+            FileMsg::Reject => match self.outgoing.iter().position(|t| t.peer == peer && t.id == id) {
+                Some(idx) => {
+                    let t = self.outgoing.remove(idx);
+                    (Vec::new(), Some(Status::Rejected { peer: t.peer, name: t.name }))
+                },
+                None => (Vec::new(), None),
+            },
+            FileMsg::Cancel(reason) => {
+                let status = self.remove(peer, id, reason);
+                (Vec::new(), status)
+            },
+            // Only decrypted by clients and servers with an identity:
+            FileMsg::Sealed { .. } => {
+                (vec![FileMsg::Cancel("The recipient does not use end-to-end encryption."
+                    .to_owned())], None)
+            },
+        }
+    }
+
+    /// Ends a transfer, returning its failure status.
+    fn remove(&mut self, peer: &str, id: u64, reason: String) -> Option<Status> {
+        if let Some(idx) = self.outgoing.iter().position(|t| t.peer == peer && t.id == id) {
+            let t = self.outgoing.remove(idx);
+            return Some(Status::Failed { direction: Direction::Sending, peer: t.peer,
+                name: t.name, reason });
+        }
+        // Partial files are kept to be resumed:
+        self.incoming.iter().position(|t| t.peer == peer && t.id == id).map(|idx| {
+            let t = self.incoming.remove(idx);
+            Status::Failed { direction: Direction::Receiving, peer: t.peer, name: t.name, reason }
+        })
+    }
+
+    /// Ends a transfer which has failed locally, returning the cancellation
+    /// to send and the failure status.
+    fn fail(&mut self, peer: &str, id: u64, reason: String) -> (Vec<FileMsg>, Option<Status>) {
+        let status = self.remove(peer, id, reason.clone());
+        (vec![FileMsg::Cancel(reason)], status)
+    }
+
+    /// Reads and returns the chunks which may be sent ahead of the last
+    /// acknowledgement of outgoing transfer `idx`.
+    fn fill_window(&mut self, idx: usize) -> io::Result<Vec<FileMsg>> {
+        let t = &mut self.outgoing[idx];
+        let mut chunks = Vec::new();
+        while t.sent < t.size && t.sent - t.acked < WINDOW {
+            let len = (t.size - t.sent).min(CHUNK_SIZE as u64) as usize;
+            let mut data = vec![0; len];
+            t.file.read_exact(&mut data)?;
+            chunks.push(FileMsg::Chunk { offset: t.sent, data });
+            t.sent += len as u64;
+        }
+        Ok(chunks)
+    }
+
+    /// Starts sending a file once `peer` has accepted it.
+    fn accepted(&mut self, peer: &str, id: u64, offset: u64) -> (Vec<FileMsg>, Option<Status>) {
+        let idx = match self.outgoing.iter().position(|t| t.peer == peer && t.id == id) {
+            Some(idx) if !self.outgoing[idx].accepted => idx,
+            _ => return (Vec::new(), None),
+        };
+        let res = {
+            let t = &mut self.outgoing[idx];
+            if offset > t.size {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid offset."))
+            } else {
+                t.accepted = true;
+                t.sent = offset;
+                t.acked = offset;
+                t.file.seek(SeekFrom::Start(offset)).map(|_| ())
+            }
+        };
+        // An empty file is sent as a single empty chunk:
+        let chunks = res.and_then(|_| match self.outgoing[idx].size {
+            0 => Ok(vec![FileMsg::Chunk { offset: 0, data: Vec::new() }]),
+            _ => self.fill_window(idx),
+        });
+        match chunks {
+            Ok(chunks) => {
+                let t = &self.outgoing[idx];
+                (chunks, Some(Status::Accepted { peer: t.peer.clone(), name: t.name.clone(),
+                    offset }))
+            },
+            Err(err) => self.fail(peer, id, format!("Unable to read the file: {}", err)),
+        }
+    }
+
+    /// Sends more of a file as `peer` acknowledges what it has received.
+    /// Acknowledgements of files not yet accepted are ignored.
+    fn acknowledged(&mut self, peer: &str, id: u64, offset: u64)
+            -> (Vec<FileMsg>, Option<Status>) {
+        let idx = match self.outgoing.iter().position(|t| t.peer == peer && t.id == id) {
+            Some(idx) if self.outgoing[idx].accepted => idx,
+            _ => return (Vec::new(), None),
+        };
+        {
+            let t = &mut self.outgoing[idx];
+            t.acked = offset.min(t.sent);
+            // The recipient acknowledges the end of the file once verified:
+            if t.acked == t.size {
+                let t = self.outgoing.remove(idx);
+                return (Vec::new(), Some(Status::Sent { peer: t.peer, name: t.name }));
+            }
+        }
+        match self.fill_window(idx) {
+            Ok(chunks) => (chunks, None),
+            Err(err) => self.fail(peer, id, format!("Unable to read the file: {}", err)),
+        }
+    }
+
+    /// Writes a chunk of a file being received, verifying and saving the
+    /// file once complete.
+    fn chunk(&mut self, peer: &str, id: u64, offset: u64, data: &[u8])
+            -> (Vec<FileMsg>, Option<Status>) {
+        let idx = match self.incoming.iter().position(|t| t.peer == peer && t.id == id) {
+            Some(idx) if self.incoming[idx].part.is_some() => idx,
+            _ => return (Vec::new(), None),
+        };
+        let res = {
+            let t = &mut self.incoming[idx];
+            if offset != t.received || t.received + data.len() as u64 > t.size {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected chunk."))
+            } else {
+                let (_, ref mut file, ref mut hasher) = *t.part.as_mut().unwrap();
+                hasher.update(data);
+                t.received += data.len() as u64;
+                file.write_all(data)
+            }
+        };
+        if let Err(err) = res {
+            return self.fail(peer, id, format!("Unable to write the file: {}", err));
+        }
+        if self.incoming[idx].received < self.incoming[idx].size {
+            return (vec![FileMsg::Ack { offset: self.incoming[idx].received }], None);
+        }
+
+        let Incoming { peer, name, size, sha256, part, .. } = self.incoming.remove(idx);
+        let (part_path, file, hasher) = part.unwrap();
+        drop(file);
+        let res = if memcmp::eq(&hasher.finish(), &sha256) {
+            let path = unique_path(&self.download_dir, &name);
+            fs::rename(&part_path, &path).map(|_| path)
+                .map_err(|err| format!("Unable to save the file: {}", err))
+        } else {
+            fs::remove_file(&part_path).ok();
+            Err("The SHA-256 digest does not match.".to_owned())
+        };
+        match res {
+            Ok(path) => (vec![FileMsg::Ack { offset: size }], Some(Status::Received { peer, path })),
+            Err(reason) => (vec![FileMsg::Cancel(reason.clone())],
+                Some(Status::Failed { direction: Direction::Receiving, peer, name, reason })),
+        }
+    }
+
+    /// Returns true if no file has been offered, sent or received.
+    pub fn is_empty(&self) -> bool {
+        self.outgoing.is_empty() && self.incoming.is_empty()
+    }
+
+    /// Abandons the transfers with participants for which `keep` returns
+    /// false (e.g. because they have disconnected), giving `reason`. Returns
+    /// the peer, identifier and cancellation to send for each transfer, and
+    /// the transfers' failure statuses.
+    pub fn interrupt<F: Fn(&str) -> bool>(&mut self, keep: F, reason: &str)
+            -> (Vec<(String, u64, FileMsg)>, Vec<Status>) {
+        let mut cancels = Vec::new();
+        let mut statuses = Vec::new();
+        let (outgoing, incoming): (Vec<_>, Vec<_>) = (
+            self.outgoing.iter().filter(|t| !keep(&t.peer)).map(|t| (t.peer.clone(), t.id))
+                .collect(),
+            self.incoming.iter().filter(|t| !keep(&t.peer)).map(|t| (t.peer.clone(), t.id))
+                .collect());
+        for (peer, id) in outgoing.into_iter().chain(incoming) {
+            statuses.extend(self.remove(&peer, id, reason.to_owned()));
+            cancels.push((peer, id, FileMsg::Cancel(reason.to_owned())));
+        }
+        (cancels, statuses)
+    }
+
+    /// Returns the progress of each accepted transfer.
+    pub fn progress(&self) -> Vec<Progress> {
+        let outgoing = self.outgoing.iter().filter(|t| t.accepted).map(|t| Progress {
+            direction: Direction::Sending, peer: t.peer.clone(), name: t.name.clone(),
+            done: t.acked, size: t.size });
+        let incoming = self.incoming.iter().filter(|t| t.part.is_some()).map(|t| Progress {
+            direction: Direction::Receiving, peer: t.peer.clone(), name: t.name.clone(),
+            done: t.received, size: t.size });
+        outgoing.chain(incoming).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// Returns a new, empty directory named `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("headsup-transfer-{}-{}", process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Passes the messages of transfer `id` between 'alice', sending, and
+    /// 'bob', receiving, until neither has anything left to send. Returns
+    /// the changes reported to each.
+    fn exchange(alice: &mut Transfers, bob: &mut Transfers, id: u64, mut to_bob: Vec<FileMsg>)
+            -> (Vec<Status>, Vec<Status>) {
+        let (mut alice_statuses, mut bob_statuses) = (Vec::new(), Vec::new());
+        while !to_bob.is_empty() {
+            let mut to_alice = Vec::new();
+            for msg in to_bob {
+                let (replies, status) = bob.handle("alice", id, msg);
+                to_alice.extend(replies);
+                bob_statuses.extend(status);
+            }
+            to_bob = Vec::new();
+            for msg in to_alice {
+                let (replies, status) = alice.handle("bob", id, msg);
+                to_bob.extend(replies);
+                alice_statuses.extend(status);
+            }
+        }
+        (alice_statuses, bob_statuses)
+    }
+
+    #[test]
+    fn sends_files_in_acknowledged_chunks() {
+        let dir = temp_dir("send");
+        let contents: Vec<u8> = (0..WINDOW as usize * 2 + 10).map(|i| i as u8).collect();
+        fs::write(dir.join("data.bin"), &contents).unwrap();
+        let (mut alice, mut bob) = (Transfers::new(dir.clone()), Transfers::new(dir.join("in")));
+        fs::create_dir(dir.join("in")).unwrap();
+
+        let (id, offer) = alice.offer("bob", &dir.join("data.bin")).unwrap();
+        let (_, bob_statuses) = exchange(&mut alice, &mut bob, id, vec![offer]);
+        let size = contents.len() as u64;
+        assert!(matches!(bob_statuses[..],
+            [Status::Offered { ref name, size: s, .. }] if name == "data.bin" && s == size));
+
+        let (peer, accept_id, accept) = bob.accept(None).unwrap();
+        assert_eq!((peer.as_str(), accept_id), ("alice", id));
+        let (chunks, status) = alice.handle("bob", id, accept);
+        assert!(matches!(status, Some(Status::Accepted { offset: 0, .. })));
+        assert_eq!(chunks.len() as u64, WINDOW / CHUNK_SIZE as u64);
+
+        let (alice_statuses, bob_statuses) = exchange(&mut alice, &mut bob, id, chunks);
+        assert!(matches!(alice_statuses[..], [Status::Sent { .. }]));
+        match bob_statuses[..] {
+            [Status::Received { ref path, .. }] => {
+                assert_eq!(path, &dir.join("in").join("data.bin"));
+                assert_eq!(fs::read(path).unwrap(), contents);
+            },
+            _ => panic!("not received: {:?}", bob_statuses),
+        }
+        assert!(alice.is_empty() && bob.is_empty());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn ignores_acknowledgements_before_acceptance() {
+        let dir = temp_dir("unaccepted");
+        fs::write(dir.join("empty"), b"").unwrap();
+        let mut alice = Transfers::new(dir.clone());
+        let (id, _) = alice.offer("bob", &dir.join("empty")).unwrap();
+
+        let (replies, status) = alice.handle("bob", id, FileMsg::Ack { offset: 0 });
+        assert!(replies.is_empty() && status.is_none());
+        assert!(!alice.is_empty());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn resumes_partial_downloads() {
+        let dir = temp_dir("resume");
+        let contents = vec![7u8; CHUNK_SIZE + 100];
+        fs::write(dir.join("data.bin"), &contents).unwrap();
+        let (mut alice, mut bob) = (Transfers::new(dir.clone()), Transfers::new(dir.join("in")));
+        fs::create_dir(dir.join("in")).unwrap();
+
+        let (id, offer) = alice.offer("bob", &dir.join("data.bin")).unwrap();
+        exchange(&mut alice, &mut bob, id, vec![offer.clone()]);
+        let (_, _, accept) = bob.accept(None).unwrap();
+        let (chunks, _) = alice.handle("bob", id, accept);
+        // Only the first chunk arrives before alice goes away:
+        bob.handle("alice", id, chunks[0].clone());
+        let (_, statuses) = bob.interrupt(|_| false, "Disconnected.");
+        assert!(matches!(statuses[..], [Status::Failed { direction: Direction::Receiving, .. }]));
+
+        let mut alice = Transfers::new(dir.clone());
+        let (id, offer) = alice.offer("bob", &dir.join("data.bin")).unwrap();
+        exchange(&mut alice, &mut bob, id, vec![offer]);
+        let (_, _, accept) = bob.accept(None).unwrap();
+        assert!(matches!(accept, FileMsg::Accept { offset } if offset == CHUNK_SIZE as u64));
+        let (chunks, _) = alice.handle("bob", id, accept);
+        let (alice_statuses, _) = exchange(&mut alice, &mut bob, id, chunks);
+        assert!(matches!(alice_statuses[..], [Status::Sent { .. }]));
+        assert_eq!(fs::read(dir.join("in").join("data.bin")).unwrap(), contents);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn cancels_on_unexpected_chunks() {
+        let dir = temp_dir("unexpected");
+        let mut bob = Transfers::new(dir.clone());
+        bob.handle("alice", 1, FileMsg::Offer { name: "data.bin".to_owned(), size: 10,
+            sha256: vec![0; 32] });
+        bob.accept(Some("alice")).unwrap();
+
+        let (replies, status) = bob.handle("alice", 1, FileMsg::Chunk { offset: 5,
+            data: vec![0; 5] });
+        assert!(matches!(replies[..], [FileMsg::Cancel(_)]));
+        assert!(matches!(status, Some(Status::Failed { direction: Direction::Receiving, .. })));
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn strips_directories_from_offered_names() {
+        assert_eq!(safe_name("../../etc/passwd"), "passwd");
+        assert_eq!(safe_name(".."), "download");
+        assert_eq!(safe_name("a\u{1b}b"), "a_b");
+    }
+}
@@ -0,0 +1,254 @@
+//! Chat between a server and clients on the local machine, observed through
+//! the events they report.
+
+// The `ws` handlers of the silent peers return `ws::Result`, whose error is large:
+#![allow(clippy::result_large_err)]
+
+extern crate headsup;
+extern crate ws;
+
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use headsup::{Client, ClientOptions, Server, ServerOptions, Event, RecordingSink};
+use headsup::client::{self, ReconnectOptions};
+use headsup::crypto::{Identity, Context};
+use headsup::history::{History, HistoryOptions};
+use headsup::protocol::{Envelope, SealedPart, Timestamps, DEFAULT_ROOM};
+
+
+/// How long to wait for an event before failing.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+
+/// Waits until `done` returns true.
+fn wait_until<F: Fn() -> bool>(done: F) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Waits for `sink` to record an event for which `matches` returns true.
+fn wait_for<F: Fn(&Event) -> bool>(sink: &RecordingSink, matches: F) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !sink.events().iter().any(&matches) {
+        assert!(Instant::now() < deadline, "no matching event in {:?}", sink.events());
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Returns a local port which nothing listens on.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Starts a server on a free port with the local user 'host'.
+fn server(opts: ServerOptions) -> (Server, Arc<RecordingSink>) {
+    let events = Arc::new(RecordingSink::new());
+    let history = History::open(&HistoryOptions::default()).unwrap();
+    let addr = ([127, 0, 0, 1], free_port()).into();
+    let server = Server::new(addr, "host", history, opts, events.clone()).unwrap();
+    (server, events)
+}
+
+/// Connects to `server` using `nick`, waiting until the default room has
+/// been joined.
+fn client(server: &Server, nick: &str, opts: ClientOptions) -> (Client, Arc<RecordingSink>) {
+    let events = Arc::new(RecordingSink::new());
+    let url = client::parse_url(&server.url().to_string()).unwrap();
+    let client = Client::new(url, nick, opts, events.clone()).unwrap();
+    wait_for(&events, |e| matches!(*e, Event::RoomJoined(ref room) if room == DEFAULT_ROOM));
+    (client, events)
+}
+
+/// Generates a new identity, saved only temporarily in the file `name`.
+fn identity(name: &str) -> Arc<Identity> {
+    let path = env::temp_dir().join(format!("headsup-chat-{}-{}", process::id(), name));
+    let identity = Identity::load_or_generate(&path).unwrap();
+    fs::remove_file(path).unwrap();
+    Arc::new(identity)
+}
+
+
+#[test]
+fn relays_chat_to_room_members() {
+    let (server, server_events) = server(ServerOptions::default());
+    let (alice, _) = client(&server, "alice", ClientOptions::default());
+    let (_bob, bob_events) = client(&server, "bob", ClientOptions::default());
+
+    alice.send_chat(DEFAULT_ROOM, "hello").unwrap();
+    wait_for(&bob_events, |e| matches!(*e,
+        Event::MessageRecvd(_, ref from, ref text, _) if from == "alice" && text == "hello"));
+    wait_for(&server_events, |e| matches!(*e,
+        Event::MessageRecvd(_, ref from, ref text, _) if from == "alice" && text == "hello"));
+
+    server.send_chat(DEFAULT_ROOM, "welcome").unwrap();
+    wait_for(&bob_events, |e| matches!(*e,
+        Event::MessageRecvd(_, ref from, ref text, _) if from == "host" && text == "welcome"));
+}
+
+#[test]
+fn replays_history_to_room_members_only() {
+    let (server, _) = server(ServerOptions::default());
+    let (alice, alice_events) = client(&server, "alice", ClientOptions::default());
+    alice.join("#ops").unwrap();
+    wait_for(&alice_events, |e| matches!(*e, Event::RoomJoined(ref room) if room == "#ops"));
+    alice.send_chat("#ops", "deploying").unwrap();
+    wait_until(|| !server.history("#ops", 1).is_empty());
+
+    let (bob, bob_events) = client(&server, "bob", ClientOptions::default());
+    bob.request_history("#ops", 10).unwrap();
+    wait_for(&bob_events, |e| matches!(*e,
+        Event::ReplyRecvd(false, ref text) if text == "You are not in #ops."));
+
+    bob.join("#ops").unwrap();
+    wait_for(&bob_events, |e| match *e {
+        Event::HistoryRecvd(ref entries) => entries.iter().any(|e| e.text == "deploying"),
+        _ => false,
+    });
+}
+
+#[test]
+fn delivers_encrypted_direct_messages() {
+    let (server, server_events) = server(ServerOptions::default());
+    let opts = |name| ClientOptions { identity: Some(identity(name)), ..ClientOptions::default() };
+    let (alice, _) = client(&server, "alice", opts("alice.pem"));
+    let (_bob, bob_events) = client(&server, "bob", opts("bob.pem"));
+    wait_until(|| alice.fingerprint("bob").is_some());
+
+    alice.send_direct("bob", "secret").unwrap();
+    wait_for(&bob_events, |e| matches!(*e,
+        Event::DirectRecvd(ref from, ref text, _) if from == "alice" && text == "secret"));
+    // The server only relays the ciphertext:
+    assert!(!server_events.events().iter().any(|e| matches!(*e, Event::DirectRecvd(..))));
+}
+
+#[test]
+fn ignores_replayed_messages_to_the_server() {
+    let host = identity("replay-host.pem");
+    let alice_id = identity("replay-alice.pem");
+    let (server, server_events) = server(ServerOptions { identity: Some(host.clone()),
+        ..ServerOptions::default() });
+    let opts = ClientOptions { identity: Some(alice_id.clone()), ..ClientOptions::default() };
+    let (alice, _) = client(&server, "alice", opts);
+    wait_until(|| server.fingerprint("alice").is_some());
+
+    let times = Timestamps::now();
+    let context = Context::Message { room: None, from: "alice", to: "host", sent: times.sent };
+    let data = alice_id.seal(host.public_key(), &context, "secret").unwrap();
+    let sealed = Envelope::Sealed { room: None, from: None,
+        parts: vec![SealedPart { to: "host".to_owned(), data }] }.stamped(times);
+    alice.send(&sealed).unwrap();
+    alice.send(&sealed).unwrap();
+    wait_for(&server_events, |e| matches!(*e,
+        Event::NoticeRecvd(ref text) if text.starts_with("Ignored a message from alice")));
+    let received = server_events.events().iter()
+        .filter(|e| matches!(**e, Event::DirectRecvd(ref from, ..) if from == "alice"))
+        .count();
+    assert_eq!(received, 1);
+}
+
+/// Returns options reconnecting after `delay`, giving up after `max_attempts`.
+fn reconnecting(delay: Duration, max_attempts: u32) -> ClientOptions {
+    let reconnect = ReconnectOptions { enabled: true, initial_delay: delay, max_delay: delay,
+        max_attempts };
+    ClientOptions { reconnect, ..ClientOptions::default() }
+}
+
+#[test]
+fn gives_up_reconnecting_after_max_attempts() {
+    let url = client::parse_url(&format!("127.0.0.1:{}", free_port())).unwrap();
+    let events = Arc::new(RecordingSink::new());
+    let client = Client::new(url, "alice", reconnecting(Duration::from_millis(10), 2),
+        events.clone()).unwrap();
+    wait_for(&events, |e| matches!(*e, Event::ClientDisconnected));
+    let attempts: Vec<_> = events.events().iter()
+        .filter_map(|e| match *e { Event::ClientReconnecting(_, n) => Some(n), _ => None })
+        .collect();
+    assert_eq!(attempts, [1, 2]);
+    assert!(!client.is_connected());
+}
+
+#[test]
+fn rejoins_rooms_after_reconnecting() {
+    let (server, _) = server(ServerOptions::default());
+    let (alice, alice_events) = client(&server, "alice",
+        reconnecting(Duration::from_millis(10), 0));
+    alice.join("#ops").unwrap();
+    wait_for(&alice_events, |e| matches!(*e, Event::RoomJoined(ref room) if room == "#ops"));
+    alice_events.take();
+
+    server.close_all().unwrap();
+    wait_for(&alice_events, |e| matches!(*e, Event::ClientReconnecting(..)));
+    wait_for(&alice_events, |e| matches!(*e, Event::RoomJoined(ref room) if room == "#ops"));
+    assert!(alice.is_connected());
+}
+
+#[test]
+fn cancels_reconnecting_after_a_drop() {
+    let (server, _) = server(ServerOptions::default());
+    let (alice, alice_events) = client(&server, "alice",
+        reconnecting(Duration::from_secs(60), 0));
+
+    server.close_all().unwrap();
+    wait_for(&alice_events, |e| matches!(*e, Event::ClientReconnecting(..)));
+    assert!(!alice.is_connected());
+    assert!(alice.cancel());
+    wait_for(&alice_events, |e| matches!(*e, Event::ClientDisconnected));
+}
+
+#[test]
+fn measures_round_trip_times() {
+    let (server, server_events) = server(ServerOptions::default());
+    let (alice, alice_events) = client(&server, "alice", ClientOptions::default());
+
+    alice.ping().unwrap();
+    wait_for(&alice_events, |e| matches!(*e,
+        Event::PongRecvd(ref from, _) if from == client::SERVER_PEER));
+    assert_eq!(server.ping(None).unwrap(), ["alice"]);
+    wait_for(&server_events, |e| matches!(*e, Event::PongRecvd(ref from, _) if from == "alice"));
+}
+
+/// Starts a websocket server on a free port which never answers, returning
+/// its address.
+fn silent_server() -> String {
+    let addr = format!("127.0.0.1:{}", free_port());
+    let ws = ws::WebSocket::new(|_| |_| Ok(())).unwrap().bind(&addr).unwrap();
+    thread::spawn(move || ws.run().ok());
+    addr
+}
+
+#[test]
+fn clients_close_connections_which_stop_answering_pings() {
+    let url = client::parse_url(&silent_server()).unwrap();
+    let events = Arc::new(RecordingSink::new());
+    let opts = ClientOptions { ping_interval: Some(Duration::from_millis(20)),
+        max_missed_pings: 2, ..ClientOptions::default() };
+    let _client = Client::new(url, "alice", opts, events.clone()).unwrap();
+    wait_for(&events, |e| matches!(*e, Event::ClientDisconnected));
+    assert!(events.events().iter().any(|e| matches!(*e,
+        Event::NoticeRecvd(ref text) if text.starts_with("The server has not answered 2 pings"))));
+}
+
+#[test]
+fn servers_close_connections_which_stop_answering_pings() {
+    let (server, server_events) = server(ServerOptions {
+        ping_interval: Some(Duration::from_millis(20)), max_missed_pings: 2,
+        ..ServerOptions::default() });
+    let (alice, _) = client(&server, "alice", ClientOptions::default());
+    let url = format!("ws://{}/?nick=mute", server.url());
+    thread::spawn(move || ws::connect(url, |_| |_| Ok(())).ok());
+
+    wait_for(&server_events, |e| matches!(*e,
+        Event::NoticeRecvd(ref text) if text.starts_with("mute has not answered 2 pings")));
+    wait_until(|| !server.nicks().iter().any(|n| n == "mute"));
+    // Clients which answer stay connected:
+    assert!(alice.is_connected());
+    assert!(server.nicks().iter().any(|n| n == "alice"));
+}